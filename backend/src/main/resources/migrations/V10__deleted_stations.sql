@@ -0,0 +1,5 @@
+CREATE TABLE deleted_stations (
+    id UUID NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    deleted_on TIMESTAMPTZ NOT NULL
+);
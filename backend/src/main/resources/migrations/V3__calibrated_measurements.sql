@@ -0,0 +1,2 @@
+ALTER TABLE station_measurements ADD COLUMN soil_moisture decimal;
+ALTER TABLE station_measurements ADD COLUMN tank_fill decimal;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE station_measurements ALTER COLUMN temperature DROP NOT NULL;
+ALTER TABLE station_measurements ALTER COLUMN humidity DROP NOT NULL;
+ALTER TABLE station_measurements ALTER COLUMN lux DROP NOT NULL;
\ No newline at end of file
@@ -0,0 +1,4 @@
+ALTER TABLE stations ADD COLUMN capabilities JSON;
+ALTER TABLE station_measurements ALTER COLUMN battery_voltage DROP NOT NULL;
+ALTER TABLE station_measurements ALTER COLUMN soil_pf DROP NOT NULL;
+ALTER TABLE station_measurements ALTER COLUMN tank_pf DROP NOT NULL;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE station_measurements ADD COLUMN battery_percentage decimal;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE station_measurements ADD COLUMN statistics JSON;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE station_measurements ADD COLUMN sequence bigint;
+ALTER TABLE station_measurements ADD COLUMN boot_id bigint;
+CREATE UNIQUE INDEX station_measurements_sequence ON station_measurements (station_id, boot_id, sequence);
@@ -0,0 +1,7 @@
+CREATE TABLE station_check_ins (
+    station_id UUID NOT NULL REFERENCES stations (id) ON DELETE CASCADE,
+    idempotency_key TEXT NOT NULL,
+    result JSON NOT NULL,
+    occurred_on TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (station_id, idempotency_key)
+);
@@ -1,13 +1,21 @@
 package co.mycelium
 
 import co.mycelium.domain.{
+  CheckIn,
+  CheckInResult,
+  MeasurementRejection,
+  SensorStatistics,
   Station,
   StationDetails,
   StationEvent,
   StationInsert,
+  StationCapabilities,
+  StationInstruction,
   StationLog,
   StationMeasurement,
   StationUpdate,
+  Statistics,
+  TankLevel,
   Watering,
   WateringSchedule
 }
@@ -39,12 +47,28 @@ object CirceCodecs {
 
   implicit val codecWateringSchedule: Codec[WateringSchedule] = deriveConfiguredCodec
   implicit val codecStationEvent: Codec[StationEvent]         = deriveConfiguredCodec
+  implicit val codecStationInstruction: Codec[StationInstruction] = deriveConfiguredCodec
   implicit val codecStationLog: Codec[StationLog]             = deriveCodec
+  implicit val codecStatistics: Codec[Statistics]             = deriveCodec
+  implicit val codecSensorStatistics: Codec[SensorStatistics] = deriveCodec
   implicit val codecStationReading: Codec[StationMeasurement] = deriveCodec
+  implicit val codecCapabilities: Codec[StationCapabilities]  = deriveCodec
 
   implicit val codecInsert: Codec[StationInsert]          = deriveCodec
   implicit val codecUpdate: Codec[StationUpdate]          = deriveCodec
   implicit val codecWatering: Codec[Watering]             = deriveCodec
+  implicit val codecRejection: Codec[MeasurementRejection] = deriveCodec
+  implicit val codecCheckInResult: Codec[CheckInResult]   = deriveCodec
+
+  // stations which don't report their capabilities send a plain list of measurements
+  implicit val codecCheckIn: Codec[CheckIn] = {
+    val derived: Codec[CheckIn] = deriveCodec
+    Codec.from(
+      derived.or(Decoder[List[StationMeasurement]].map(CheckIn(_, None))),
+      derived
+    )
+  }
+  implicit val codecTankLevel: Codec[TankLevel]           = deriveCodec
   implicit val codecStation: Codec[Station]               = deriveCodec
   implicit val codecStationDetails: Codec[StationDetails] = deriveCodec
 }
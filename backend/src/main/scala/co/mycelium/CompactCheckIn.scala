@@ -0,0 +1,150 @@
+package co.mycelium
+
+import co.mycelium.domain.{CheckIn, StationCapabilities, StationMeasurement}
+import sttp.model.MediaType
+import sttp.tapir.{Codec, CodecFormat, DecodeResult}
+
+import java.nio.{BufferUnderflowException, ByteBuffer, ByteOrder}
+import java.time.Instant
+import scala.util.{Failure, Success, Try}
+
+/** The compact binary encoding of a check-in, which costs stations a fraction of the airtime of JSON. Little endian:
+  * a header of version (u8), measurements of the capabilities (u16, bit i is `fields(i)`), parts of the capabilities
+  * (u8, bit i is `parts(i)`), number of measurements (u16), time in milliseconds (i64), sequence (u32) and boot id
+  * (u32) of the first measurement. Followed by every measurement: presence of the values (u16, bit i is `fields(i)`,
+  * bit 15 when the boot id changed), milliseconds (i32) and sequence (i32) since the measurement before, the boot id
+  * (u32) when it changed and the present values as integers (i16 or i32) divided by their scale.
+  */
+object CompactCheckIn {
+  val mediaType: MediaType = MediaType("application", "vnd.mycelium.checkin.v1")
+
+  final case class Format() extends CodecFormat {
+    override val mediaType: MediaType = CompactCheckIn.mediaType
+  }
+
+  private final case class Field(name: String, scale: Double, wide: Boolean)
+
+  private val version = 1
+  private val newBootId = 1 << 15
+
+  private val fields = List(
+    Field("batteryVoltage", 1000, wide = false),
+    Field("batteryPercentage", 100, wide = false),
+    Field("temperature", 100, wide = false),
+    Field("humidity", 100, wide = false),
+    Field("lux", 100, wide = true),
+    Field("soilPf", 100, wide = true),
+    Field("tankPf", 100, wide = true),
+    Field("soilMoisture", 100, wide = false),
+    Field("tankFill", 100, wide = false)
+  )
+
+  private val parts = List("Sht3x", "Sht4x", "Bh1750")
+
+  private def values(m: StationMeasurement): List[Option[Double]] =
+    List(m.batteryVoltage, m.batteryPercentage, m.temperature, m.humidity, m.lux, m.soilPf, m.tankPf, m.soilMoisture, m.tankFill)
+
+  private def mask(present: List[Boolean]): Int =
+    present.zipWithIndex.collect { case (true, i) => 1 << i }.sum
+
+  def encode(checkIn: CheckIn): Array[Byte] = {
+    val measurements = checkIn.measurements
+    val capabilities = checkIn.capabilities.getOrElse(StationCapabilities(Nil, Nil))
+    val buffer       = ByteBuffer.allocate(22 + measurements.size * (14 + fields.size * 4)).order(ByteOrder.LITTLE_ENDIAN)
+
+    def sequence(m: StationMeasurement): Long = m.sequence.getOrElse(0L)
+    def bootId(m: StationMeasurement): Long   = m.bootId.getOrElse(0L)
+
+    buffer.put(version.toByte)
+    buffer.putShort(mask(fields.map(field => capabilities.measurements.contains(field.name))).toShort)
+    buffer.put(mask(parts.map(capabilities.parts.contains)).toByte)
+    buffer.putShort(measurements.size.toShort)
+    buffer.putLong(measurements.headOption.map(_.on.toEpochMilli).getOrElse(0L))
+    buffer.putInt(measurements.headOption.map(sequence).getOrElse(0L).toInt)
+    buffer.putInt(measurements.headOption.map(bootId).getOrElse(0L).toInt)
+
+    measurements.zip(measurements.headOption.toList ++ measurements).foreach { case (m, previous) =>
+      val bootChanged = bootId(m) != bootId(previous)
+
+      buffer.putShort((mask(values(m).map(_.isDefined)) | (if (bootChanged) newBootId else 0)).toShort)
+      buffer.putInt((m.on.toEpochMilli - previous.on.toEpochMilli).toInt)
+      buffer.putInt((sequence(m) - sequence(previous)).toInt)
+      if (bootChanged) buffer.putInt(bootId(m).toInt)
+
+      fields.zip(values(m)).foreach {
+        case (field, Some(value)) if field.wide => buffer.putInt(Math.round(value * field.scale).toInt)
+        case (field, Some(value))               => buffer.putShort(Math.round(value * field.scale).toShort)
+        case (_, None)                          => ()
+      }
+    }
+
+    buffer.array().take(buffer.position())
+  }
+
+  def decode(bytes: Array[Byte]): Either[String, CheckIn] = {
+    val buffer = ByteBuffer.wrap(bytes).order(ByteOrder.LITTLE_ENDIAN)
+
+    def unsignedShort(): Int = buffer.getShort() & 0xffff
+    def unsignedInt(): Long  = buffer.getInt() & 0xffffffffL
+
+    def measurement(on: Long, sequence: Long, bootId: Long): StationMeasurement = {
+      val presence = unsignedShort()
+      val time     = on + buffer.getInt()
+      val number   = sequence + buffer.getInt()
+      val boot     = if ((presence & newBootId) != 0) unsignedInt() else bootId
+      val decoded = fields.zipWithIndex.map { case (field, i) =>
+        if ((presence & (1 << i)) == 0) None
+        else Some((if (field.wide) buffer.getInt().toDouble else buffer.getShort().toDouble) / field.scale)
+      }
+
+      StationMeasurement(
+        on = Instant.ofEpochMilli(time),
+        batteryVoltage = decoded(0),
+        batteryPercentage = decoded(1),
+        temperature = decoded(2),
+        humidity = decoded(3),
+        lux = decoded(4),
+        soilPf = decoded(5),
+        tankPf = decoded(6),
+        soilMoisture = decoded(7),
+        tankFill = decoded(8),
+        statistics = None,
+        sequence = Some(number),
+        bootId = Some(boot)
+      )
+    }
+
+    Try {
+      if (buffer.get() != version) Left("Unsupported version")
+      else {
+        val measurementMask = unsignedShort()
+        val partMask        = buffer.get() & 0xff
+        val count           = unsignedShort()
+        val capabilities = StationCapabilities(
+          fields.zipWithIndex.collect { case (field, i) if (measurementMask & (1 << i)) != 0 => field.name },
+          parts.zipWithIndex.collect { case (part, i) if (partMask & (1 << i)) != 0 => part }
+        )
+
+        val first = (buffer.getLong(), unsignedInt(), unsignedInt())
+        val measurements = (0 until count)
+          .foldLeft((List.empty[StationMeasurement], first)) { case ((decoded, (on, sequence, bootId)), _) =>
+            val m = measurement(on, sequence, bootId)
+            (m :: decoded, (m.on.toEpochMilli, m.sequence.get, m.bootId.get))
+          }
+          ._1
+          .reverse
+
+        if (buffer.hasRemaining) Left("Trailing bytes") else Right(CheckIn(measurements, Some(capabilities)))
+      }
+    } match {
+      case Success(result)                       => result
+      case Failure(_: BufferUnderflowException) => Left("Truncated check-in")
+      case Failure(e)                            => Left(e.getMessage)
+    }
+  }
+
+  implicit val codec: Codec[Array[Byte], CheckIn, Format] =
+    Codec.byteArray
+      .mapDecode(bytes => DecodeResult.fromEitherString(s"${bytes.length} bytes", decode(bytes)))(encode)
+      .format(Format())
+}
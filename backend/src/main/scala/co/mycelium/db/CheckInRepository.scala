@@ -0,0 +1,30 @@
+package co.mycelium.db
+
+import cats.tagless.{Derive, FunctorK}
+import co.mycelium.domain._
+import doobie._
+import doobie.implicits._
+import doobie.postgres.implicits._
+
+import java.time.Instant
+import java.util.UUID
+
+/** Results of check-ins by their idempotency key, so a check-in which is sent again gets the same result */
+trait CheckInRepository[F[_]] {
+  def insert(stationId: UUID, idempotencyKey: String, result: CheckInResult, on: Instant): F[Int]
+  def find(stationId: UUID, idempotencyKey: String): F[Option[CheckInResult]]
+}
+
+object CheckInRepository {
+  implicit val functorK: FunctorK[CheckInRepository] = Derive.functorK
+}
+
+object DoobieCheckInRepository extends CheckInRepository[ConnectionIO] {
+  override def insert(stationId: UUID, idempotencyKey: String, result: CheckInResult, on: Instant): ConnectionIO[Int] =
+    sql"INSERT INTO station_check_ins (station_id, idempotency_key, result, occurred_on) VALUES ($stationId, $idempotencyKey, $result, $on) ON CONFLICT DO NOTHING".update.run
+
+  override def find(stationId: UUID, idempotencyKey: String): ConnectionIO[Option[CheckInResult]] =
+    sql"SELECT result FROM station_check_ins WHERE station_id = $stationId AND idempotency_key = $idempotencyKey"
+      .query[CheckInResult]
+      .option
+}
@@ -8,6 +8,7 @@ trait Repositories[F[_]] {
   def stationLog: StationLogRepository[F]
   def stations: StationRepository[F]
   def measurements: StationMeasurementRepository[F]
+  def checkIns: CheckInRepository[F]
 }
 
 object DoobieRepositories extends Repositories[ConnectionIO] {
@@ -15,6 +16,7 @@ object DoobieRepositories extends Repositories[ConnectionIO] {
   override def stations: StationRepository[ConnectionIO]      = DoobieStationRepository
   override def measurements: StationMeasurementRepository[ConnectionIO] =
     DoobieStationMeasurementRepository
+  override def checkIns: CheckInRepository[ConnectionIO] = DoobieCheckInRepository
 }
 
 object Repositories {
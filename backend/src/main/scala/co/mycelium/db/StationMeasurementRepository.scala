@@ -24,7 +24,7 @@ object DoobieStationMeasurementRepository extends StationMeasurementRepository[C
       measurements: List[StationMeasurement]
   ): ConnectionIO[Int] =
     Update[(UUID, StationMeasurement)](
-      "insert into station_measurements (station_id, occurred_on, battery_voltage, temperature, humidity, lux, soil_pf, tank_pf) values (?, ?, ?, ?, ?, ?, ?, ?)"
+      "insert into station_measurements (station_id, occurred_on, battery_voltage, battery_percentage, temperature, humidity, lux, soil_pf, tank_pf, soil_moisture, tank_fill, statistics, sequence, boot_id) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) on conflict do nothing"
     )
       .updateMany(measurements.map(x => (stationId, x)))
 
@@ -47,7 +47,7 @@ object DoobieStationMeasurementRepository extends StationMeasurementRepository[C
       case MeasurementPeriod.LastMonth           => 31
     }
 
-    fr"SELECT $timeBucket AS bucket, avg(battery_voltage) as battery_voltage, avg(temperature) as temperature, avg(humidity) as humidity, avg(lux) as lux, avg(soil_pf) as soil_pf, avg(tank_pf) as tank_pf FROM station_measurements GROUP BY bucket ORDER BY bucket ASC LIMIT $limit"
+    fr"SELECT $timeBucket AS bucket, avg(battery_voltage) as battery_voltage, avg(battery_percentage) as battery_percentage, avg(temperature) as temperature, avg(humidity) as humidity, avg(lux) as lux, avg(soil_pf) as soil_pf, avg(tank_pf) as tank_pf, avg(soil_moisture) as soil_moisture, avg(tank_fill) as tank_fill, NULL::json as statistics, NULL::bigint as sequence, NULL::bigint as boot_id FROM station_measurements GROUP BY bucket ORDER BY bucket ASC LIMIT $limit"
       .query[StationMeasurement]
       .to[List]
   }
@@ -16,8 +16,11 @@ trait StationRepository[F[_]] {
   def insert(station: Station, on: Instant): F[UUID]
   def listByUserId(userId: String): F[List[Station]]
   def findById(id: UUID, userId: String): F[Option[Station]]
-  def delete(id: UUID, userId: String): F[Int]
+  /** Deletes the station, keeping its id so a station which still checks in can be told it was deleted */
+  def delete(id: UUID, userId: String, on: Instant): F[Int]
+  def isDeleted(id: UUID, userId: String): F[Boolean]
   def update(id: UUID, userId: String, update: StationUpdate, now: Instant): F[Int]
+  def updateCapabilities(id: UUID, capabilities: StationCapabilities): F[Int]
 }
 
 object StationRepository {
@@ -28,7 +31,7 @@ object DoobieStationRepository extends StationRepository[ConnectionIO] {
   def insert(station: Station, on: Instant): ConnectionIO[UUID] = {
 
     def insertIntoStations =
-      sql"INSERT INTO stations (id, mac_addr, name, location, description, user_id, watering_schedule, created) VALUES (${station.id}, ${station.mac}, ${station.name}, ${station.location}, ${station.description}, ${station.userId}, ${station.wateringSchedule}, $on) on conflict on constraint unique_mac do update set updated = now(), name = excluded.name, description = excluded.description, location = excluded.location, user_id = excluded.user_id, watering_schedule = excluded.watering_schedule returning id".query[UUID]
+      sql"INSERT INTO stations (id, mac_addr, name, location, description, user_id, watering_schedule, capabilities, created) VALUES (${station.id}, ${station.mac}, ${station.name}, ${station.location}, ${station.description}, ${station.userId}, ${station.wateringSchedule}, ${station.capabilities}, $on) on conflict on constraint unique_mac do update set updated = now(), name = excluded.name, description = excluded.description, location = excluded.location, user_id = excluded.user_id, watering_schedule = excluded.watering_schedule, capabilities = excluded.capabilities returning id".query[UUID]
 
     for {
       id <- insertIntoStations.unique
@@ -37,17 +40,29 @@ object DoobieStationRepository extends StationRepository[ConnectionIO] {
   }
 
   def listByUserId(userId: String): ConnectionIO[List[Station]] =
-    sql"SELECT id, mac_addr, name, location, description, watering_schedule, user_id, created, updated FROM stations where user_id = $userId"
+    sql"SELECT id, mac_addr, name, location, description, watering_schedule, capabilities, user_id, created, updated FROM stations where user_id = $userId"
       .query[Station]
       .to[List]
 
   def findById(id: UUID, userId: String): ConnectionIO[Option[Station]] =
-    sql"SELECT id, mac_addr, name, location, description, watering_schedule, user_id, created, updated FROM stations WHERE id = $id AND user_id = $userId"
+    sql"SELECT id, mac_addr, name, location, description, watering_schedule, capabilities, user_id, created, updated FROM stations WHERE id = $id AND user_id = $userId"
       .query[Station]
       .option
 
-  def delete(id: UUID, userId: String): ConnectionIO[Int] =
-    sql"DELETE FROM stations WHERE id = $id AND user_id = $userId".update.run
+  def delete(id: UUID, userId: String, on: Instant): ConnectionIO[Int] =
+    for {
+      deleted <- sql"DELETE FROM stations WHERE id = $id AND user_id = $userId".update.run
+      _ <-
+        if (deleted > 0)
+          sql"INSERT INTO deleted_stations (id, user_id, deleted_on) VALUES ($id, $userId, $on) ON CONFLICT DO NOTHING".update.run
+        else Applicative[ConnectionIO].pure(0)
+    } yield deleted
+
+  def isDeleted(id: UUID, userId: String): ConnectionIO[Boolean] =
+    sql"SELECT EXISTS (SELECT 1 FROM deleted_stations WHERE id = $id AND user_id = $userId)".query[Boolean].unique
+
+  def updateCapabilities(id: UUID, capabilities: StationCapabilities): ConnectionIO[Int] =
+    sql"UPDATE stations SET capabilities = $capabilities WHERE id = $id".update.run
 
   override def update(
       id: UUID,
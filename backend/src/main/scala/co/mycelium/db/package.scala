@@ -19,6 +19,18 @@ package object db {
   implicit val getWateringSchedule: Get[WateringSchedule] =
     Get[Json].temap(_.as[WateringSchedule].leftMap(_.message))
 
+  implicit val putCapabilities: Put[StationCapabilities] = Put[Json].contramap(_.asJson)
+  implicit val getCapabilities: Get[StationCapabilities] =
+    Get[Json].temap(_.as[StationCapabilities].leftMap(_.message))
+
+  implicit val putSensorStatistics: Put[SensorStatistics] = Put[Json].contramap(_.asJson)
+  implicit val getSensorStatistics: Get[SensorStatistics] =
+    Get[Json].temap(_.as[SensorStatistics].leftMap(_.message))
+
+  implicit val putCheckInResult: Put[CheckInResult] = Put[Json].contramap(_.asJson)
+  implicit val getCheckInResult: Get[CheckInResult] =
+    Get[Json].temap(_.as[CheckInResult].leftMap(_.message))
+
   implicit val putStationEvent: Put[StationEvent] = Put[Json].contramap(_.asJson)
   implicit val getStationEvent: Get[StationEvent] =
     Get[Json].temap(_.as[StationEvent].leftMap(_.message))
@@ -0,0 +1,3 @@
+package co.mycelium.domain
+
+final case class CheckIn(measurements: List[StationMeasurement], capabilities: Option[StationCapabilities])
@@ -0,0 +1,17 @@
+package co.mycelium.domain
+
+import scala.concurrent.duration.FiniteDuration
+
+/** @param schedule the current watering schedule, which the station caches to keep watering when the backend is unreachable
+  * @param accepted the number of measurements stored, or already stored by an earlier check-in
+  * @param rejected the measurements which were not stored, together with `accepted` these acknowledge every measurement of the check-in
+  * @param encodings content types of check-ins accepted besides JSON, so the station can switch to a compact one
+  */
+final case class CheckInResult(
+    watering: Option[FiniteDuration],
+    instruction: Option[StationInstruction],
+    schedule: Option[WateringSchedule],
+    accepted: Int,
+    rejected: List[MeasurementRejection],
+    encodings: List[String]
+)
@@ -0,0 +1,6 @@
+package co.mycelium.domain
+
+import java.time.Instant
+
+/** A measurement of a check-in which was not stored. The station drops it rather than sending it again */
+final case class MeasurementRejection(sequence: Option[Long], on: Instant, reason: String)
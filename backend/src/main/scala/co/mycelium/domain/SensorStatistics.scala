@@ -0,0 +1,13 @@
+package co.mycelium.domain
+
+/** Spread of the samples a station took of a sensor for a single measurement, after dropping the outliers. */
+final case class Statistics(mean: Double, min: Double, max: Double, stddev: Double, samples: Int)
+
+/** Statistics per sensor, only sent by stations which are configured to report them. */
+final case class SensorStatistics(
+    temperature: Option[Statistics],
+    humidity: Option[Statistics],
+    lux: Option[Statistics],
+    soilPf: Option[Statistics],
+    tankPf: Option[Statistics]
+)
@@ -10,6 +10,7 @@ final case class Station(
     location: String,
     description: String,
     wateringSchedule: WateringSchedule,
+    capabilities: Option[StationCapabilities],
     userId: String,
     created: Instant,
     updated: Option[Instant]
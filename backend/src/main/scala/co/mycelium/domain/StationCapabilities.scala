@@ -0,0 +1,8 @@
+package co.mycelium.domain
+
+/** What a station can measure, so a missing sensor can be told apart from a reading of zero.
+  *
+  * @param measurements the measurement fields the station provides, e.g. `soilPf`
+  * @param parts the sensor parts the station detected, e.g. `Sht4x`
+  */
+final case class StationCapabilities(measurements: List[String], parts: List[String])
@@ -9,4 +9,6 @@ object StationEvent {
 
   case class Watered(period: FiniteDuration) extends StationEvent
 
+  case class LowWater(fillPercentage: Double) extends StationEvent
+
 }
@@ -8,7 +8,8 @@ final case class StationInsert(
     name: String,
     location: String,
     description: String,
-    wateringSchedule: WateringSchedule
+    wateringSchedule: WateringSchedule,
+    capabilities: Option[StationCapabilities]
 ) {
   def toStation(id: UUID, created: Instant, userId: String): Station =
     Station(
@@ -18,6 +19,7 @@ final case class StationInsert(
       location = location,
       description = description,
       wateringSchedule = wateringSchedule,
+      capabilities = capabilities,
       userId = userId,
       created = created,
       updated = None
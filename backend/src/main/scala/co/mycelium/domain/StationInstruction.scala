@@ -0,0 +1,7 @@
+package co.mycelium.domain
+
+sealed trait StationInstruction
+
+object StationInstruction {
+  case object FactoryReset extends StationInstruction
+}
@@ -4,10 +4,16 @@ import java.time.Instant
 
 final case class StationMeasurement(
     on: Instant,
-    batteryVoltage: Double,
-    temperature: Double,
-    humidity: Double,
-    lux: Double,
-    soilPf: Double,
-    tankPf: Double
+    batteryVoltage: Option[Double],
+    batteryPercentage: Option[Double],
+    temperature: Option[Double],
+    humidity: Option[Double],
+    lux: Option[Double],
+    soilPf: Option[Double],
+    tankPf: Option[Double],
+    soilMoisture: Option[Double],
+    tankFill: Option[Double],
+    statistics: Option[SensorStatistics],
+    sequence: Option[Long],
+    bootId: Option[Long]
 )
@@ -1,5 +1,7 @@
 package co.mycelium.domain
 
+import java.time.Instant
 import scala.concurrent.duration.FiniteDuration
 
-final case class Watering(watering: Option[FiniteDuration])
+/** @param on when the station watered, defaults to now. Set when the station reports waterings it queued while offline */
+final case class Watering(watering: Option[FiniteDuration], on: Option[Instant])
@@ -2,18 +2,20 @@ package co.mycelium.endpoints
 
 import cats.effect.IO
 import co.mycelium.CirceCodecs._
+import co.mycelium.CompactCheckIn
 import co.mycelium.db.Repositories
 import co.mycelium.domain._
 import cron4s.CronExpr
 import cron4s.lib.javatime.javaTemporalInstance
 import org.http4s.HttpRoutes
+import sttp.model.StatusCode
 import sttp.tapir._
 import sttp.tapir.generic.Configuration
 import sttp.tapir.generic.auto._
 import sttp.tapir.json.circe._
 import sttp.tapir.server.http4s.Http4sServerInterpreter
 
-import java.time.Instant
+import java.time.{Duration, Instant}
 import java.util.UUID
 import scala.concurrent.duration.FiniteDuration
 
@@ -34,18 +36,45 @@ object Stations extends TapirSchemas {
       .in(path[UUID]("stationId"))
       .in("checkin")
       .put
-      .in(jsonBody[List[StationMeasurement]])
-      .out(jsonBody[Watering])
-    val watered = stations.in(path[UUID]("stationId")).in("watered").post.in(jsonBody[Watering])
+      .in(header[Option[String]]("Idempotency-Key"))
+      .in(
+        oneOfBody(
+          jsonBody[CheckIn],
+          EndpointIO.Body(RawBodyType.ByteArrayBody, CompactCheckIn.codec, EndpointIO.Info.empty[CheckIn])
+        )
+      )
+      .out(jsonBody[CheckInResult])
+      .errorOut(statusCode(StatusCode.NotFound))
+    val watered = stations
+      .in(path[UUID]("stationId"))
+      .in("watered")
+      .post
+      .in(jsonBody[Watering])
+      .errorOut(statusCode(StatusCode.NotFound))
+    val lowWater = stations
+      .in(path[UUID]("stationId"))
+      .in("lowwater")
+      .post
+      .in(jsonBody[TankLevel])
+      .errorOut(statusCode(StatusCode.NotFound))
     val log = stations
       .in(path[UUID]("stationId"))
       .in("log")
       .in(query[Option[Long]]("page"))
       .out(jsonBody[List[StationLog]])
 
-    val all = Set(list, add, details, update, delete, checkIn, watered, log)
+    val all = Set(list, add, details, update, delete, checkIn, watered, lowWater, log)
   }
 
+  /** A station which was deleted, e.g. from the app, is told to forget its registration. Any other station which
+    * isn't found, e.g. because of a bug or a mixed up database, keeps its registration and gets a 404.
+    */
+  def unknownStation(repos: Repositories[IO], id: UUID, userId: String): IO[Either[Unit, CheckInResult]] =
+    repos.stations.isDeleted(id, userId).map {
+      case true  => Right(CheckInResult(None, Some(StationInstruction.FactoryReset), None, 0, Nil, Nil))
+      case false => Left(())
+    }
+
   def routes(repos: Repositories[IO]): HttpRoutes[IO] = {
 
     val list =
@@ -60,47 +89,111 @@ object Stations extends TapirSchemas {
     }
 
     val delete =
-      endpoints.delete.serverLogic(at => id => repos.stations.delete(id, at.sub).as(Right(())))
+      endpoints.delete.serverLogic(at => id => repos.stations.delete(id, at.sub, Instant.now()).as(Right(())))
+
+    val encodings = List(CompactCheckIn.mediaType.toString)
+
+    // measurements before this were taken before the station synchronized its clock
+    val earliestMeasurement = Instant.parse("2023-01-01T00:00:00Z")
+
+    def validate(measurement: StationMeasurement, now: Instant): Option[String] =
+      if (measurement.on.isBefore(earliestMeasurement)) Some("Clock not synchronized")
+      else if (measurement.on.isAfter(now.plus(Duration.ofDays(1)))) Some("Taken in the future")
+      else None
+
+    def checkInStation(
+        id: UUID,
+        station: Station,
+        received: List[StationMeasurement],
+        capabilities: Option[StationCapabilities]
+    ): IO[CheckInResult] = {
+      val now = Instant.now()
+      val (rejected, measurements) = received.partitionMap { measurement =>
+        validate(measurement, now) match {
+          case Some(reason) => Left(MeasurementRejection(measurement.sequence, measurement.on, reason))
+          case None         => Right(measurement)
+        }
+      }
 
-    val checkin = endpoints.checkIn.serverLogic { at =>
-      { case (id, measurements) =>
-        for {
-          stationOpt <- repos.stations.findById(id, at.sub)
-          _          <- repos.measurements.insertMany(id, measurements)
-          watering <- stationOpt match {
-            case Some(station) =>
-              station.wateringSchedule match {
-                case WateringSchedule.Interval(schedule, period) =>
-                  repos.stationLog.lastTimeWatered(id).flatMap {
-                    case Some(lastTime) =>
-                      schedule.next(lastTime) match {
-                        case Some(nextTime) if Instant.now().isAfter(nextTime) =>
-                          IO(Watering(Some(period)))
-                        case None => IO(Watering(Some(period)))
-                        case _    => IO(Watering(None))
-                      }
-                    case None => IO(Watering(None))
-                  }
-
-                case WateringSchedule.Threshold(belowSoilPf, period) =>
-                  if (measurements.lastOption.exists(_.soilPf < belowSoilPf))
-                    IO(Watering(Some(period)))
-                  else IO(Watering(None))
+      val watering = station.wateringSchedule match {
+        case WateringSchedule.Interval(schedule, period) =>
+          repos.stationLog.lastTimeWatered(id).flatMap {
+            case Some(lastTime) =>
+              schedule.next(lastTime) match {
+                case Some(nextTime) if Instant.now().isAfter(nextTime) =>
+                  IO(Some(period))
+                case None => IO(Some(period))
+                case _    => IO(None)
               }
-            case None => IO(Watering(None))
+            case None => IO(None)
           }
-        } yield Right(watering)
+
+        case WateringSchedule.Threshold(belowSoilPf, period) =>
+          if (measurements.lastOption.flatMap(_.soilPf).exists(_ < belowSoilPf))
+            IO(Some(period))
+          else IO(None)
+      }
+
+      val updateCapabilities = capabilities match {
+        case Some(c) if !station.capabilities.contains(c) => repos.stations.updateCapabilities(id, c).void
+        case _                                              => IO.unit
+      }
+
+      // measurements which were stored before are skipped, but still count as accepted
+      repos.measurements.insertMany(id, measurements) *> updateCapabilities *>
+        watering.map(w => CheckInResult(w, None, Some(station.wateringSchedule), measurements.size, rejected, encodings))
+    }
+
+    val checkin = endpoints.checkIn.serverLogic { at =>
+      { case (id, idempotencyKey, CheckIn(measurements, capabilities)) =>
+        repos.stations.findById(id, at.sub).flatMap {
+          case Some(station) =>
+            idempotencyKey match {
+              case Some(key) =>
+                // a check-in which is sent again, e.g. because the response got lost, gets the result of the first
+                repos.checkIns.find(id, key).flatMap {
+                  case Some(result) => IO(Right(result))
+                  case None =>
+                    checkInStation(id, station, measurements, capabilities).flatMap { result =>
+                      repos.checkIns.insert(id, key, result, Instant.now()).as(Right(result))
+                    }
+                }
+              case None =>
+                checkInStation(id, station, measurements, capabilities).map(Right(_))
+            }
+
+          case None =>
+            unknownStation(repos, id, at.sub)
+        }
       }
     }
 
     val watered = endpoints.watered.serverLogic { at =>
       { case (id, request) =>
-        request.watering match {
-          case Some(watered) =>
+        repos.stations.findById(id, at.sub).flatMap {
+          case Some(_) =>
+            request.watering match {
+              case Some(watered) =>
+                repos.stationLog
+                  .insert(StationLog(id, request.on.getOrElse(Instant.now()), StationEvent.Watered(watered)))
+                  .as(Right(()))
+              case None => IO.unit.as(Right(()))
+            }
+          case None =>
+            IO.delay(Left(()))
+        }
+      }
+    }
+
+    val lowWater = endpoints.lowWater.serverLogic { at =>
+      { case (id, level) =>
+        repos.stations.findById(id, at.sub).flatMap {
+          case Some(_) =>
             repos.stationLog
-              .insert(StationLog(id, Instant.now(), StationEvent.Watered(watered)))
+              .insert(StationLog(id, Instant.now(), StationEvent.LowWater(level.fillPercentage)))
               .as(Right(()))
-          case None => IO.unit.as(Right(()))
+          case None =>
+            IO.delay(Left(()))
         }
       }
     }
@@ -131,7 +224,7 @@ object Stations extends TapirSchemas {
     }
 
     Http4sServerInterpreter[IO]().toRoutes(
-      List(list, add, delete, log, watered, checkin, details, update)
+      List(list, add, delete, log, watered, lowWater, checkin, details, update)
     )
   }
 }
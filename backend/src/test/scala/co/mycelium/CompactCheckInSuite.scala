@@ -0,0 +1,65 @@
+package co.mycelium
+
+import co.mycelium.domain.{CheckIn, StationCapabilities, StationMeasurement}
+
+import java.time.Instant
+
+class CompactCheckInSuite extends munit.FunSuite {
+
+  /** Encoded by the tests of `compact.rs` in the firmware, keep the two in sync. */
+  private val encoded =
+    """01 31 00 06 03 00 2d 3a 22 34 8f 01 00 00 0a 00 00 00 ef be ad de bf 00 00 00 00 00 00 00
+      |00 00 48 0f 52 1c c6 fe ae 15 3a f4 7b 00 40 e2 01 00 94 11 b7 00 fb d2 01 00 01 00 00 00 48 0f 52 1c c6 fe 3a
+      |f4 7b 00 40 e2 01 00 94 11 bf 80 f9 9b 02 00 21 01 00 00 07 00 00 00 48 0f 52 1c c6 fe 64 00 3a f4 7b 00 40 e2
+      |01 00 94 11""".stripMargin.split("\\s+").map(Integer.parseInt(_, 16).toByte)
+
+  private def measurement(on: String, sequence: Long, bootId: Long, humidity: Option[Double]) =
+    StationMeasurement(
+      on = Instant.parse(on),
+      batteryVoltage = Some(3.912),
+      batteryPercentage = Some(72.5),
+      temperature = Some(-3.14),
+      humidity = humidity,
+      lux = Some(81234.5),
+      soilPf = Some(1234.56),
+      tankPf = None,
+      soilMoisture = Some(45.0),
+      tankFill = None,
+      statistics = None,
+      sequence = Some(sequence),
+      bootId = Some(bootId)
+    )
+
+  private val checkIn = CheckIn(
+    List(
+      measurement("2024-05-01T12:30:09.453Z", 10, 0xdeadbeefL, Some(55.5)),
+      measurement("2024-05-01T12:32:09.000Z", 11, 0xdeadbeefL, None),
+      measurement("2024-05-01T12:35:00.001Z", 300, 7, Some(1.0))
+    ),
+    Some(StationCapabilities(List("batteryVoltage", "lux", "soilPf"), List("Sht4x", "Bh1750")))
+  )
+
+  test("decodes the check-in encoded by the firmware") {
+    assertEquals(CompactCheckIn.decode(encoded), Right(checkIn))
+  }
+
+  test("encodes the check-in like the firmware") {
+    assertEquals(CompactCheckIn.encode(checkIn).toList, encoded.toList)
+  }
+
+  test("decodes what it encodes") {
+    assertEquals(CompactCheckIn.decode(CompactCheckIn.encode(checkIn)), Right(checkIn))
+  }
+
+  test("rejects a truncated check-in") {
+    assertEquals(CompactCheckIn.decode(encoded.dropRight(1)), Left("Truncated check-in"))
+  }
+
+  test("rejects trailing bytes") {
+    assertEquals(CompactCheckIn.decode(encoded :+ 0.toByte), Left("Trailing bytes"))
+  }
+
+  test("rejects an unsupported version") {
+    assertEquals(CompactCheckIn.decode(2.toByte +: encoded.tail), Left("Unsupported version"))
+  }
+}
@@ -0,0 +1,47 @@
+package co.mycelium
+
+import cron4s.Cron
+import cron4s.lib.javatime._
+
+import java.time.LocalDateTime
+import scala.concurrent.duration._
+import scala.util.Try
+
+class CronScheduleSuite extends munit.FunSuite {
+
+  /** Also checked by the tests of `schedule.rs` in the firmware, keep the two in sync. */
+  private val next = List(
+    ("0 */15 * * * ?", "2024-05-01T12:30:09", Some("2024-05-01T12:45:00")),
+    ("9 30 12 * * ?", "2024-05-01T12:30:09", Some("2024-05-02T12:30:09")),
+    ("0 0 6,18 * * ?", "2024-05-01T12:30:09", Some("2024-05-01T18:00:00")),
+    ("0 0 9-17/4 * * ?", "2024-05-01T12:30:09", Some("2024-05-01T13:00:00")),
+    ("30 5-59/20 * * * ?", "2024-05-01T12:30:09", Some("2024-05-01T12:45:30")),
+    ("0 0 0 1 * ?", "2024-05-01T12:30:09", Some("2024-06-01T00:00:00")),
+    ("0 0 0 * 1 ?", "2024-05-01T12:30:09", Some("2025-01-01T00:00:00")),
+    ("0 0 12 29 2 ?", "2024-05-01T12:30:09", Some("2028-02-29T12:00:00")),
+    ("0 0 8 ? * 5", "2024-05-01T12:30:09", Some("2024-05-04T08:00:00")),
+    ("0 0 12 13 * 4", "2024-05-01T12:30:09", Some("2024-09-13T12:00:00"))
+  )
+
+  next.foreach { case (expression, after, expected) =>
+    test(s"$expression after $after is the same time as on the station") {
+      val schedule = Cron.parse(expression).fold(throw _, identity)
+
+      assertEquals(schedule.next(LocalDateTime.parse(after)), expected.map(LocalDateTime.parse))
+    }
+  }
+
+  test("parses the durations the station parses") {
+    assertEquals(Duration("5 seconds"), 5.seconds)
+    assertEquals(Duration("1.5min"), 90.seconds)
+    assertEquals(Duration("2 hrs"), 2.hours)
+    assertEquals(Duration("250 millis"), 250.millis)
+    assertEquals(Duration("1 d"), 1.day)
+    assertEquals(Duration("106751 days"), 106751.days)
+  }
+
+  test("rejects the durations the station rejects as out of range") {
+    assert(Try(Duration("106752 days")).isFailure)
+    assert(Try(Duration("99999999999999999999999 days")).isFailure)
+  }
+}
@@ -0,0 +1,44 @@
+package co.mycelium
+
+import cats.effect.IO
+import cats.effect.unsafe.implicits.global
+import co.mycelium.db._
+import co.mycelium.domain._
+import co.mycelium.endpoints.Stations
+
+import java.time.Instant
+import java.util.UUID
+
+class StationsSuite extends munit.FunSuite {
+
+  private val deletedId = UUID.fromString("0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0")
+
+  /** Knows a single deleted station and no others */
+  private object stations extends StationRepository[IO] {
+    def insert(station: Station, on: Instant): IO[UUID]                                = ???
+    def listByUserId(userId: String): IO[List[Station]]                                = IO.pure(Nil)
+    def findById(id: UUID, userId: String): IO[Option[Station]]                        = IO.pure(None)
+    def delete(id: UUID, userId: String, on: Instant): IO[Int]                         = ???
+    def isDeleted(id: UUID, userId: String): IO[Boolean]                               = IO.pure(id == deletedId && userId == "user")
+    def update(id: UUID, userId: String, update: StationUpdate, now: Instant): IO[Int] = ???
+    def updateCapabilities(id: UUID, capabilities: StationCapabilities): IO[Int]       = ???
+  }
+
+  private val repos = new Repositories[IO] {
+    def stationLog: StationLogRepository[IO]           = ???
+    def stations: StationRepository[IO]                = StationsSuite.this.stations
+    def measurements: StationMeasurementRepository[IO] = ???
+    def checkIns: CheckInRepository[IO]                = ???
+  }
+
+  test("tells a deleted station to forget its registration") {
+    val result = Stations.unknownStation(repos, deletedId, "user").unsafeRunSync()
+
+    assertEquals(result.map(_.instruction), Right(Some(StationInstruction.FactoryReset)))
+  }
+
+  test("does not reset a station which isn't known to be deleted") {
+    assertEquals(Stations.unknownStation(repos, UUID.randomUUID(), "user").unsafeRunSync(), Left(()))
+    assertEquals(Stations.unknownStation(repos, deletedId, "someone else").unsafeRunSync(), Left(()))
+  }
+}
@@ -0,0 +1,397 @@
+//! Wires the drivers to the logic, on the ESP32 only.
+
+use std::time::{Duration};
+
+
+use esp_idf_hal::adc::ADC1;
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::{Gpio21, Gpio22, Gpio35, IOPin, OutputPin};
+use esp_idf_hal::i2c::{I2C0, I2cConfig, I2cDriver};
+use esp_idf_hal::modem::Modem;
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::ulp::ULP;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+
+use esp_idf_svc::wifi::EspWifi;
+use esp_idf_sys::*;
+use log::{error, info, warn};
+use retry::delay::Fixed;
+use retry::retry;
+
+use crate::{ble, tank, ui, watering};
+use crate::auth0::EspAuth0;
+#[cfg(not(feature = "mock-sensors"))]
+use crate::battery::AdcBattery;
+#[cfg(feature = "mock-sensors")]
+use crate::battery::MockVoltageSensor;
+use crate::battery::{BatteryConfig, BatteryLevel, measure_battery};
+use crate::ble::{BleMaintenance, BleOnboarding};
+use crate::device::EspDevice;
+use crate::environment::EnvironmentSensors;
+use crate::http::EspHttpClient;
+use crate::wifi::EspMyceliumWifi;
+use crate::kv::NvsKvStore;
+use crate::capacitance::{CapacitanceMeter, MeterConfig, Probes};
+#[cfg(not(feature = "mock-sensors"))]
+use crate::capacitance::TouchPad;
+#[cfg(feature = "mock-sensors")]
+use crate::capacitance::MockCapacitanceSensor;
+use crate::clock::{Clock, EspClock, SyncPolicy};
+use crate::maintenance::MaintenanceController;
+use crate::pump::{GpioPump, PumpController, PumpLimits};
+use crate::rtc::RtcState;
+use crate::onboarding::{AppError, OnboardingController};
+use crate::mycelium::{CheckInResult, EspMyceliumBackend, StationInstruction, WateringSchedule};
+use crate::sensors::{SamplingConfig, Sensors, StationSensors};
+use crate::settings::FlashState;
+use crate::station::{check_in, factory_reset, read_measurement, sample, sampled_while_sleeping};
+use crate::ui::{Button, LedPattern, Press, StatusLed};
+#[cfg(not(feature = "mock-sensors"))]
+use crate::ulp::UlpConfig;
+use crate::ulp::UlpSampler;
+
+/// How long the maintenance service is advertised after a power-on or a long press before continuing with the measurement.
+const MAINTENANCE_WINDOW: Duration = Duration::from_secs(120);
+/// How often the live sensor values are sampled during maintenance
+const LIVE_SENSORS_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the error class is shown on the LED before going back to sleep.
+const ERROR_DISPLAY: Duration = Duration::from_secs(6);
+/// How long the station sleeps between measurements when the ULP doesn't sample in between.
+const WAKE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[cfg(not(feature = "mock-sensors"))]
+type Probe = TouchPad;
+#[cfg(feature = "mock-sensors")]
+type Probe = MockCapacitanceSensor;
+
+type EspSensors = StationSensors<Probe, I2cDriver<'static>, FreeRtos>;
+
+pub fn main() -> ! {
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_sys::link_patches();
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let nvs_partition = EspDefaultNvsPartition::take().unwrap();
+    let nvs = EspDefaultNvs::new(nvs_partition, "mycelium", true).unwrap();
+    let kv = NvsKvStore::new(nvs);
+    let flash_state = FlashState::new(kv);
+
+    if flash_state.has_station_id().unwrap() {
+        operational(&flash_state)
+    } else {
+        onboarding(flash_state)
+    }
+}
+
+fn operational(flash_state: &FlashState<NvsKvStore>) -> ! {
+
+    let wakes = RtcState::update(|state| {
+        if unsafe { esp_reset_reason() } != esp_reset_reason_t_ESP_RST_DEEPSLEEP {
+            state.boot_id = unsafe { esp_random() };
+        }
+
+        state.wakes += 1;
+        state.wakes_since_check_in += 1;
+        (state.wakes, state.wakes_since_check_in)
+    });
+    info!("Wake {}, {} since the last check-in", wakes.0, wakes.1);
+
+    let peripherals = Peripherals::take().unwrap();
+    let button = Button::new(peripherals.pins.gpio0.downgrade()).unwrap();
+    let led = StatusLed::start(peripherals.pins.gpio2.downgrade_output()).unwrap();
+    // the board has no sense input for the pump, a pump which keeps running is noticed from the tank draining instead
+    let pump = GpioPump::new(peripherals.pins.gpio26.downgrade_output(), None).unwrap();
+    let mut ulp = ulp(peripherals.ulp).unwrap();
+    let sensors = sensors(peripherals.adc1, peripherals.pins.gpio35, peripherals.i2c0, peripherals.pins.gpio21, peripherals.pins.gpio22).unwrap();
+    let modem = peripherals.modem;
+    let sysloop = EspSystemEventLoop::take().unwrap();
+    let esp_wifi = EspWifi::new(modem, sysloop.clone(), None).unwrap();
+    let wifi = EspMyceliumWifi::new(sysloop, esp_wifi);
+    let http = EspHttpClient::new().unwrap();
+    let auth = EspAuth0::new(http.clone());
+    let backend = EspMyceliumBackend::new(http);
+    let clock = EspClock::new(Some(backend.clone()), SyncPolicy::default());
+    let mut pump = PumpController::new(pump, clock.clone(), flash_state.clone(), PumpLimits::default());
+
+    let press = if ui::woken_by_button() { Some(button.press(&led)) } else { None };
+
+    let enter_maintenance = match press {
+        Some(Press::VeryLong) => {
+            led.show(LedPattern::FACTORY_RESET);
+            factory_reset(flash_state, &wifi, &auth, &backend, &clock, &EspDevice).unwrap();
+            false
+        }
+        Some(Press::Long) => true,
+        Some(Press::Short) => false,
+        // not after a crash or a watchdog reset, so a fault doesn't keep the station advertising instead of watering
+        None => unsafe { esp_reset_reason() } == esp_reset_reason_t_ESP_RST_POWERON
+    };
+
+    if enter_maintenance {
+        led.show(LedPattern::MAINTENANCE);
+        maintenance(flash_state, &wifi, &auth, &backend, &clock, &sensors);
+        led.show(LedPattern::Off);
+    }
+
+    // the samples taken while sleeping are numbered first, as they are older
+    let mut measurements = match &ulp {
+        Some(ulp) => sampled_while_sleeping(flash_state, &ulp.readings().unwrap()).unwrap(),
+        None => Vec::new()
+    };
+    let measurement = sample(flash_state, &clock, &sensors).unwrap();
+    let capabilities = sensors.capabilities();
+    let pending = RtcState::load().pending();
+    measurements.push(measurement.clone());
+
+    let result = retry(Fixed::from_millis(1000).take(2), || {
+        let all = pending.iter().chain(measurements.iter()).cloned().collect();
+        check_in(&flash_state, &wifi, &auth, &backend, &clock, all, capabilities.clone())
+    });
+
+    // measurements which were not acknowledged are kept in RTC memory for the next check-in, sending them again is
+    // safe as the backend skips the ones it already stored
+    RtcState::update(|state| match &result {
+        Ok(result) if result.acknowledges(pending.len() + measurements.len()) => state.clear_pending(),
+        Ok(_) => {
+            warn!("Check-in not acknowledged, keeping the measurements");
+            state.push_pending(&measurements)
+        }
+        Err(_) => state.push_pending(&measurements)
+    });
+
+    if let Some(ulp) = &mut ulp {
+        ulp.clear().unwrap();
+    }
+
+    let due = match &result {
+        Ok(result) => result.watering.clone(),
+        Err(_) => watering::local_watering(flash_state, &clock, &measurement).unwrap_or_else(|err| {
+            error!("Could not evaluate the cached schedule: {:?}", err);
+            None
+        })
+    };
+
+    // the tank fill of the previous wake is only kept when the pump didn't run since
+    let idle_tank_fill = RtcState::update(|state| {
+        let previous = state.idle_tank_fill();
+        state.set_idle_tank_fill(if due.is_some() { None } else { measurement.tank_fill });
+        previous
+    });
+
+    if let Err(err) = pump.check_idle(idle_tank_fill, measurement.tank_fill) {
+        error!("Pump fault: {:?}", err);
+        led.show(LedPattern::error(&AppError::Pump(err)));
+        std::thread::sleep(ERROR_DISPLAY);
+    }
+
+    if let Some(period) = due {
+        if let Err(err) = watering::water(flash_state, &clock, &mut pump, period, measurement.tank_fill) {
+            error!("Watering failed: {:?}", err);
+            led.show(LedPattern::error(&err));
+            std::thread::sleep(ERROR_DISPLAY);
+        }
+    }
+
+    if result.is_ok() {
+        if let Err(err) = watering::report_waterings(flash_state, &auth, &backend, &clock) {
+            error!("Could not report waterings: {:?}", err);
+        }
+
+        if let Some(tank_fill) = measurement.tank_fill {
+            if let Err(err) = tank::alert_low_water(flash_state, &auth, &backend, &clock, tank_fill) {
+                error!("Could not report the tank level: {:?}", err);
+            }
+        }
+    }
+
+    match result {
+        Ok(CheckInResult { instruction: Some(StationInstruction::FactoryReset), .. }) => {
+            info!("Station was deleted at the backend");
+            led.show(LedPattern::FACTORY_RESET);
+            factory_reset(flash_state, &wifi, &auth, &backend, &clock, &EspDevice).unwrap();
+        },
+        Ok(_) => {
+            // skips the flash write on the wakes which had no errors to reset
+            if flash_state.get_num_errors().unwrap() > 0 {
+                flash_state.reset_errors().unwrap();
+            }
+        },
+        Err(err) => {
+            error!("Error: {:?}", err);
+            flash_state.increment_errors().unwrap();
+            led.show(LedPattern::error(&err.error));
+            std::thread::sleep(ERROR_DISPLAY);
+        }
+    }
+
+    if flash_state.get_num_errors().unwrap() == 10 {
+        flash_state.erase_settings().unwrap();
+        RtcState::clear();
+    }
+
+    button.enable_wakeup().unwrap();
+
+    let sleep = match &mut ulp {
+        Some(ulp) => {
+            let dry_below_pf = match flash_state.get_opt_watering_schedule().unwrap() {
+                Some(WateringSchedule::Threshold { below_soil_pf, .. }) => Some(below_soil_pf as f64),
+                _ => None
+            };
+
+            ulp.start(dry_below_pf, clock.now()).unwrap();
+            ulp.config().upload_interval
+        }
+        None => WAKE_INTERVAL
+    };
+
+    unsafe {
+        esp_sleep_enable_timer_wakeup(sleep.as_micros() as u64);
+
+        if ulp.is_none() {
+            esp_sleep_pd_config(esp_sleep_pd_domain_t_ESP_PD_DOMAIN_RTC_PERIPH, esp_sleep_pd_option_t_ESP_PD_OPTION_OFF);
+        }
+
+        esp_sleep_pd_config(esp_sleep_pd_domain_t_ESP_PD_DOMAIN_XTAL, esp_sleep_pd_option_t_ESP_PD_OPTION_OFF);
+        esp_deep_sleep_disable_rom_logging();
+        esp_deep_sleep_start();
+    }
+}
+
+/// Soil probe on touch pad 8 (GPIO33), tank probe on touch pad 9 (GPIO32).
+#[cfg(not(feature = "mock-sensors"))]
+fn probes() -> Result<Probes<Probe>, EspError> {
+    Ok(Probes::new(
+        Some(CapacitanceMeter::new(TouchPad::new(touch_pad_t_TOUCH_PAD_NUM8)?, MeterConfig::default())),
+        Some(CapacitanceMeter::new(TouchPad::new(touch_pad_t_TOUCH_PAD_NUM9)?, MeterConfig::default()))
+    ))
+}
+
+/// Replays noisy readings with the occasional spike, for a development board without probes.
+#[cfg(feature = "mock-sensors")]
+fn probes() -> Result<Probes<Probe>, EspError> {
+    Ok(Probes::new(
+        Some(CapacitanceMeter::new(MockCapacitanceSensor::new(vec![812.0, 798.0, 805.0, 1950.0, 801.0, 795.0]), MeterConfig::default())),
+        Some(CapacitanceMeter::new(MockCapacitanceSensor::new(vec![1010.0, 990.0, 40.0, 1003.0, 997.0]), MeterConfig::default()))
+    ))
+}
+
+/// Samples the soil probe and the battery while sleeping.
+#[cfg(not(feature = "mock-sensors"))]
+fn ulp(ulp: ULP) -> Result<Option<UlpSampler>, EspError> {
+    UlpSampler::new(ulp, UlpConfig::default(), BatteryConfig::default()).map(Some)
+}
+
+/// The ULP reads the real touch pad and ADC, so it doesn't sample without the probes and the battery.
+#[cfg(feature = "mock-sensors")]
+fn ulp(_ulp: ULP) -> Result<Option<UlpSampler>, EspError> {
+    Ok(None)
+}
+
+/// Battery behind a divider on GPIO35.
+#[cfg(not(feature = "mock-sensors"))]
+fn battery(adc: ADC1, pin: Gpio35) -> Result<BatteryLevel, EspError> {
+    measure_battery(&mut AdcBattery::new(adc, pin)?, &BatteryConfig::default())
+}
+
+/// A half charged LiPo cell, for a development board without a battery.
+#[cfg(feature = "mock-sensors")]
+fn battery(_adc: ADC1, _pin: Gpio35) -> Result<BatteryLevel, EspError> {
+    measure_battery(&mut MockVoltageSensor::new(1.92), &BatteryConfig::default())
+}
+
+/// The battery, the probes and the environment sensors detected on I2C (SDA GPIO21, SCL GPIO22).
+/// Has to be called before WiFi is started, as the battery is only measured here.
+fn sensors(adc: ADC1, battery_pin: Gpio35, i2c: I2C0, sda: Gpio21, scl: Gpio22) -> Result<EspSensors, EspError> {
+    let battery = battery(adc, battery_pin).map_err(|err| error!("Could not measure the battery: {:?}", err)).ok();
+    let i2c = I2cDriver::new(i2c, sda, scl, &I2cConfig::new().baudrate(100.kHz().into()))?;
+
+    Ok(StationSensors::new(battery, probes()?, EnvironmentSensors::detect(i2c, FreeRtos), SamplingConfig::default()))
+}
+
+/// Serves the maintenance service until the client exits or no command arrived within the maintenance window.
+fn maintenance(flash_state: &FlashState<NvsKvStore>, wifi: &EspMyceliumWifi, auth: &EspAuth0, backend: &EspMyceliumBackend, clock: &EspClock<EspMyceliumBackend>, sensors: &EspSensors) {
+    let passkey = match ble::passkey() {
+        Ok(passkey) => passkey,
+        Err(err) => {
+            error!("No BLE passkey, skipping maintenance: {:?}", err);
+            return
+        }
+    };
+    let ble = match BleMaintenance::start(passkey) {
+        Ok(ble) => ble,
+        Err(err) => {
+            error!("Could not start the maintenance service: {:?}", err);
+            return
+        }
+    };
+    let controller = MaintenanceController::new(flash_state.clone(), wifi.clone(), auth.clone(), backend.clone(), clock.clone(), EspDevice, sensors.clone());
+    let mut idle = Duration::ZERO;
+
+    controller.subscribe_responses(ble.rpc().response_subscriber());
+
+    info!("Maintenance service available for {:?}", MAINTENANCE_WINDOW);
+
+    while idle < MAINTENANCE_WINDOW {
+        ble.set_sensors(&read_measurement(flash_state, clock, sensors).ok());
+
+        match ble.rpc().recv_timeout(LIVE_SENSORS_INTERVAL) {
+            Some(bytes) => {
+                idle = Duration::ZERO;
+
+                if !controller.process(&bytes) {
+                    break;
+                }
+            }
+            None => idle += LIVE_SENSORS_INTERVAL
+        }
+    }
+
+    info!("Leaving maintenance");
+    ble.stop();
+}
+
+/// Serves onboarding until the station restarts once onboarded. A station which can't be onboarded, e.g. because it
+/// has no passkey, shows the error and stops there rather than restarting over and over.
+fn onboarding(flash_state: FlashState<NvsKvStore>) -> ! {
+    let peripherals = Peripherals::take().unwrap();
+    let led = StatusLed::start(peripherals.pins.gpio2.downgrade_output()).unwrap();
+    let served = sensors(peripherals.adc1, peripherals.pins.gpio35, peripherals.i2c0, peripherals.pins.gpio21, peripherals.pins.gpio22)
+        .map_err(AppError::from)
+        .and_then(|sensors| serve_onboarding(flash_state, sensors, peripherals.modem, &led));
+
+    if let Err(err) = served {
+        error!("Onboarding failed: {:?}", err);
+        led.show(LedPattern::error(&err));
+    }
+
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
+fn serve_onboarding(flash_state: FlashState<NvsKvStore>, sensors: EspSensors, modem: Modem, led: &StatusLed) -> Result<(), AppError> {
+    let passkey = ble::passkey()?;
+    let sysloop = EspSystemEventLoop::take()?;
+    let esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
+    let wifi = EspMyceliumWifi::new(sysloop, esp_wifi);
+    let http = EspHttpClient::new()?;
+    let backend = EspMyceliumBackend::new(http.clone());
+    let clock = EspClock::new(Some(backend.clone()), SyncPolicy::default());
+    let controller = OnboardingController::new(flash_state, wifi, EspAuth0::new(http), backend, clock, EspDevice, sensors.capabilities());
+    let ble = BleOnboarding::start(passkey)?;
+
+    controller.subscribe(ble.state_subscriber());
+    controller.subscribe(led.state_subscriber());
+    controller.subscribe_responses(ble.rpc().response_subscriber());
+
+    loop {
+        if let Some(bytes) = ble.rpc().recv() {
+            controller.process(&bytes);
+        }
+
+        std::thread::sleep(Duration::from_secs(5));
+    }
+}
@@ -2,13 +2,17 @@ use std::str::Utf8Error;
 
 use embedded_svc::http::client::Client;
 use embedded_svc::io::Write;
-use esp_idf_svc::errors::EspIOError;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::http::client::EspHttpConnection;
 use heapless::String;
 use serde::{Deserialize};
 use serde::de::DeserializeOwned;
 use serde_json::{from_str};
 
+#[cfg(target_os = "espidf")]
+use crate::http::EspHttpClient;
+use crate::sys::EspIOError;
+
 
 #[derive(Deserialize, Debug)]
 pub struct DeviceCodeResponse {
@@ -24,7 +28,8 @@ pub struct DeviceCodeResponse {
 pub enum AuthError {
     Json(serde_json::Error),
     String(Utf8Error),
-    IO(EspIOError)
+    IO(EspIOError),
+    UnexpectedResponse { status: u16 }
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,6 +55,7 @@ pub enum TokenResult {
 }
 
 
+#[cfg(target_os = "espidf")]
 fn post_form<T, const N : usize>(client: &mut Client<EspHttpConnection>, url: &str, payload: [(&str, &str); N]) -> Result<T, AuthError> where T : DeserializeOwned {
 
     let payload_str = payload.map(|(k, v)| format!("{}={}", k, v)).join("&");
@@ -76,18 +82,89 @@ fn post_form<T, const N : usize>(client: &mut Client<EspHttpConnection>, url: &s
     Ok(res)
 }
 
+#[cfg(target_os = "espidf")]
 pub fn refresh_token(client: &mut Client<EspHttpConnection>, refresh_token: &String<128>) -> Result<TokenResult, AuthError> {
     post_form(client, &format!("https://{}/oauth/token", option_env!("AUTH0_DOMAIN").unwrap_or("dev-plq6-asi.eu.auth0.com")), [("client_id", option_env!("AUTH0_CLIENT_ID").unwrap_or("5nYFEjhKlvTPheFxEDIEo97wLx3auwB7")), ("client_secret", option_env!("AUTH0_CLIENT_SECRET").unwrap_or("zp-7XzX4rP-ihysBSPoF2fXLfQRAxv2WnJEw-dp4f2LEa_rN8T2gU4fU-OqxWg4I")), ("grant_type", "refresh_token"), ("refresh_token", refresh_token.as_str())])
 }
 
+/// Revokes the refresh token, so it can't be used anymore once the station is factory reset. Auth0 responds with an empty body.
+#[cfg(target_os = "espidf")]
+pub fn revoke_token(client: &mut Client<EspHttpConnection>, refresh_token: &String<128>) -> Result<(), AuthError> {
+    let payload_str = format!("client_id={}&client_secret={}&token={}", option_env!("AUTH0_CLIENT_ID").unwrap_or("5nYFEjhKlvTPheFxEDIEo97wLx3auwB7"), option_env!("AUTH0_CLIENT_SECRET").unwrap_or("zp-7XzX4rP-ihysBSPoF2fXLfQRAxv2WnJEw-dp4f2LEa_rN8T2gU4fU-OqxWg4I"), refresh_token);
+    let payload = payload_str.as_bytes();
+    let payload_length = format!("{}", payload.len());
+
+    let headers = [
+        ("content-type", "application/x-www-form-urlencoded"),
+        ("content-length", &*payload_length),
+    ];
+
+    let mut request = client.post(&format!("https://{}/oauth/revoke", option_env!("AUTH0_DOMAIN").unwrap_or("dev-plq6-asi.eu.auth0.com")), &headers)?;
+    request.write_all(payload)?;
+    request.flush()?;
+
+    let response = request.submit()?;
+
+    if response.status() == 200 {
+        Ok(())
+    } else {
+        Err(AuthError::UnexpectedResponse { status: response.status() })
+    }
+}
+
+#[cfg(target_os = "espidf")]
 pub fn poll_token(client: &mut Client<EspHttpConnection>, device_code: &str) -> Result<TokenResult, AuthError> {
     post_form(client, &format!("https://{}/oauth/token", option_env!("AUTH0_DOMAIN").unwrap_or("dev-plq6-asi.eu.auth0.com")), [("client_id", option_env!("AUTH0_CLIENT_ID").unwrap_or("5nYFEjhKlvTPheFxEDIEo97wLx3auwB7")), ("device_code", device_code), ("grant_type", "urn:ietf:params:oauth:grant-type:device_code")])
 }
 
+#[cfg(target_os = "espidf")]
 pub fn request_device_code(client: &mut Client<EspHttpConnection>) -> Result<DeviceCodeResponse, AuthError> {
     post_form(client, &format!("https://{}/oauth/device/code", option_env!("AUTH0_DOMAIN").unwrap_or("dev-plq6-asi.eu.auth0.com")), [("client_id", option_env!("AUTH0_CLIENT_ID").unwrap_or("5nYFEjhKlvTPheFxEDIEo97wLx3auwB7")), ("scope", option_env!("AUTH0_SCOPE").unwrap_or("offline_access")), ("audience", option_env!("AUTH0_AUDIENCE").unwrap_or("https://mycelium.co"))])
 }
 
+pub trait Auth0 : Send + Sync + Clone {
+    fn request_device_code(&self) -> Result<DeviceCodeResponse, AuthError>;
+    fn poll_token(&self, device_code: &str) -> Result<TokenResult, AuthError>;
+    fn refresh_token(&self, refresh_token: &String<128>) -> Result<TokenResult, AuthError>;
+    fn revoke_token(&self, refresh_token: &String<128>) -> Result<(), AuthError>;
+}
+
+#[cfg(target_os = "espidf")]
+pub struct EspAuth0 {
+    client: EspHttpClient
+}
+
+#[cfg(target_os = "espidf")]
+impl EspAuth0 {
+    pub fn new(client: EspHttpClient) -> EspAuth0 { EspAuth0 { client } }
+}
+
+#[cfg(target_os = "espidf")]
+impl Clone for EspAuth0 {
+    fn clone(&self) -> Self {
+        EspAuth0 { client: self.client.clone() }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Auth0 for EspAuth0 {
+    fn request_device_code(&self) -> Result<DeviceCodeResponse, AuthError> {
+        request_device_code(&mut self.client.lock())
+    }
+
+    fn poll_token(&self, device_code: &str) -> Result<TokenResult, AuthError> {
+        poll_token(&mut self.client.lock(), device_code)
+    }
+
+    fn refresh_token(&self, refresh_token: &String<128>) -> Result<TokenResult, AuthError> {
+        self::refresh_token(&mut self.client.lock(), refresh_token)
+    }
+
+    fn revoke_token(&self, refresh_token: &String<128>) -> Result<(), AuthError> {
+        revoke_token(&mut self.client.lock(), refresh_token)
+    }
+}
+
 impl From<Utf8Error> for AuthError {
     fn from(value: Utf8Error) -> Self {
         AuthError::String(value)
@@ -0,0 +1,221 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::adc::config::Config;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::adc::{ADC1, AdcChannelDriver, AdcDriver, Atten11dB};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::gpio::Gpio35;
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::{esp, esp_adc_cal_check_efuse, esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_VREF};
+use log::{info, warn};
+
+use crate::sys::EspError;
+
+/// Single raw reading of the battery voltage behind the divider.
+pub trait VoltageSensor {
+    /// Voltage at the ADC pin in V
+    fn read(&mut self) -> Result<f64, EspError>;
+}
+
+/// Battery voltage on GPIO35 (ADC1 channel 7), with 11 dB attenuation for a range up to about 2.5 V.
+/// ADC1 keeps working while WiFi is on, unlike ADC2, although the radio still adds noise to the readings.
+#[cfg(target_os = "espidf")]
+pub struct AdcBattery<'d> {
+    adc: AdcDriver<'d, ADC1>,
+    channel: AdcChannelDriver<'d, Gpio35, Atten11dB<ADC1>>
+}
+
+#[cfg(target_os = "espidf")]
+impl<'d> AdcBattery<'d> {
+    /// Converts the readings with the reference voltage burnt into the eFuses, falling back to the nominal 1.1 V
+    /// reference of uncalibrated chips (which can be off by up to 6%).
+    pub fn new(adc: ADC1, pin: Gpio35) -> Result<AdcBattery<'d>, EspError> {
+        // the driver only takes the ADC, so check whether calibration is available without consuming it
+        let calibrated = unsafe { esp!(esp_adc_cal_check_efuse(esp_adc_cal_value_t_ESP_ADC_CAL_VAL_EFUSE_VREF)).is_ok() };
+
+        if !calibrated {
+            warn!("No ADC calibration in eFuse, the battery voltage is approximate");
+        }
+
+        let adc = AdcDriver::new(adc, &Config::new().calibration(calibrated))?;
+        let channel = AdcChannelDriver::new(pin)?;
+
+        Ok(AdcBattery { adc, channel })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl<'d> VoltageSensor for AdcBattery<'d> {
+    fn read(&mut self) -> Result<f64, EspError> {
+        Ok(self.adc.read(&mut self.channel)? as f64 / 1000.0)
+    }
+}
+
+/// Always reads the given voltage at the pin, for a development board without a battery.
+#[cfg(feature = "mock-sensors")]
+pub struct MockVoltageSensor {
+    volts: f64
+}
+
+#[cfg(feature = "mock-sensors")]
+impl MockVoltageSensor {
+    pub fn new(volts: f64) -> MockVoltageSensor {
+        MockVoltageSensor { volts }
+    }
+}
+
+#[cfg(feature = "mock-sensors")]
+impl VoltageSensor for MockVoltageSensor {
+    fn read(&mut self) -> Result<f64, EspError> {
+        Ok(self.volts)
+    }
+}
+
+/// Battery types with their discharge curve, as open circuit voltage against state of charge in percent.
+/// The curves are typical values at room temperature, good for an estimate but not for a fuel gauge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Chemistry {
+    /// Single lithium polymer or lithium ion cell
+    LiPo,
+    /// Two alkaline AA cells in series
+    TwoAa,
+    /// Single lithium iron phosphate cell
+    LiFePo4
+}
+
+impl Chemistry {
+    fn curve(&self) -> &'static [(f64, f64)] {
+        match self {
+            Chemistry::LiPo => &[
+                (3.30, 0.0), (3.50, 5.0), (3.68, 10.0), (3.74, 20.0), (3.77, 30.0), (3.80, 40.0),
+                (3.84, 50.0), (3.88, 60.0), (3.95, 70.0), (4.02, 80.0), (4.10, 90.0), (4.20, 100.0)
+            ],
+            Chemistry::TwoAa => &[
+                (2.00, 0.0), (2.20, 10.0), (2.30, 20.0), (2.38, 30.0), (2.44, 40.0), (2.48, 50.0),
+                (2.52, 60.0), (2.56, 70.0), (2.62, 80.0), (2.72, 90.0), (3.10, 100.0)
+            ],
+            Chemistry::LiFePo4 => &[
+                (2.50, 0.0), (3.00, 10.0), (3.20, 20.0), (3.25, 30.0), (3.28, 40.0), (3.30, 50.0),
+                (3.31, 60.0), (3.32, 70.0), (3.33, 80.0), (3.35, 90.0), (3.60, 100.0)
+            ]
+        }
+    }
+
+    /// Interpolates the discharge curve linearly, clamped to 0 and 100 percent outside of it.
+    pub fn state_of_charge(&self, voltage: f64) -> f64 {
+        let curve = self.curve();
+        let (first, last) = (curve[0], curve[curve.len() - 1]);
+
+        if voltage <= first.0 {
+            return first.1
+        }
+
+        if voltage >= last.0 {
+            return last.1
+        }
+
+        curve.windows(2)
+            .find(|w| voltage < w[1].0)
+            .map(|w| {
+                let ((v0, p0), (v1, p1)) = (w[0], w[1]);
+                p0 + (voltage - v0) / (v1 - v0) * (p1 - p0)
+            })
+            .unwrap_or(last.1)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BatteryConfig {
+    pub chemistry: Chemistry,
+    /// Battery voltage divided by the voltage at the pin, (R1 + R2) / R2 for the resistors of the divider
+    pub divider_ratio: f64,
+    /// Readings averaged per measurement
+    pub samples: usize
+}
+
+impl Default for BatteryConfig {
+    /// A LiPo cell behind two equal resistors, which keeps its 4.2 V within the range of the ADC.
+    fn default() -> Self {
+        BatteryConfig { chemistry: Chemistry::LiPo, divider_ratio: 2.0, samples: 32 }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryLevel {
+    /// Battery voltage in V
+    pub voltage: f64,
+    /// Estimated state of charge in percent
+    pub percentage: f64
+}
+
+/// Measures the battery before the radio is turned on: WiFi draws enough current to sag the battery voltage, and
+/// disturbs the ADC on top of that. The measurement is taken once at boot and kept for the rest of the wake.
+pub fn measure_battery<V : VoltageSensor>(sensor: &mut V, config: &BatteryConfig) -> Result<BatteryLevel, EspError> {
+    let samples = config.samples.max(1);
+    let mut sum = 0.0;
+
+    for _ in 0..samples {
+        sum += sensor.read()?;
+    }
+
+    let voltage = sum / samples as f64 * config.divider_ratio;
+    let level = BatteryLevel { voltage, percentage: config.chemistry.state_of_charge(voltage) };
+
+    info!("Battery: {:?}", level);
+
+    Ok(level)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHEMISTRIES: [Chemistry; 3] = [Chemistry::LiPo, Chemistry::TwoAa, Chemistry::LiFePo4];
+
+    /// Reads a fixed voltage at the pin
+    struct FixedVoltage(f64);
+
+    impl VoltageSensor for FixedVoltage {
+        fn read(&mut self) -> Result<f64, EspError> {
+            Ok(self.0)
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {} but got {}", expected, actual);
+    }
+
+    #[test]
+    fn clamps_outside_of_the_curve() {
+        for chemistry in CHEMISTRIES {
+            assert_eq!(chemistry.state_of_charge(0.0), 0.0);
+            assert_eq!(chemistry.state_of_charge(1.9), 0.0);
+            assert_eq!(chemistry.state_of_charge(4.5), 100.0);
+            assert_eq!(chemistry.state_of_charge(12.0), 100.0);
+        }
+    }
+
+    #[test]
+    fn matches_the_points_of_the_curve() {
+        for chemistry in CHEMISTRIES {
+            for (voltage, percentage) in chemistry.curve() {
+                assert_close(chemistry.state_of_charge(*voltage), *percentage);
+            }
+        }
+    }
+
+    #[test]
+    fn interpolates_between_the_points_of_the_curve() {
+        assert_close(Chemistry::LiPo.state_of_charge(3.82), 45.0);
+        assert_close(Chemistry::LiPo.state_of_charge(3.40), 2.5);
+        assert_close(Chemistry::TwoAa.state_of_charge(2.91), 95.0);
+        assert_close(Chemistry::LiFePo4.state_of_charge(3.475), 95.0);
+    }
+
+    #[test]
+    fn averages_the_readings_behind_the_divider() {
+        let level = measure_battery(&mut FixedVoltage(1.91), &BatteryConfig::default()).unwrap();
+
+        assert_close(level.voltage, 3.82);
+        assert_close(level.percentage, 45.0);
+    }
+}
@@ -0,0 +1,388 @@
+use std::ffi::c_void;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use bluedroid::gatt_server::{Characteristic, GLOBAL_GATT_SERVER, Profile, Service};
+use bluedroid::utilities::{AttributePermissions, BleUuid, CharacteristicProperties};
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs};
+use esp_idf_sys::*;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use serde_json::to_vec;
+use thingbuf::mpsc::blocking::{channel, Receiver};
+
+use crate::framing::{FrameAssembler, FrameError};
+use crate::onboarding::{AppError, OnboardingState};
+use crate::rpc::{encode, RejectionReason, RpcResponse};
+
+const RPC_RESPONSE_MAX_LENGTH: u16 = 512;
+
+/// Peers which paired with MITM protection, i.e. entered the passkey. The `AttributePermissions` of bluedroid can only
+/// require encryption and not `ESP_GATT_PERM_*_ENC_MITM`, so the characteristics check this list instead.
+///
+/// Peers connect with a resolvable private address which changes over time, so an address only stands for the peer
+/// while its connection lasts. bluedroid keeps the GATTS callback and with it the disconnects to itself, hence a peer
+/// is dropped once the stack has no connection to its address anymore, and whenever the address pairs again.
+static AUTHENTICATED: Mutex<Vec<esp_bd_addr_t>> = Mutex::new(Vec::new());
+
+#[derive(Clone)]
+enum Inbound {
+    Command(Vec<u8>),
+    MalformedFrame(FrameError)
+}
+
+impl Default for Inbound {
+    fn default() -> Self {
+        Inbound::Command(Vec::new())
+    }
+}
+
+/// RPC characteristic shared by the BLE services. Written frames are reassembled into commands, responses are set as
+/// the characteristic value, so they can be read and are notified to subscribed clients.
+pub struct BleRpc {
+    inbox: Receiver<Inbound>,
+    characteristic: Arc<RwLock<Characteristic>>
+}
+
+impl BleRpc {
+    fn new(uuid: &str) -> BleRpc {
+        let (tx, rx) = channel::<Inbound>(4);
+        let assembler = Mutex::new(FrameAssembler::new());
+
+        let characteristic = Characteristic::new(BleUuid::from_uuid128_string(uuid))
+            .name("RPC command handler")
+            .permissions(AttributePermissions::new().write().read().encrypted())
+            .properties(CharacteristicProperties::new().write().read().notify())
+            .max_value_length(RPC_RESPONSE_MAX_LENGTH)
+            .set_value("{}")
+            .on_write(move |bytes, param| {
+                if !authenticated(&param.bda) {
+                    warn!("Ignored a write from {:02X?}, which didn't pair with the passkey", param.bda);
+                    return
+                }
+
+                let inbound = match assembler.lock().unwrap().push(&bytes) {
+                    Ok(Some(command)) => Inbound::Command(command),
+                    Ok(None) => return,
+                    Err(error) => {
+                        warn!("Malformed frame: {:?}", error);
+                        Inbound::MalformedFrame(error)
+                    }
+                };
+
+                // blocking here would stall the Bluetooth stack, and nobody receives once the service stopped
+                if let Err(err) = tx.try_send(inbound) {
+                    warn!("Dropped a command: {}", err);
+                }
+            })
+            .show_name()
+            .build();
+
+        BleRpc { inbox: rx, characteristic }
+    }
+
+    /// Subscriber for the `subscribe_responses` of a controller, sets and notifies the response on the RPC characteristic.
+    pub fn response_subscriber<T : Serialize + 'static>(&self) -> impl Fn(&RpcResponse<T>) + Send + Sync + 'static {
+        let characteristic = self.characteristic.clone();
+        move |response| set_response(&characteristic, response)
+    }
+
+    /// Blocks until a complete command has been received, answering malformed frames in the meantime.
+    ///
+    /// The characteristic can't be updated from within the write callback as bluedroid holds its lock, hence this happens here.
+    pub fn recv(&self) -> Option<Vec<u8>> {
+        loop {
+            match self.inbox.recv()? {
+                Inbound::Command(bytes) => return Some(bytes),
+                Inbound::MalformedFrame(error) => self.reject_frame(error)
+            }
+        }
+    }
+
+    /// Like `recv`, but gives up when no frame arrives within the timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        loop {
+            match self.inbox.recv_timeout(timeout).ok()? {
+                Inbound::Command(bytes) => return Some(bytes),
+                Inbound::MalformedFrame(error) => self.reject_frame(error)
+            }
+        }
+    }
+
+    fn reject_frame(&self, error: FrameError) {
+        set_response(&self.characteristic, &RpcResponse::<()>::Rejected { id: None, reason: RejectionReason::MalformedFrame { error } })
+    }
+}
+
+/// Sets the response as the characteristic value, a response which doesn't fit is replaced by a rejection.
+fn set_response<T : Serialize>(characteristic: &Arc<RwLock<Characteristic>>, response: &RpcResponse<T>) {
+    characteristic.write().unwrap().set_value(encode(response, RPC_RESPONSE_MAX_LENGTH as usize));
+}
+
+/// BLE transport for onboarding: the RPC characteristic plus one exposing the latest published state.
+pub struct BleOnboarding {
+    rpc: BleRpc,
+    state: Arc<RwLock<OnboardingState>>
+}
+
+impl BleOnboarding {
+    pub fn start(passkey: u32) -> Result<BleOnboarding, EspError> {
+        let state = Arc::new(RwLock::new(OnboardingState::AwaitingSettings));
+        let state_read = state.clone();
+
+        let current_state = Characteristic::new(BleUuid::from_uuid128_string("00467768-6228-2272-4663-277478269001"))
+            .name("Current state")
+            .permissions(AttributePermissions::new().read().encrypted())
+            .properties(CharacteristicProperties::new().read().notify())
+            .show_name()
+            .on_read(move |param| {
+                if !authenticated(&param.bda) {
+                    return Vec::new()
+                }
+
+                let s = state_read.read().unwrap().clone();
+                return to_vec(&s).unwrap();
+            })
+            .build();
+
+        let rpc = BleRpc::new("00467768-6228-2272-4663-277478269002");
+
+        let service = Service::new(BleUuid::from_uuid128_string("00467768-6228-2272-4663-277478269000"))
+            .name("Mycelium onboarding service")
+            .primary()
+            .characteristic(&rpc.characteristic)
+            .characteristic(&current_state)
+            .build();
+
+        start_server("Mycelium onboarding", &service, passkey)?;
+
+        Ok(BleOnboarding { rpc, state })
+    }
+
+    pub fn rpc(&self) -> &BleRpc {
+        &self.rpc
+    }
+
+    /// Subscriber for `OnboardingController::subscribe`, keeps the state characteristic up to date.
+    pub fn state_subscriber(&self) -> impl Fn(&OnboardingState) + Send + Sync + 'static {
+        let state = self.state.clone();
+        move |s| *state.write().unwrap() = s.clone()
+    }
+}
+
+/// BLE transport for maintenance of an onboarded station: the RPC characteristic plus one to read live sensor values.
+pub struct BleMaintenance {
+    rpc: BleRpc,
+    sensors: Arc<RwLock<Vec<u8>>>
+}
+
+impl BleMaintenance {
+    pub fn start(passkey: u32) -> Result<BleMaintenance, EspError> {
+        let sensors = Arc::new(RwLock::new(b"null".to_vec()));
+        let sensors_read = sensors.clone();
+
+        let live_sensors = Characteristic::new(BleUuid::from_uuid128_string("00467768-6228-2272-4663-277478269101"))
+            .name("Live sensor values")
+            .permissions(AttributePermissions::new().read().encrypted())
+            .properties(CharacteristicProperties::new().read())
+            .show_name()
+            .on_read(move |param| if authenticated(&param.bda) { sensors_read.read().unwrap().clone() } else { Vec::new() })
+            .build();
+
+        let rpc = BleRpc::new("00467768-6228-2272-4663-277478269102");
+
+        let service = Service::new(BleUuid::from_uuid128_string("00467768-6228-2272-4663-277478269100"))
+            .name("Mycelium maintenance service")
+            .primary()
+            .characteristic(&rpc.characteristic)
+            .characteristic(&live_sensors)
+            .build();
+
+        start_server("Mycelium maintenance", &service, passkey)?;
+
+        Ok(BleMaintenance { rpc, sensors })
+    }
+
+    /// Sets the values read from the live sensor characteristic. Sampling takes seconds, so it can't happen in the
+    /// read callback and the values are sampled periodically instead.
+    pub fn set_sensors<T : Serialize>(&self, sensors: &T) {
+        *self.sensors.write().unwrap() = to_vec(sensors).unwrap();
+    }
+
+    pub fn rpc(&self) -> &BleRpc {
+        &self.rpc
+    }
+
+    /// Stops advertising and shuts down Bluetooth, so no client can connect to a service nobody answers anymore.
+    pub fn stop(self) {
+        stop_server();
+    }
+}
+
+fn start_server(device_name: &str, service: &Arc<RwLock<Service>>, passkey: u32) -> Result<(), EspError> {
+    let profile = Profile::new(0x0001)
+        .name("Default Profile")
+        .service(service)
+        .build();
+
+    GLOBAL_GATT_SERVER
+        .lock()
+        .unwrap()
+        .profile(profile)
+        .device_name(device_name)
+        .appearance(bluedroid::utilities::Appearance::GenericComputer)
+        .set_adv_params(advertisement_parameters())
+        .advertise_service(service)
+        .start();
+
+    configure_security(passkey)
+}
+
+fn stop_server() {
+    let stopped = unsafe {
+        esp!(esp_ble_gap_stop_advertising())
+            .and_then(|_| esp!(esp_bluedroid_disable()))
+            .and_then(|_| esp!(esp_bluedroid_deinit()))
+            .and_then(|_| esp!(esp_bt_controller_disable()))
+            .and_then(|_| esp!(esp_bt_controller_deinit()))
+    };
+
+    match stopped {
+        Ok(_) => info!("Bluetooth stopped"),
+        Err(err) => warn!("Could not stop Bluetooth: {:?}", err)
+    }
+
+    AUTHENTICATED.lock().unwrap().clear();
+}
+
+fn authenticated(bda: &esp_bd_addr_t) -> bool {
+    let mut authenticated = AUTHENTICATED.lock().unwrap();
+    authenticated.retain(connected);
+    authenticated.contains(bda)
+}
+
+/// Whether the stack has a connection to the address.
+fn connected(bda: &esp_bd_addr_t) -> bool {
+    let mut bda = *bda;
+    let mut params = esp_gap_conn_params_t::default();
+
+    unsafe { esp!(esp_ble_get_current_conn_params(bda.as_mut_ptr(), &mut params)).is_ok() }
+}
+
+#[derive(Debug)]
+pub enum PasskeyError {
+    Esp(EspError),
+    /// The factory partition has no passkey, the device wasn't provisioned
+    NotProvisioned,
+    /// Not a passkey of 6 digits
+    OutOfRange(u32)
+}
+
+impl From<EspError> for PasskeyError {
+    fn from(value: EspError) -> Self {
+        PasskeyError::Esp(value)
+    }
+}
+
+impl From<PasskeyError> for AppError {
+    fn from(value: PasskeyError) -> Self {
+        match value {
+            PasskeyError::Esp(err) => AppError::Esp(err),
+            PasskeyError::NotProvisioned | PasskeyError::OutOfRange(_) => AppError::Unprovisioned
+        }
+    }
+}
+
+/// Passkey for BLE Secure Connections pairing, written at manufacturing to the `factory_nvs` partition along with the
+/// label printed on the device. The partition is only opened read-only and isn't touched by a factory reset.
+pub fn passkey() -> Result<u32, PasskeyError> {
+    let partition = EspCustomNvsPartition::take("factory_nvs")?;
+    let nvs = EspNvs::new(partition, "factory", false)?;
+
+    match nvs.get_u32("ble_passkey")? {
+        Some(passkey) if passkey < 1_000_000 => Ok(passkey),
+        Some(passkey) => Err(PasskeyError::OutOfRange(passkey)),
+        None => Err(PasskeyError::NotProvisioned)
+    }
+}
+
+fn advertisement_parameters() -> esp_ble_adv_params_t {
+    esp_ble_adv_params_t {
+        adv_int_min: 0x20,
+        adv_int_max: 0x40,
+        adv_type: esp_ble_adv_type_t_ADV_TYPE_IND,
+        own_addr_type: esp_ble_addr_type_t_BLE_ADDR_TYPE_RPA_PUBLIC,
+        channel_map: esp_ble_adv_channel_t_ADV_CHNL_ALL,
+        adv_filter_policy: esp_ble_adv_filter_t_ADV_FILTER_ALLOW_SCAN_ANY_CON_ANY,
+        ..Default::default()
+    }
+}
+
+/// Requires LE Secure Connections with MITM protection using a static passkey (the device only has a display, i.e. the label).
+///
+/// The GAP callback registered by bluedroid drops security requests, so it is replaced by `gap_event_handler`
+/// which answers those and takes over starting the advertisement.
+fn configure_security(passkey: u32) -> Result<(), EspError> {
+    let mut passkey = passkey;
+    let mut auth_req = ESP_LE_AUTH_REQ_SC_MITM_BOND as esp_ble_auth_req_t;
+    let mut iocap = ESP_IO_CAP_OUT as esp_ble_io_cap_t;
+    let mut key_size = 16u8;
+    let mut init_key = (ESP_BLE_ENC_KEY_MASK | ESP_BLE_ID_KEY_MASK) as u8;
+    let mut rsp_key = (ESP_BLE_ENC_KEY_MASK | ESP_BLE_ID_KEY_MASK) as u8;
+    let mut auth_option = ESP_BLE_ONLY_ACCEPT_SPECIFIED_AUTH_ENABLE as u8;
+
+    unsafe {
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_SET_STATIC_PASSKEY, &mut passkey as *mut u32 as *mut c_void, 4))?;
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_AUTHEN_REQ_MODE, &mut auth_req as *mut u8 as *mut c_void, 1))?;
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_IOCAP_MODE, &mut iocap as *mut u8 as *mut c_void, 1))?;
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_MAX_KEY_SIZE, &mut key_size as *mut u8 as *mut c_void, 1))?;
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_SET_INIT_KEY, &mut init_key as *mut u8 as *mut c_void, 1))?;
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_SET_RSP_KEY, &mut rsp_key as *mut u8 as *mut c_void, 1))?;
+        esp!(esp_ble_gap_set_security_param(esp_ble_sm_param_t_ESP_BLE_SM_ONLY_ACCEPT_SPECIFIED_SEC_AUTH, &mut auth_option as *mut u8 as *mut c_void, 1))?;
+        esp!(esp_ble_gap_register_callback(Some(gap_event_handler)))?;
+    }
+
+    Ok(())
+}
+
+extern "C" fn gap_event_handler(event: esp_gap_ble_cb_event_t, param: *mut esp_ble_gap_cb_param_t) {
+    #[allow(non_upper_case_globals)]
+    match event {
+        esp_gap_ble_cb_event_t_ESP_GAP_BLE_ADV_DATA_SET_COMPLETE_EVT | esp_gap_ble_cb_event_t_ESP_GAP_BLE_SCAN_RSP_DATA_SET_COMPLETE_EVT => {
+            if let Err(err) = unsafe { esp!(esp_ble_gap_start_advertising(&mut advertisement_parameters())) } {
+                error!("Could not start advertising: {:?}", err);
+            }
+        }
+        esp_gap_ble_cb_event_t_ESP_GAP_BLE_SEC_REQ_EVT => {
+            let mut bd_addr = unsafe { (*param).ble_security.ble_req.bd_addr };
+            info!("Accepting security request from {:02X?}", bd_addr);
+
+            if let Err(err) = unsafe { esp!(esp_ble_gap_security_rsp(bd_addr.as_mut_ptr(), true)) } {
+                warn!("Could not accept the security request of {:02X?}: {:?}", bd_addr, err);
+            }
+        }
+        esp_gap_ble_cb_event_t_ESP_GAP_BLE_PASSKEY_NOTIF_EVT => {
+            info!("Pairing requested, enter the passkey printed on the device");
+        }
+        esp_gap_ble_cb_event_t_ESP_GAP_BLE_AUTH_CMPL_EVT => {
+            let auth_cmpl = unsafe { (*param).ble_security.auth_cmpl };
+            let mut authenticated = AUTHENTICATED.lock().unwrap();
+            authenticated.retain(|bda| *bda != auth_cmpl.bd_addr && connected(bda));
+
+            if auth_cmpl.success && (auth_cmpl.auth_mode & ESP_LE_AUTH_REQ_MITM as esp_ble_auth_req_t) != 0 {
+                info!("Paired with {:02X?}", auth_cmpl.bd_addr);
+                authenticated.push(auth_cmpl.bd_addr);
+            } else if auth_cmpl.success {
+                let mut bd_addr = auth_cmpl.bd_addr;
+                warn!("Disconnecting {:02X?}, which paired without the passkey", bd_addr);
+
+                if let Err(err) = unsafe { esp!(esp_ble_gap_disconnect(bd_addr.as_mut_ptr())) } {
+                    warn!("Could not disconnect {:02X?}: {:?}", bd_addr, err);
+                }
+            } else {
+                warn!("Pairing with {:02X?} failed, reason: 0x{:x}", auth_cmpl.bd_addr, auth_cmpl.fail_reason);
+            }
+        }
+        _ => debug!("Unhandled GAP event: {:?}", event)
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+/// Capacitive sensor which is calibrated to a percentage.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Sensor {
+    Soil,
+    Tank
+}
+
+/// Reference point of a calibration: the probe in air or dry soil (an empty tank), or submerged in water (a full tank).
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Reference {
+    Dry,
+    Wet
+}
+
+/// Maps a capacitance linearly to a percentage between the dry and wet reference. The offset corrects the probe's
+/// parasitic capacitance (e.g. of a long cable), it is subtracted from a reading before comparing it to the references.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub dry_pf: f64,
+    pub wet_pf: f64,
+    #[serde(default)]
+    pub offset_pf: f64
+}
+
+impl Calibration {
+    /// Typical readings of an uncalibrated soil probe
+    pub const SOIL: Calibration = Calibration { dry_pf: 200.0, wet_pf: 1800.0, offset_pf: 0.0 };
+    /// Typical readings of an uncalibrated tank probe
+    pub const TANK: Calibration = Calibration { dry_pf: 100.0, wet_pf: 1500.0, offset_pf: 0.0 };
+
+    pub fn default_for(sensor: Sensor) -> Calibration {
+        match sensor {
+            Sensor::Soil => Calibration::SOIL,
+            Sensor::Tank => Calibration::TANK
+        }
+    }
+
+    /// Percentage between 0 (dry) and 100 (wet), clamped as readings drift outside the references.
+    pub fn percentage(&self, pf: f64) -> f64 {
+        let span = self.wet_pf - self.dry_pf;
+
+        if span.abs() < f64::EPSILON {
+            return 0.0
+        }
+
+        ((pf - self.offset_pf - self.dry_pf) / span * 100.0).clamp(0.0, 100.0)
+    }
+
+    /// Replaces one of the references by a reading taken in that condition.
+    pub fn with_reference(&self, reference: Reference, pf: f64) -> Calibration {
+        let corrected = pf - self.offset_pf;
+
+        match reference {
+            Reference::Dry => Calibration { dry_pf: corrected, ..self.clone() },
+            Reference::Wet => Calibration { wet_pf: corrected, ..self.clone() }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::sync::{Arc, Mutex};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::*;
+
+use crate::sys::{EspError, touch_pad_t};
+
+/// Cycles counted by a bare touch pad (about 15 pF including the trace), times that capacitance.
+pub const DEFAULT_TOUCH_SCALE: f64 = 15_000.0;
+/// Temperature at which the probes are calibrated, in degrees Celsius.
+const REFERENCE_TEMPERATURE: f64 = 25.0;
+
+/// Single raw reading of a capacitive probe.
+pub trait CapacitanceSensor : Send {
+    /// Capacitance in pF
+    fn read(&mut self) -> Result<f64, EspError>;
+}
+
+/// Probe on one of the ESP32's touch pads. The peripheral charges and discharges the pad during a fixed measurement
+/// time and counts the cycles, which is inversely proportional to the capacitance. The scale converts the count to pF,
+/// only the order of magnitude matters as the probes are calibrated to percentages.
+#[cfg(target_os = "espidf")]
+pub struct TouchPad {
+    pad: touch_pad_t,
+    scale: f64
+}
+
+#[cfg(target_os = "espidf")]
+impl TouchPad {
+    pub fn new(pad: touch_pad_t) -> Result<TouchPad, EspError> {
+        TouchPad::with_scale(pad, DEFAULT_TOUCH_SCALE)
+    }
+
+    pub fn with_scale(pad: touch_pad_t, scale: f64) -> Result<TouchPad, EspError> {
+        unsafe {
+            // initializing the peripheral again is harmless, so every pad can do it
+            esp!(touch_pad_init())?;
+            esp!(touch_pad_set_fsm_mode(touch_fsm_mode_t_TOUCH_FSM_MODE_SW))?;
+            esp!(touch_pad_config(pad, 0))?;
+        }
+
+        Ok(TouchPad { pad, scale })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl CapacitanceSensor for TouchPad {
+    fn read(&mut self) -> Result<f64, EspError> {
+        let mut count: u16 = 0;
+
+        unsafe {
+            esp!(touch_pad_read(self.pad, &mut count))?;
+        }
+
+        Ok(capacitance(count, self.scale))
+    }
+}
+
+/// Capacitance in pF of a touch pad which counted `count` charge cycles. A count of 0 can't be told from 1.
+fn capacitance(count: u16, scale: f64) -> f64 {
+    scale / count.max(1) as f64
+}
+
+/// Replays the given readings in a loop, for running without probes attached.
+#[cfg(any(test, feature = "mock-sensors"))]
+pub struct MockCapacitanceSensor {
+    readings: Vec<f64>,
+    next: usize
+}
+
+#[cfg(any(test, feature = "mock-sensors"))]
+impl MockCapacitanceSensor {
+    pub fn new(readings: Vec<f64>) -> MockCapacitanceSensor {
+        MockCapacitanceSensor { readings, next: 0 }
+    }
+}
+
+#[cfg(any(test, feature = "mock-sensors"))]
+impl CapacitanceSensor for MockCapacitanceSensor {
+    fn read(&mut self) -> Result<f64, EspError> {
+        let reading = self.readings[self.next % self.readings.len()];
+        self.next += 1;
+        Ok(reading)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MeterConfig {
+    /// Raw readings taken per measurement
+    pub samples: usize,
+    /// Drift of the probe in pF per degree Celsius above the reference temperature, depends on the probe and soil
+    pub temperature_coefficient: f64
+}
+
+impl Default for MeterConfig {
+    fn default() -> Self {
+        MeterConfig { samples: 16, temperature_coefficient: 0.0 }
+    }
+}
+
+/// Oversamples a probe and filters the readings, so a single disturbed reading doesn't end up in the measurement.
+pub struct CapacitanceMeter<S : CapacitanceSensor> {
+    sensor: S,
+    config: MeterConfig
+}
+
+impl<S : CapacitanceSensor> CapacitanceMeter<S> {
+    pub fn new(sensor: S, config: MeterConfig) -> CapacitanceMeter<S> {
+        CapacitanceMeter { sensor, config }
+    }
+
+    /// Capacitance in pF, compensated for the temperature in degrees Celsius when it is known.
+    pub fn measure(&mut self, temperature: Option<f64>) -> Result<f64, EspError> {
+        let mut readings = Vec::with_capacity(self.config.samples);
+
+        for _ in 0..self.config.samples.max(1) {
+            readings.push(self.sensor.read()?);
+        }
+
+        let pf = median_mean(&mut readings);
+
+        let drift = temperature.map(|t| self.config.temperature_coefficient * (t - REFERENCE_TEMPERATURE)).unwrap_or(0.0);
+
+        Ok(pf - drift)
+    }
+}
+
+/// Mean of the middle half of the readings: a median filter which drops the outliers on both ends, averaging the
+/// remaining readings for a finer resolution than a single one.
+fn median_mean(readings: &mut [f64]) -> f64 {
+    readings.sort_by(|a, b| a.total_cmp(b));
+
+    let quarter = readings.len() / 4;
+    let middle = &readings[quarter..readings.len() - quarter];
+
+    middle.iter().sum::<f64>() / middle.len() as f64
+}
+
+/// Readings of the probes, `None` for a probe which isn't fitted.
+#[derive(Debug, Clone)]
+pub struct ProbeReadings {
+    pub soil_pf: Option<f64>,
+    pub tank_pf: Option<f64>
+}
+
+/// The soil and tank probe of the station, shared between the measurement and the maintenance service.
+/// A station without a tank, or only monitoring its tank, leaves the other probe out.
+pub struct Probes<S : CapacitanceSensor> {
+    soil: Option<Arc<Mutex<CapacitanceMeter<S>>>>,
+    tank: Option<Arc<Mutex<CapacitanceMeter<S>>>>
+}
+
+impl<S : CapacitanceSensor> Probes<S> {
+    pub fn new(soil: Option<CapacitanceMeter<S>>, tank: Option<CapacitanceMeter<S>>) -> Probes<S> {
+        Probes { soil: soil.map(|m| Arc::new(Mutex::new(m))), tank: tank.map(|m| Arc::new(Mutex::new(m))) }
+    }
+
+    pub fn has_soil(&self) -> bool {
+        self.soil.is_some()
+    }
+
+    pub fn has_tank(&self) -> bool {
+        self.tank.is_some()
+    }
+
+    pub fn read(&self, temperature: Option<f64>) -> Result<ProbeReadings, EspError> {
+        let measure = |meter: &Option<Arc<Mutex<CapacitanceMeter<S>>>>| match meter {
+            Some(meter) => meter.lock().unwrap().measure(temperature).map(Some),
+            None => Ok(None)
+        };
+
+        Ok(ProbeReadings { soil_pf: measure(&self.soil)?, tank_pf: measure(&self.tank)? })
+    }
+}
+
+impl<S : CapacitanceSensor> Clone for Probes<S> {
+    fn clone(&self) -> Self {
+        Probes { soil: self.soil.clone(), tank: self.tank.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter(readings: Vec<f64>, temperature_coefficient: f64) -> CapacitanceMeter<MockCapacitanceSensor> {
+        CapacitanceMeter::new(MockCapacitanceSensor::new(readings), MeterConfig { temperature_coefficient, ..MeterConfig::default() })
+    }
+
+    #[test]
+    fn converts_the_count_of_a_touch_pad() {
+        assert_eq!(capacitance(1000, DEFAULT_TOUCH_SCALE), 15.0);
+        assert_eq!(capacitance(30, DEFAULT_TOUCH_SCALE), 500.0);
+        assert_eq!(capacitance(0, DEFAULT_TOUCH_SCALE), DEFAULT_TOUCH_SCALE);
+    }
+
+    #[test]
+    fn averages_the_middle_half_of_the_readings() {
+        let mut readings = vec![800.0; 12];
+        readings.extend([0.0, 1.0, 5000.0, 9000.0]);
+
+        assert_eq!(meter(readings, 0.0).measure(None).unwrap(), 800.0);
+        assert_eq!(meter(vec![100.0, 200.0, 300.0, 400.0], 0.0).measure(None).unwrap(), 250.0);
+    }
+
+    #[test]
+    fn takes_the_configured_number_of_readings() {
+        let readings = (1..=16).map(f64::from).collect::<Vec<_>>();
+        let mut meter = meter(readings, 0.0);
+
+        // readings 5 to 12 are the middle half of the first 16
+        assert_eq!(meter.measure(None).unwrap(), 8.5);
+        // the mock starts over, so the second measurement reads the same
+        assert_eq!(meter.measure(None).unwrap(), 8.5);
+    }
+
+    #[test]
+    fn compensates_the_temperature() {
+        let mut meter = meter(vec![800.0], 2.0);
+
+        assert_eq!(meter.measure(Some(25.0)).unwrap(), 800.0);
+        assert_eq!(meter.measure(Some(30.0)).unwrap(), 790.0);
+        assert_eq!(meter.measure(Some(15.0)).unwrap(), 820.0);
+        assert_eq!(meter.measure(None).unwrap(), 800.0);
+    }
+
+    #[test]
+    fn does_not_compensate_without_a_coefficient() {
+        let mut meter = CapacitanceMeter::new(MockCapacitanceSensor::new(vec![800.0]), MeterConfig::default());
+
+        assert_eq!(meter.config.temperature_coefficient, 0.0);
+        assert_eq!(meter.measure(Some(40.0)).unwrap(), 800.0);
+    }
+
+    #[test]
+    fn leaves_out_a_probe_which_is_not_fitted() {
+        let probes = Probes::new(Some(meter(vec![800.0], 0.0)), None);
+
+        let readings = probes.read(Some(25.0)).unwrap();
+
+        assert_eq!(readings.soil_pf, Some(800.0));
+        assert_eq!(readings.tank_pf, None);
+        assert!(probes.has_soil() && !probes.has_tank());
+    }
+}
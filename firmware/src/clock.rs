@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sntp::{EspSntp, SyncStatus};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::systime::EspSystemTime;
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::{esp, esp_timer_get_time, settimeofday, timeval};
+use log::{info, warn};
+
+use crate::mycelium::{MyceliumBackend, MyceliumError};
+use crate::rtc::RtcState;
+use crate::sys::EspError;
+
+pub trait Clock : Send + Sync + Clone {
+    /// Seconds since the unix epoch, kept across deep sleep by the RTC.
+    fn now(&self) -> u64;
+    /// Seconds since the unix epoch, synchronizing the clock first when it was never synchronized or could have
+    /// drifted too far since. For when the time has to be right, e.g. to tell whether a token expired.
+    fn synchronized(&self) -> Result<u64, ClockError>;
+    /// Milliseconds since the unix epoch of a time read before the clock was synchronized for the first time, moved by
+    /// how far the synchronization moved the clock. Later times are returned as they are.
+    fn rebase(&self, millis: u64) -> u64;
+    /// Time since boot, for measuring durations.
+    fn monotonic(&self) -> Duration;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The clock starts at 0 on a power-on, so a time before 2023-01-01 was read before it was ever synchronized.
+pub const SYNCHRONIZED_AFTER: u64 = 1_672_531_200;
+
+/// When to synchronize the clock again.
+#[derive(Debug, Clone)]
+pub struct SyncPolicy {
+    /// Drift of the RTC in parts per million, the internal 150 kHz oscillator used in deep sleep is calibrated
+    /// against the crystal but still drifts with temperature
+    pub drift_ppm: u64,
+    /// Synchronizes again once the drift since the last synchronization could exceed this
+    pub max_drift: Duration,
+    pub sntp_timeout: Duration
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy { drift_ppm: 500, max_drift: Duration::from_secs(5), sntp_timeout: Duration::from_secs(30) }
+    }
+}
+
+impl SyncPolicy {
+    /// Whether the clock could have drifted more than allowed since `last_sync`, which is 0 when the clock was never
+    /// synchronized. A clock which went back is synchronized again too.
+    pub fn needs_sync(&self, last_sync: u64, now: u64) -> bool {
+        if last_sync == 0 || now < last_sync {
+            return true
+        }
+
+        (now - last_sync) * self.drift_ppm / 1_000_000 >= self.max_drift.as_secs()
+    }
+}
+
+/// Synchronizes with SNTP, falling back to the `Date` header of the backend when no time server can be reached.
+/// The time of the last synchronization is kept in RTC memory, so most wakes don't synchronize at all.
+#[cfg(target_os = "espidf")]
+pub struct EspClock<B : MyceliumBackend> {
+    backend: Option<B>,
+    policy: SyncPolicy
+}
+
+#[cfg(target_os = "espidf")]
+impl<B : MyceliumBackend> EspClock<B> {
+    pub fn new(backend: Option<B>, policy: SyncPolicy) -> EspClock<B> {
+        EspClock { backend, policy }
+    }
+
+    fn sntp(&self) -> Result<Option<u64>, ClockError> {
+        let sntp = EspSntp::new_default()?;
+        let started = self.monotonic();
+
+        while sntp.get_sync_status() != SyncStatus::Completed {
+            if self.monotonic() - started >= self.policy.sntp_timeout {
+                return Ok(None)
+            }
+
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Ok(Some(self.now()))
+    }
+
+    fn server_time(&self) -> Result<Option<u64>, ClockError> {
+        let now = match &self.backend {
+            Some(backend) => backend.server_time()?,
+            None => None
+        };
+
+        if let Some(now) = now {
+            let time = timeval { tv_sec: now as _, tv_usec: 0 };
+            esp!(unsafe { settimeofday(&time, std::ptr::null()) })?;
+        }
+
+        Ok(now)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl<B : MyceliumBackend> Clone for EspClock<B> {
+    fn clone(&self) -> Self {
+        EspClock { backend: self.backend.clone(), policy: self.policy.clone() }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl<B : MyceliumBackend> Clock for EspClock<B> {
+    fn now(&self) -> u64 {
+        EspSystemTime{}.now().as_secs()
+    }
+
+    fn synchronized(&self) -> Result<u64, ClockError> {
+        let now = self.now();
+        let started = self.monotonic();
+        let last_sync = RtcState::load().last_sync as u64;
+
+        if !self.policy.needs_sync(last_sync, now) {
+            return Ok(now)
+        }
+
+        let synchronized = match self.sntp()? {
+            Some(now) => Some(now),
+            None => {
+                warn!("SNTP timed out, using the time of the backend");
+                self.server_time().unwrap_or_else(|err| {
+                    warn!("Could not get the time of the backend: {:?}", err);
+                    None
+                })
+            }
+        };
+
+        match synchronized {
+            Some(synchronized) => {
+                info!("Clock synchronized: {}", synchronized);
+                let offset = synchronized.saturating_sub(now + (self.monotonic() - started).as_secs());
+
+                RtcState::update(|state| {
+                    if state.last_sync == 0 {
+                        state.sync_offset = offset as u32;
+                    }
+                    state.last_sync = synchronized as u32;
+                });
+                Ok(synchronized)
+            }
+            // a clock which was synchronized before is still closer than no time at all
+            None if last_sync != 0 => {
+                warn!("Could not synchronize the clock, last synchronized at {}", last_sync);
+                Ok(now)
+            }
+            None => Err(ClockError::Unsynchronized)
+        }
+    }
+
+    fn rebase(&self, millis: u64) -> u64 {
+        if millis < SYNCHRONIZED_AFTER * 1000 {
+            millis + RtcState::load().sync_offset as u64 * 1000
+        } else {
+            millis
+        }
+    }
+
+    fn monotonic(&self) -> Duration {
+        Duration::from_micros(unsafe { esp_timer_get_time() } as u64)
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClockError {
+    Esp(EspError),
+    Mycelium(MyceliumError),
+    /// The clock was never synchronized and no time source could be reached
+    Unsynchronized
+}
+
+impl From<EspError> for ClockError {
+    fn from(value: EspError) -> Self {
+        ClockError::Esp(value)
+    }
+}
+
+impl From<MyceliumError> for ClockError {
+    fn from(value: MyceliumError) -> Self {
+        ClockError::Mycelium(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+    const LAST_SYNC: u64 = 1_714_566_609;
+
+    #[test]
+    fn synchronizes_a_clock_which_never_was() {
+        assert!(SyncPolicy::default().needs_sync(0, 12));
+    }
+
+    #[test]
+    fn skips_synchronizing_while_the_drift_is_within_bounds() {
+        let policy = SyncPolicy::default();
+
+        assert!(!policy.needs_sync(LAST_SYNC, LAST_SYNC));
+        assert!(!policy.needs_sync(LAST_SYNC, LAST_SYNC + HOUR));
+        // 500 ppm drifts 5 seconds in 10 000 seconds
+        assert!(!policy.needs_sync(LAST_SYNC, LAST_SYNC + 9_999));
+    }
+
+    #[test]
+    fn synchronizes_once_the_drift_could_exceed_the_bound() {
+        let policy = SyncPolicy::default();
+
+        assert!(policy.needs_sync(LAST_SYNC, LAST_SYNC + 10_000));
+        assert!(policy.needs_sync(LAST_SYNC, LAST_SYNC + 24 * HOUR));
+    }
+
+    #[test]
+    fn synchronizes_a_clock_which_went_back() {
+        assert!(SyncPolicy::default().needs_sync(LAST_SYNC, LAST_SYNC - 1));
+    }
+
+    #[test]
+    fn follows_the_configured_drift() {
+        let policy = SyncPolicy { drift_ppm: 50, max_drift: Duration::from_secs(1), ..SyncPolicy::default() };
+
+        assert!(!policy.needs_sync(LAST_SYNC, LAST_SYNC + 19_999));
+        assert!(policy.needs_sync(LAST_SYNC, LAST_SYNC + 20_000));
+    }
+}
@@ -0,0 +1,277 @@
+use chrono::DateTime;
+
+use crate::environment::Part;
+use crate::mycelium::{CheckIn, StationMeasurement};
+
+/// Content type of a check-in in the compact encoding, sent instead of JSON once the backend advertises it.
+pub const CONTENT_TYPE: &str = "application/vnd.mycelium.checkin.v1";
+
+const VERSION: u8 = 1;
+/// Set in the presence mask of a measurement whose boot id differs from the one before, the boot id follows the mask
+const NEW_BOOT_ID: u16 = 1 << 15;
+
+/// How a value is stored: multiplied by the scale, rounded and written as a little endian integer of the width.
+#[derive(Clone, Copy)]
+enum Width {
+    I16,
+    I32
+}
+
+/// The values in the order of their bits in the presence mask and in the encoding, by the field name of the
+/// capabilities.
+const FIELDS: [(&str, f64, Width); 9] = [
+    ("batteryVoltage", 1000.0, Width::I16),
+    ("batteryPercentage", 100.0, Width::I16),
+    ("temperature", 100.0, Width::I16),
+    ("humidity", 100.0, Width::I16),
+    ("lux", 100.0, Width::I32),
+    ("soilPf", 100.0, Width::I32),
+    ("tankPf", 100.0, Width::I32),
+    ("soilMoisture", 100.0, Width::I16),
+    ("tankFill", 100.0, Width::I16)
+];
+
+const PARTS: [Part; 3] = [Part::Sht3x, Part::Sht4x, Part::Bh1750];
+
+/// Encodes a check-in as little endian binary, a fraction of the size of the JSON:
+///
+/// | field        | type |                                                       |
+/// |--------------|------|-------------------------------------------------------|
+/// | version      | u8   | 1                                                     |
+/// | measurements | u16  | capabilities, bit i is `FIELDS[i]`                    |
+/// | parts        | u8   | capabilities, bit i is `PARTS[i]`                     |
+/// | count        | u16  | number of measurements                                |
+/// | on           | i64  | milliseconds since the epoch of the first measurement |
+/// | sequence     | u32  | of the first measurement                              |
+/// | boot id      | u32  | of the first measurement                              |
+///
+/// followed by every measurement:
+///
+/// | field    | type    |                                           |
+/// |----------|---------|-------------------------------------------|
+/// | presence | u16     | bit i is set when `FIELDS[i]` has a value |
+/// | on       | i32     | milliseconds since the measurement before |
+/// | sequence | i32     | difference with the measurement before    |
+/// | boot id  | u32     | only when bit 15 of the presence is set   |
+/// | values   | i16/i32 | the present values, scaled to integers    |
+///
+/// `None` when the check-in can't be encoded compactly, e.g. because it has statistics or a value is out of range,
+/// in which case it is sent as JSON.
+pub fn encode(check_in: &CheckIn) -> Option<Vec<u8>> {
+    let first = check_in.measurements.first()?;
+
+    if check_in.measurements.iter().any(|m| m.statistics.is_some()) || check_in.measurements.len() > u16::MAX as usize {
+        return None
+    }
+
+    let mut measurements = 0u16;
+
+    for name in &check_in.capabilities.measurements {
+        measurements |= 1 << FIELDS.iter().position(|(field, _, _)| field == name)?;
+    }
+
+    let mut parts = 0u8;
+
+    for part in &check_in.capabilities.parts {
+        parts |= 1 << PARTS.iter().position(|p| p == part)?;
+    }
+
+    let mut out = Vec::with_capacity(22 + check_in.measurements.len() * 28);
+    out.push(VERSION);
+    out.extend(measurements.to_le_bytes());
+    out.push(parts);
+    out.extend((check_in.measurements.len() as u16).to_le_bytes());
+    out.extend(millis(first)?.to_le_bytes());
+    out.extend(first.sequence.to_le_bytes());
+    out.extend(first.boot_id.to_le_bytes());
+
+    let mut previous = first;
+
+    for measurement in &check_in.measurements {
+        let values = values(measurement);
+        let mut presence = values.iter().enumerate()
+            .filter(|(_, value)| value.is_some())
+            .fold(0u16, |mask, (i, _)| mask | 1 << i);
+
+        if measurement.boot_id != previous.boot_id {
+            presence |= NEW_BOOT_ID;
+        }
+
+        out.extend(presence.to_le_bytes());
+        out.extend(i32::try_from(millis(measurement)? - millis(previous)?).ok()?.to_le_bytes());
+        out.extend(i32::try_from(measurement.sequence as i64 - previous.sequence as i64).ok()?.to_le_bytes());
+
+        if presence & NEW_BOOT_ID != 0 {
+            out.extend(measurement.boot_id.to_le_bytes());
+        }
+
+        for ((_, scale, width), value) in FIELDS.iter().zip(values) {
+            if let Some(value) = value {
+                let scaled = (value * scale).round();
+
+                match width {
+                    Width::I16 if scaled >= i16::MIN as f64 && scaled <= i16::MAX as f64 => out.extend((scaled as i16).to_le_bytes()),
+                    Width::I32 if scaled >= i32::MIN as f64 && scaled <= i32::MAX as f64 => out.extend((scaled as i32).to_le_bytes()),
+                    _ => return None
+                }
+            }
+        }
+
+        previous = measurement;
+    }
+
+    Some(out)
+}
+
+fn millis(measurement: &StationMeasurement) -> Option<i64> {
+    Some(DateTime::parse_from_rfc3339(&measurement.on).ok()?.timestamp_millis())
+}
+
+fn values(measurement: &StationMeasurement) -> [Option<f64>; 9] {
+    [
+        measurement.battery_voltage, measurement.battery_percentage, measurement.temperature, measurement.humidity,
+        measurement.lux, measurement.soil_pf, measurement.tank_pf, measurement.soil_moisture, measurement.tank_fill
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{SecondsFormat, TimeZone, Utc};
+
+    use super::*;
+    use crate::mycelium::Capabilities;
+
+    /// Also decoded by `CompactCheckInSuite` of the backend, keep the two in sync.
+    const ENCODED: &str = "01 31 00 06 03 00 2d 3a 22 34 8f 01 00 00 0a 00 00 00 ef be ad de bf 00 00 00 00 00 00 00 \
+        00 00 48 0f 52 1c c6 fe ae 15 3a f4 7b 00 40 e2 01 00 94 11 b7 00 fb d2 01 00 01 00 00 00 48 0f 52 1c c6 fe 3a \
+        f4 7b 00 40 e2 01 00 94 11 bf 80 f9 9b 02 00 21 01 00 00 07 00 00 00 48 0f 52 1c c6 fe 64 00 3a f4 7b 00 40 e2 \
+        01 00 94 11";
+
+    // -3.14 is a temperature rather than pi, it is in the bytes the backend decodes
+    #[allow(clippy::approx_constant)]
+    fn measurement(on: &str, sequence: u32, boot_id: u32, humidity: Option<f64>) -> StationMeasurement {
+        StationMeasurement {
+            on: on.to_string(),
+            sequence,
+            boot_id,
+            battery_voltage: Some(3.912),
+            battery_percentage: Some(72.5),
+            temperature: Some(-3.14),
+            humidity,
+            lux: Some(81234.5),
+            soil_pf: Some(1234.56),
+            tank_pf: None,
+            soil_moisture: Some(45.0),
+            tank_fill: None,
+            statistics: None
+        }
+    }
+
+    fn check_in() -> CheckIn {
+        CheckIn {
+            measurements: vec![
+                measurement("2024-05-01T12:30:09.453Z", 10, 0xdeadbeef, Some(55.5)),
+                measurement("2024-05-01T12:32:09.000Z", 11, 0xdeadbeef, None),
+                measurement("2024-05-01T12:35:00.001Z", 300, 7, Some(1.0))
+            ],
+            capabilities: Capabilities {
+                measurements: vec!["batteryVoltage", "soilPf", "lux"],
+                parts: vec![Part::Bh1750, Part::Sht4x]
+            }
+        }
+    }
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        hex.split_whitespace().map(|b| u8::from_str_radix(b, 16).unwrap()).collect()
+    }
+
+    fn take<const N: usize>(bytes: &mut &[u8]) -> [u8; N] {
+        let all = *bytes;
+        let (head, tail) = all.split_at(N);
+        *bytes = tail;
+        head.try_into().unwrap()
+    }
+
+    /// Reads the measurements back the way the backend does.
+    fn decode(mut bytes: &[u8]) -> Vec<StationMeasurement> {
+        let [version] = take(&mut bytes);
+        assert_eq!(version, VERSION);
+        take::<3>(&mut bytes);
+        let count = u16::from_le_bytes(take(&mut bytes));
+        let mut on = i64::from_le_bytes(take(&mut bytes));
+        let mut sequence = u32::from_le_bytes(take(&mut bytes));
+        let mut boot_id = u32::from_le_bytes(take(&mut bytes));
+        let mut measurements = Vec::new();
+
+        for _ in 0..count {
+            let presence = u16::from_le_bytes(take(&mut bytes));
+            on += i32::from_le_bytes(take(&mut bytes)) as i64;
+            sequence = (sequence as i64 + i32::from_le_bytes(take(&mut bytes)) as i64) as u32;
+
+            if presence & NEW_BOOT_ID != 0 {
+                boot_id = u32::from_le_bytes(take(&mut bytes));
+            }
+
+            let mut values = [None; 9];
+
+            for (i, (_, scale, width)) in FIELDS.iter().enumerate() {
+                if presence & 1 << i != 0 {
+                    let scaled = match width {
+                        Width::I16 => i16::from_le_bytes(take(&mut bytes)) as f64,
+                        Width::I32 => i32::from_le_bytes(take(&mut bytes)) as f64
+                    };
+                    values[i] = Some(scaled / scale);
+                }
+            }
+
+            let [battery_voltage, battery_percentage, temperature, humidity, lux, soil_pf, tank_pf, soil_moisture, tank_fill] = values;
+
+            measurements.push(StationMeasurement {
+                on: Utc.timestamp_millis_opt(on).unwrap().to_rfc3339_opts(SecondsFormat::Millis, true),
+                sequence,
+                boot_id,
+                battery_voltage, battery_percentage, temperature, humidity, lux, soil_pf, tank_pf, soil_moisture, tank_fill,
+                statistics: None
+            });
+        }
+
+        assert!(bytes.is_empty());
+        measurements
+    }
+
+    #[test]
+    fn encodes_the_shared_vector() {
+        assert_eq!(encode(&check_in()), Some(bytes(ENCODED)));
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let check_in = check_in();
+        let decoded = decode(&encode(&check_in).unwrap());
+
+        assert_eq!(decoded.len(), check_in.measurements.len());
+
+        for (decoded, measurement) in decoded.iter().zip(&check_in.measurements) {
+            assert_eq!(decoded.on, measurement.on);
+            assert_eq!(decoded.sequence, measurement.sequence);
+            assert_eq!(decoded.boot_id, measurement.boot_id);
+            assert_eq!(values(decoded), values(measurement));
+        }
+    }
+
+    #[test]
+    fn falls_back_to_json_for_values_out_of_range() {
+        let mut check_in = check_in();
+        check_in.measurements[1].temperature = Some(400.0);
+
+        assert_eq!(encode(&check_in), None);
+    }
+
+    #[test]
+    fn falls_back_to_json_for_unknown_capabilities() {
+        let mut check_in = check_in();
+        check_in.capabilities.measurements.push("pressure");
+
+        assert_eq!(encode(&check_in), None);
+    }
+}
@@ -0,0 +1,33 @@
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::netif::{EspNetif, NetifStack};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::esp_restart;
+use heapless::String;
+
+use crate::sys::EspError;
+
+pub trait Device : Send + Sync + Clone {
+    fn mac_address(&self) -> Result<String<17>, EspError>;
+    fn restart(&self);
+}
+
+#[cfg(target_os = "espidf")]
+#[derive(Clone)]
+pub struct EspDevice;
+
+#[cfg(target_os = "espidf")]
+impl Device for EspDevice {
+    fn mac_address(&self) -> Result<String<17>, EspError> {
+        let netif = EspNetif::new(NetifStack::Eth)?;
+        let mac = netif.get_mac()?;
+        let mac_addr_str = String::from(format!("{:<02X}:{:<02X}:{:<02X}:{:<02X}:{:<02X}:{:<02X}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]).as_str());
+
+        Ok(mac_addr_str)
+    }
+
+    fn restart(&self) {
+        unsafe {
+            esp_restart();
+        }
+    }
+}
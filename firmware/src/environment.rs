@@ -0,0 +1,230 @@
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use log::{info, warn};
+use serde::Serialize;
+
+/// Readings of the environment sensors, `None` when the sensor is not present or failed to measure.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub lux: Option<f64>
+}
+
+/// Sensor parts which are detected on the I2C bus.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    Sht3x,
+    Sht4x,
+    Bh1750
+}
+
+#[derive(Debug)]
+pub enum I2cSensorError<E> {
+    Bus(E),
+    Crc
+}
+
+impl<E> From<E> for I2cSensorError<E> {
+    fn from(value: E) -> Self {
+        I2cSensorError::Bus(value)
+    }
+}
+
+/// Temperature and relative humidity of a Sensirion SHT3x, in single shot mode with high repeatability.
+struct Sht3x {
+    address: u8
+}
+
+impl Sht3x {
+    const ADDRESSES: [u8; 2] = [0x44, 0x45];
+
+    /// Reads the status register, which any SHT3x answers with a valid checksum.
+    fn detect<I, E>(i2c: &mut I, address: u8) -> Option<Sht3x> where I : Write<Error = E> + WriteRead<Error = E> {
+        let mut status = [0u8; 3];
+
+        i2c.write_read(address, &[0xF3, 0x2D], &mut status).ok()?;
+
+        valid_words(&status).then_some(Sht3x { address })
+    }
+
+    fn measure<I, D, E>(&self, i2c: &mut I, delay: &mut D) -> Result<(f64, f64), I2cSensorError<E>> where I : Read<Error = E> + Write<Error = E>, D : DelayMs<u16> {
+        let mut data = [0u8; 6];
+
+        i2c.write(self.address, &[0x24, 0x00])?;
+        delay.delay_ms(16);
+        i2c.read(self.address, &mut data)?;
+
+        if !valid_words(&data) {
+            return Err(I2cSensorError::Crc)
+        }
+
+        let temperature = -45.0 + 175.0 * word(&data[0..2]) / 65535.0;
+        let humidity = 100.0 * word(&data[3..5]) / 65535.0;
+
+        Ok((temperature, humidity.clamp(0.0, 100.0)))
+    }
+}
+
+/// Temperature and relative humidity of a Sensirion SHT4x, which shares its address with the SHT3x.
+struct Sht4x {
+    address: u8
+}
+
+impl Sht4x {
+    const ADDRESSES: [u8; 1] = [0x44];
+
+    /// Reads the serial number. The SHT3x only knows two byte commands, so it doesn't answer this.
+    fn detect<I, D, E>(i2c: &mut I, delay: &mut D, address: u8) -> Option<Sht4x> where I : Read<Error = E> + Write<Error = E>, D : DelayMs<u16> {
+        let mut serial = [0u8; 6];
+
+        i2c.write(address, &[0x89]).ok()?;
+        delay.delay_ms(1);
+        i2c.read(address, &mut serial).ok()?;
+
+        valid_words(&serial).then_some(Sht4x { address })
+    }
+
+    fn measure<I, D, E>(&self, i2c: &mut I, delay: &mut D) -> Result<(f64, f64), I2cSensorError<E>> where I : Read<Error = E> + Write<Error = E>, D : DelayMs<u16> {
+        let mut data = [0u8; 6];
+
+        i2c.write(self.address, &[0xFD])?;
+        delay.delay_ms(10);
+        i2c.read(self.address, &mut data)?;
+
+        if !valid_words(&data) {
+            return Err(I2cSensorError::Crc)
+        }
+
+        let temperature = -45.0 + 175.0 * word(&data[0..2]) / 65535.0;
+        let humidity = -6.0 + 125.0 * word(&data[3..5]) / 65535.0;
+
+        Ok((temperature, humidity.clamp(0.0, 100.0)))
+    }
+}
+
+/// Ambient light of a ROHM BH1750, in one time high resolution mode after which it powers down by itself.
+struct Bh1750 {
+    address: u8
+}
+
+impl Bh1750 {
+    const ADDRESSES: [u8; 2] = [0x23, 0x5C];
+
+    /// The BH1750 has no identification register, powering it on is acknowledged when it is present.
+    fn detect<I, E>(i2c: &mut I, address: u8) -> Option<Bh1750> where I : Write<Error = E> {
+        i2c.write(address, &[0x01]).ok().map(|_| Bh1750 { address })
+    }
+
+    fn measure<I, D, E>(&self, i2c: &mut I, delay: &mut D) -> Result<f64, I2cSensorError<E>> where I : Read<Error = E> + Write<Error = E>, D : DelayMs<u16> {
+        let mut data = [0u8; 2];
+
+        i2c.write(self.address, &[0x01])?;
+        i2c.write(self.address, &[0x20])?;
+        delay.delay_ms(180);
+        i2c.read(self.address, &mut data)?;
+
+        Ok(word(&data) / 1.2)
+    }
+}
+
+enum Climate {
+    Sht3x(Sht3x),
+    Sht4x(Sht4x)
+}
+
+struct Bus<I, D> {
+    i2c: I,
+    delay: D,
+    climate: Option<Climate>,
+    light: Option<Bh1750>
+}
+
+/// Temperature, humidity and light sensors found on the I2C bus at boot. Missing sensors read as `None`.
+pub struct EnvironmentSensors<I, D> {
+    bus: Arc<Mutex<Bus<I, D>>>
+}
+
+impl<I, D, E> EnvironmentSensors<I, D> where I : Read<Error = E> + Write<Error = E> + WriteRead<Error = E>, D : DelayMs<u16>, E : Debug {
+    pub fn detect(mut i2c: I, mut delay: D) -> EnvironmentSensors<I, D> {
+        let climate = Sht4x::ADDRESSES.iter().find_map(|a| Sht4x::detect(&mut i2c, &mut delay, *a)).map(Climate::Sht4x)
+            .or_else(|| Sht3x::ADDRESSES.iter().find_map(|a| Sht3x::detect(&mut i2c, *a)).map(Climate::Sht3x));
+        let light = Bh1750::ADDRESSES.iter().find_map(|a| Bh1750::detect(&mut i2c, *a));
+
+        let sensors = EnvironmentSensors { bus: Arc::new(Mutex::new(Bus { i2c, delay, climate, light })) };
+
+        info!("Detected sensors: {:?}", sensors.parts());
+
+        sensors
+    }
+
+    pub fn parts(&self) -> Vec<Part> {
+        let bus = self.bus.lock().unwrap();
+        let mut parts = Vec::new();
+
+        match bus.climate {
+            Some(Climate::Sht3x(_)) => parts.push(Part::Sht3x),
+            Some(Climate::Sht4x(_)) => parts.push(Part::Sht4x),
+            None => ()
+        }
+
+        if bus.light.is_some() {
+            parts.push(Part::Bh1750);
+        }
+
+        parts
+    }
+
+    pub fn read(&self) -> Environment {
+        let mut bus = self.bus.lock().unwrap();
+        let Bus { i2c, delay, climate, light } = &mut *bus;
+
+        let climate = match climate {
+            Some(Climate::Sht3x(sensor)) => sensor.measure(i2c, delay).map(Some),
+            Some(Climate::Sht4x(sensor)) => sensor.measure(i2c, delay).map(Some),
+            None => Ok(None)
+        };
+        let (temperature, humidity) = climate
+            .unwrap_or_else(|err| { warn!("Could not measure temperature and humidity: {:?}", err); None })
+            .unzip();
+
+        let lux = match light {
+            Some(sensor) => sensor.measure(i2c, delay).map_err(|err| warn!("Could not measure light: {:?}", err)).ok(),
+            None => None
+        };
+
+        Environment { temperature, humidity, lux }
+    }
+}
+
+impl<I, D> Clone for EnvironmentSensors<I, D> {
+    fn clone(&self) -> Self {
+        EnvironmentSensors { bus: self.bus.clone() }
+    }
+}
+
+fn word(bytes: &[u8]) -> f64 {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as f64
+}
+
+/// Sensirion sends every 16 bit word followed by a CRC-8 (polynomial 0x31, initialized to 0xFF).
+fn valid_words(data: &[u8]) -> bool {
+    data.chunks(3).all(|chunk| chunk.len() == 3 && crc8(&chunk[0..2]) == chunk[2])
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+
+    for byte in bytes {
+        crc ^= byte;
+
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x31 } else { crc << 1 };
+        }
+    }
+
+    crc
+}
@@ -0,0 +1,271 @@
+//! In-memory stand-ins for the hardware and the network, for the tests of the controllers.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use heapless::String as HString;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{from_str, to_string};
+use uuid::Uuid;
+
+use crate::auth0::{Auth0, AuthError, DeviceCodeResponse, TokenResult, TokenStatus};
+use crate::battery::BatteryLevel;
+use crate::clock::{Clock, ClockError};
+use crate::device::Device;
+use crate::kv::{KvStore, KvStoreError};
+use crate::mycelium::{Capabilities, CheckIn, CheckInResult, MyceliumBackend, MyceliumError, Station, StationInsert, StationUpdate, TankLevel, WateringReport, WateringSchedule};
+use crate::rtc::RtcState;
+use crate::sensors::{SamplingConfig, SensorStatistics, Sensors};
+use crate::sys::{ESP_ERR_TIMEOUT, EspError};
+use crate::wifi::{MyceliumWifi, MyceliumWifiSettings};
+
+/// The RTC memory is a single static, so the tests which use it take turns, each starting with it cleared like after a
+/// power-on.
+pub fn rtc_memory() -> MutexGuard<'static, ()> {
+    static RTC_MEMORY: Mutex<()> = Mutex::new(());
+
+    let guard = RTC_MEMORY.lock().unwrap_or_else(PoisonError::into_inner);
+    RtcState::clear();
+    guard
+}
+
+/// Clock which only moves when slept on, starting at `now` seconds since the epoch.
+#[derive(Clone)]
+pub struct FakeClock {
+    start: u64,
+    elapsed: Arc<Mutex<Duration>>
+}
+
+impl FakeClock {
+    pub fn new(now: u64) -> FakeClock {
+        FakeClock { start: now, elapsed: Arc::new(Mutex::new(Duration::ZERO)) }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> u64 {
+        self.start + self.monotonic().as_secs()
+    }
+
+    fn synchronized(&self) -> Result<u64, ClockError> {
+        Ok(self.now())
+    }
+
+    fn rebase(&self, millis: u64) -> u64 {
+        millis
+    }
+
+    fn monotonic(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+/// Key value store keeping the JSON of the values in memory, like `NvsKvStore` keeps it in flash.
+#[derive(Clone, Default)]
+pub struct MemoryKvStore {
+    values: Arc<Mutex<HashMap<String, String>>>
+}
+
+impl KvStore for MemoryKvStore {
+    fn get_opt<T : DeserializeOwned>(&self, key: &str) -> Result<Option<T>, KvStoreError> {
+        match self.values.lock().unwrap().get(key) {
+            Some(json) => Ok(Some(from_str(json)?)),
+            None => Ok(None)
+        }
+    }
+
+    fn get<T : DeserializeOwned>(&self, key: &str) -> Result<T, KvStoreError> {
+        self.get_opt(key)?.ok_or(KvStoreError::SettingNotFound(key.to_string()))
+    }
+
+    fn set<T : Serialize>(&self, key: &str, value: T) -> Result<(), KvStoreError> {
+        self.values.lock().unwrap().insert(key.to_string(), to_string(&value)?);
+        Ok(())
+    }
+
+    fn contains(&self, key: &str) -> Result<bool, KvStoreError> {
+        Ok(self.values.lock().unwrap().contains_key(key))
+    }
+
+    fn remove(&self, key: &str) -> Result<(), KvStoreError> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// Access point which is found on channel 6, unless it is out of reach. Counts the connection attempts.
+#[derive(Clone, Default)]
+pub struct FakeWifi {
+    pub unreachable: bool,
+    pub attempts: Arc<Mutex<u32>>
+}
+
+impl MyceliumWifi for FakeWifi {
+    fn connect(&self, settings: MyceliumWifiSettings) -> Result<MyceliumWifiSettings, EspError> {
+        *self.attempts.lock().unwrap() += 1;
+
+        if self.unreachable {
+            return Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+        }
+
+        Ok(MyceliumWifiSettings { channel: Some(6), bssid: Some([0x02, 0, 0, 0, 0, 1]), ..settings })
+    }
+}
+
+/// Authorization server at which the user authorizes the device, unless `pending` keeps them from ever doing so.
+#[derive(Clone, Default)]
+pub struct FakeAuth {
+    pub pending: bool
+}
+
+impl Auth0 for FakeAuth {
+    fn request_device_code(&self) -> Result<DeviceCodeResponse, AuthError> {
+        Ok(DeviceCodeResponse {
+            device_code: HString::from("device-code"),
+            user_code: HString::from("ABCD-EFGH"),
+            verification_uri: HString::from("https://auth.example/activate"),
+            verification_uri_complete: HString::from("https://auth.example/activate?user_code=ABCD-EFGH"),
+            expires_in: 900,
+            interval: 5
+        })
+    }
+
+    fn poll_token(&self, _device_code: &str) -> Result<TokenResult, AuthError> {
+        if self.pending {
+            Ok(TokenResult::Error { error: TokenStatus::AuthorizationPending })
+        } else {
+            Ok(TokenResult::Full { access_token: HString::from("access-token"), refresh_token: HString::from("refresh-token"), expires_in: 86_400 })
+        }
+    }
+
+    fn refresh_token(&self, _refresh_token: &HString<128>) -> Result<TokenResult, AuthError> {
+        Ok(TokenResult::AccessToken { access_token: HString::from("access-token"), expires_in: 86_400 })
+    }
+
+    fn revoke_token(&self, _refresh_token: &HString<128>) -> Result<(), AuthError> {
+        Ok(())
+    }
+}
+
+/// Backend with the given stations, which registers new ones as `FakeBackend::STATION_ID` or fails every request
+/// with a 500.
+#[derive(Clone, Default)]
+pub struct FakeBackend {
+    pub stations: Vec<Station>,
+    pub failing: bool,
+    /// Names of the stations registered
+    pub inserted: Arc<Mutex<Vec<String>>>,
+    /// Schedules the stations were updated to
+    pub schedules: Arc<Mutex<Vec<WateringSchedule>>>
+}
+
+impl FakeBackend {
+    pub const STATION_ID: Uuid = Uuid::from_u128(0x0f1e2d3c_4b5a_6978_8796_a5b4c3d2e1f0);
+
+    fn respond<T>(&self, response: impl FnOnce() -> T) -> Result<T, MyceliumError> {
+        if self.failing {
+            Err(MyceliumError::UnexpectedResponse { status: 500 })
+        } else {
+            Ok(response())
+        }
+    }
+}
+
+impl MyceliumBackend for FakeBackend {
+    fn check_in(&self, _access_token: &HString<756>, _station_id: &Uuid, _request: &CheckIn, _idempotency_key: &str, _prefer_compact: bool) -> Result<CheckInResult, MyceliumError> {
+        self.respond(|| CheckInResult { watering: None, instruction: None, schedule: None, accepted: None, rejected: Vec::new(), encodings: Vec::new() })
+    }
+
+    fn insert_plant(&self, _access_token: &HString<756>, insert: &StationInsert) -> Result<Uuid, MyceliumError> {
+        self.respond(|| {
+            self.inserted.lock().unwrap().push(insert.name.to_string());
+            FakeBackend::STATION_ID
+        })
+    }
+
+    fn list_stations(&self, _access_token: &HString<756>) -> Result<Vec<Station>, MyceliumError> {
+        self.respond(|| self.stations.clone())
+    }
+
+    fn update_station(&self, _access_token: &HString<756>, _station_id: &Uuid, update: &StationUpdate) -> Result<(), MyceliumError> {
+        self.respond(|| self.schedules.lock().unwrap().extend(update.water_schedule.clone()))
+    }
+
+    fn watered(&self, _access_token: &HString<756>, _station_id: &Uuid, _report: &WateringReport) -> Result<(), MyceliumError> {
+        self.respond(|| ())
+    }
+
+    fn low_water(&self, _access_token: &HString<756>, _station_id: &Uuid, _level: &TankLevel) -> Result<(), MyceliumError> {
+        self.respond(|| ())
+    }
+
+    fn delete_station(&self, _access_token: &HString<756>, _station_id: &Uuid) -> Result<(), MyceliumError> {
+        self.respond(|| ())
+    }
+
+    fn server_time(&self) -> Result<Option<u64>, MyceliumError> {
+        self.respond(|| None)
+    }
+}
+
+#[derive(Clone)]
+pub struct FakeDevice;
+
+impl FakeDevice {
+    pub const MAC: &'static str = "24:0A:C4:00:00:01";
+}
+
+impl Device for FakeDevice {
+    fn mac_address(&self) -> Result<HString<17>, EspError> {
+        Ok(HString::from(FakeDevice::MAC))
+    }
+
+    fn restart(&self) {}
+}
+
+/// Sensors which always read the same statistics.
+#[derive(Clone)]
+pub struct FakeSensors {
+    pub statistics: SensorStatistics,
+    pub capabilities: Capabilities,
+    pub sampling: SamplingConfig
+}
+
+impl FakeSensors {
+    /// Only the soil probe fitted
+    pub fn soil() -> FakeSensors {
+        FakeSensors {
+            statistics: SensorStatistics::default(),
+            capabilities: Capabilities { measurements: vec!["soilPf"], parts: Vec::new() },
+            sampling: SamplingConfig::default()
+        }
+    }
+}
+
+impl Sensors for FakeSensors {
+    fn battery(&self) -> Option<BatteryLevel> {
+        None
+    }
+
+    fn read(&self) -> Result<SensorStatistics, EspError> {
+        Ok(self.statistics.clone())
+    }
+
+    fn sampling(&self) -> &SamplingConfig {
+        &self.sampling
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+}
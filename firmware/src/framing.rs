@@ -0,0 +1,263 @@
+use serde::Serialize;
+
+pub const MAX_MESSAGE_LENGTH: usize = 2048;
+
+const FRAME_CHUNK: u8 = 0x01;
+const FRAME_COMMIT: u8 = 0x02;
+
+/// Reassembles commands which are written to the RPC characteristic in multiple chunks, as a single write is limited by the ATT MTU.
+///
+/// * chunk:  `0x01 | sequence (u16 BE) | payload length (u8) | payload`
+/// * commit: `0x02 | chunk count (u16 BE) | total length (u16 BE)`
+///
+/// A chunk with sequence 0 starts a new command. A retransmission of the last accepted chunk is ignored. Writes
+/// starting with `{` are passed through as a complete JSON command, so clients which fit their command in a single
+/// write keep working.
+#[derive(Default)]
+pub struct FrameAssembler {
+    buffer: Vec<u8>,
+    next_sequence: u16,
+    /// Length of the payload of the last accepted chunk
+    last_length: usize
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "_type")]
+pub enum FrameError {
+    Empty,
+    Truncated,
+    NothingToCommit,
+    UnknownFrameType { frame_type: u8 },
+    OutOfSequence { expected: u16, actual: u16 },
+    TooLarge { max: usize },
+    LengthMismatch { expected: usize, actual: usize },
+    ChunkCountMismatch { expected: u16, actual: u16 }
+}
+
+impl FrameAssembler {
+    pub fn new() -> FrameAssembler {
+        FrameAssembler { buffer: Vec::new(), next_sequence: 0, last_length: 0 }
+    }
+
+    /// Returns the complete command once committed. Any malformed frame discards the command being assembled.
+    pub fn push(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, FrameError> {
+        let result = match frame.first() {
+            None => Err(FrameError::Empty),
+            Some(b'{') => {
+                self.reset();
+                Ok(Some(frame.to_vec()))
+            }
+            Some(&FRAME_CHUNK) => self.chunk(&frame[1..]),
+            Some(&FRAME_COMMIT) => self.commit(&frame[1..]),
+            Some(&frame_type) => Err(FrameError::UnknownFrameType { frame_type })
+        };
+
+        if result.is_err() {
+            self.reset();
+        }
+
+        result
+    }
+
+    fn chunk(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, FrameError> {
+        if frame.len() < 3 {
+            return Err(FrameError::Truncated)
+        }
+
+        let sequence = u16::from_be_bytes([frame[0], frame[1]]);
+        let length = frame[2] as usize;
+        let payload = &frame[3..];
+
+        if payload.len() != length {
+            return Err(FrameError::LengthMismatch { expected: length, actual: payload.len() })
+        }
+
+        if self.is_retransmission(sequence, payload) {
+            return Ok(None)
+        }
+
+        if sequence == 0 {
+            self.reset();
+        }
+
+        if sequence != self.next_sequence {
+            return Err(FrameError::OutOfSequence { expected: self.next_sequence, actual: sequence })
+        }
+
+        if self.buffer.len() + payload.len() > MAX_MESSAGE_LENGTH {
+            return Err(FrameError::TooLarge { max: MAX_MESSAGE_LENGTH })
+        }
+
+        self.buffer.extend_from_slice(payload);
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.last_length = payload.len();
+
+        Ok(None)
+    }
+
+    fn is_retransmission(&self, sequence: u16, payload: &[u8]) -> bool {
+        self.next_sequence != 0
+            && sequence == self.next_sequence - 1
+            && payload.len() == self.last_length
+            && self.buffer.ends_with(payload)
+    }
+
+    fn commit(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, FrameError> {
+        if frame.len() < 4 {
+            return Err(FrameError::Truncated)
+        }
+
+        if self.next_sequence == 0 {
+            return Err(FrameError::NothingToCommit)
+        }
+
+        let chunks = u16::from_be_bytes([frame[0], frame[1]]);
+        let length = u16::from_be_bytes([frame[2], frame[3]]) as usize;
+
+        if chunks != self.next_sequence {
+            return Err(FrameError::ChunkCountMismatch { expected: chunks, actual: self.next_sequence })
+        }
+
+        if length != self.buffer.len() {
+            return Err(FrameError::LengthMismatch { expected: length, actual: self.buffer.len() })
+        }
+
+        let message = std::mem::take(&mut self.buffer);
+        self.reset();
+
+        Ok(Some(message))
+    }
+
+    fn reset(&mut self) {
+        self.buffer.clear();
+        self.next_sequence = 0;
+        self.last_length = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(sequence: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![FRAME_CHUNK];
+        frame.extend_from_slice(&sequence.to_be_bytes());
+        frame.push(payload.len() as u8);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn commit(chunks: u16, length: u16) -> Vec<u8> {
+        let mut frame = vec![FRAME_COMMIT];
+        frame.extend_from_slice(&chunks.to_be_bytes());
+        frame.extend_from_slice(&length.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn assembles_the_chunks_once_committed() {
+        let mut assembler = FrameAssembler::new();
+
+        assert_eq!(assembler.push(&chunk(0, b"{\"_type\":")), Ok(None));
+        assert_eq!(assembler.push(&chunk(1, b"\"Ping\"}")), Ok(None));
+        assert_eq!(assembler.push(&commit(2, 16)), Ok(Some(b"{\"_type\":\"Ping\"}".to_vec())));
+
+        // ready for the next command
+        assert_eq!(assembler.push(&chunk(0, b"{}")), Ok(None));
+        assert_eq!(assembler.push(&commit(1, 2)), Ok(Some(b"{}".to_vec())));
+    }
+
+    #[test]
+    fn passes_a_json_write_through() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(&chunk(0, b"abc")).unwrap();
+
+        assert_eq!(assembler.push(b"{\"_type\":\"Ping\"}"), Ok(Some(b"{\"_type\":\"Ping\"}".to_vec())));
+        // the command being assembled was discarded
+        assert_eq!(assembler.push(&commit(1, 3)), Err(FrameError::NothingToCommit));
+    }
+
+    #[test]
+    fn rejects_chunks_out_of_sequence() {
+        let mut assembler = FrameAssembler::new();
+
+        assert_eq!(assembler.push(&chunk(1, b"abc")), Err(FrameError::OutOfSequence { expected: 0, actual: 1 }));
+
+        assembler.push(&chunk(0, b"abc")).unwrap();
+        assert_eq!(assembler.push(&chunk(2, b"def")), Err(FrameError::OutOfSequence { expected: 1, actual: 2 }));
+        assert_eq!(assembler.push(&commit(1, 3)), Err(FrameError::NothingToCommit));
+    }
+
+    #[test]
+    fn starts_over_on_sequence_zero() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(&chunk(0, b"abc")).unwrap();
+        assembler.push(&chunk(1, b"def")).unwrap();
+
+        assembler.push(&chunk(0, b"xyz")).unwrap();
+        assert_eq!(assembler.push(&commit(1, 3)), Ok(Some(b"xyz".to_vec())));
+    }
+
+    #[test]
+    fn ignores_a_retransmission_of_the_last_chunk() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(&chunk(0, b"abc")).unwrap();
+        assembler.push(&chunk(1, b"def")).unwrap();
+
+        assert_eq!(assembler.push(&chunk(1, b"def")), Ok(None));
+        assert_eq!(assembler.push(&chunk(2, b"ghi")), Ok(None));
+        assert_eq!(assembler.push(&commit(3, 9)), Ok(Some(b"abcdefghi".to_vec())));
+    }
+
+    #[test]
+    fn rejects_a_different_chunk_with_the_last_sequence() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(&chunk(0, b"abc")).unwrap();
+        assembler.push(&chunk(1, b"def")).unwrap();
+
+        assert_eq!(assembler.push(&chunk(1, b"xyz")), Err(FrameError::OutOfSequence { expected: 2, actual: 1 }));
+    }
+
+    #[test]
+    fn limits_the_message_length() {
+        let mut assembler = FrameAssembler::new();
+        let payload = [b'a'; 128];
+
+        for sequence in 0..16 {
+            assert_eq!(assembler.push(&chunk(sequence, &payload)), Ok(None));
+        }
+        assert_eq!(assembler.push(&chunk(16, b"a")), Err(FrameError::TooLarge { max: MAX_MESSAGE_LENGTH }));
+        assert_eq!(assembler.push(&commit(16, 2048)), Err(FrameError::NothingToCommit));
+
+        for sequence in 0..16 {
+            assembler.push(&chunk(sequence, &payload)).unwrap();
+        }
+        assert_eq!(assembler.push(&commit(16, 2048)).unwrap().unwrap().len(), MAX_MESSAGE_LENGTH);
+    }
+
+    #[test]
+    fn rejects_a_commit_which_does_not_match() {
+        let mut assembler = FrameAssembler::new();
+        assembler.push(&chunk(0, b"abc")).unwrap();
+        assembler.push(&chunk(1, b"def")).unwrap();
+        assert_eq!(assembler.push(&commit(3, 6)), Err(FrameError::ChunkCountMismatch { expected: 3, actual: 2 }));
+
+        assembler.push(&chunk(0, b"abc")).unwrap();
+        assembler.push(&chunk(1, b"def")).unwrap();
+        assert_eq!(assembler.push(&commit(2, 7)), Err(FrameError::LengthMismatch { expected: 7, actual: 6 }));
+
+        // both discarded the command
+        assert_eq!(assembler.push(&commit(2, 6)), Err(FrameError::NothingToCommit));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let mut assembler = FrameAssembler::new();
+
+        assert_eq!(assembler.push(&[]), Err(FrameError::Empty));
+        assert_eq!(assembler.push(&[FRAME_CHUNK, 0, 0]), Err(FrameError::Truncated));
+        assert_eq!(assembler.push(&[FRAME_COMMIT, 0, 1, 0]), Err(FrameError::Truncated));
+        assert_eq!(assembler.push(&[FRAME_CHUNK, 0, 0, 4, b'a']), Err(FrameError::LengthMismatch { expected: 4, actual: 1 }));
+        assert_eq!(assembler.push(&[0x7f]), Err(FrameError::UnknownFrameType { frame_type: 0x7f }));
+    }
+}
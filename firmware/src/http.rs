@@ -0,0 +1,35 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_sys::EspError;
+
+pub struct EspHttpClient {
+    client: Arc<Mutex<Client<EspHttpConnection>>>
+}
+
+impl EspHttpClient {
+    pub fn new() -> Result<EspHttpClient, EspError> {
+        let connection = EspHttpConnection::new(&Configuration {
+            use_global_ca_store: true,
+            buffer_size_tx: Some(1536),
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        Ok(EspHttpClient { client: Arc::new(Mutex::new(Client::wrap(connection))) })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Client<EspHttpConnection>> {
+        self.client.lock().unwrap()
+    }
+}
+
+impl Clone for EspHttpClient {
+    fn clone(&self) -> Self {
+        EspHttpClient { client: self.client.clone() }
+    }
+}
+
+unsafe impl Send for EspHttpClient { }
+unsafe impl Sync for EspHttpClient { }
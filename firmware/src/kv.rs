@@ -1,4 +1,5 @@
 use std::sync::{Arc, Mutex};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::nvs::EspDefaultNvs;
 use serde::{Serialize};
 use serde::de::DeserializeOwned;
@@ -6,9 +7,11 @@ use serde::de::DeserializeOwned;
 use serde_json::ser::{to_string};
 use serde_json::{from_str};
 
+use crate::sys::EspError;
+
 #[derive(Debug)]
 pub enum KvStoreError {
-    Esp(esp_idf_sys::EspError),
+    Esp(EspError),
     Json(serde_json::Error),
     StringConversionError,
     SettingNotFound(String)
@@ -22,20 +25,24 @@ pub trait KvStore : Send + Sync + Clone {
     fn remove(&self, key: &str) -> Result<(), KvStoreError>;
 }
 
+#[cfg(target_os = "espidf")]
 pub struct NvsKvStore {
     pub nvs: Arc<Mutex<EspDefaultNvs>>
 }
 
+#[cfg(target_os = "espidf")]
 impl NvsKvStore {
     pub fn new(nvs: EspDefaultNvs) -> NvsKvStore { NvsKvStore { nvs: Arc::new(Mutex::new(nvs)) } }
 }
 
+#[cfg(target_os = "espidf")]
 impl Clone for NvsKvStore {
     fn clone(&self) -> Self {
         NvsKvStore { nvs: self.nvs.clone() }
     }
 }
 
+#[cfg(target_os = "espidf")]
 impl KvStore for NvsKvStore {
     fn get_opt<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, KvStoreError> {
         let buf: &mut [u8; 2048] = &mut [0u8;2048];
@@ -76,8 +83,8 @@ impl KvStore for NvsKvStore {
     }
 }
 
-impl From<esp_idf_sys::EspError> for KvStoreError {
-    fn from(value: esp_idf_sys::EspError) -> Self {
+impl From<EspError> for KvStoreError {
+    fn from(value: EspError) -> Self {
         KvStoreError::Esp(value)
     }
 }
@@ -88,5 +95,7 @@ impl From<serde_json::Error> for KvStoreError {
     }
 }
 
+#[cfg(target_os = "espidf")]
 unsafe impl Send for NvsKvStore { }
+#[cfg(target_os = "espidf")]
 unsafe impl Sync for NvsKvStore { }
\ No newline at end of file
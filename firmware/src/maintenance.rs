@@ -0,0 +1,394 @@
+use heapless::String;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::auth0::Auth0;
+use crate::calibration::{Calibration, Reference, Sensor};
+use crate::clock::Clock;
+use crate::device::Device;
+use crate::kv::KvStore;
+use crate::mycelium::{MyceliumBackend, StationMeasurement, StationUpdate, WateringSchedule};
+use crate::onboarding::{AppError, WateringScheduleInput};
+use crate::rpc;
+use crate::rpc::{RejectionReason, Responders, RpcRequest, RpcResponse, truncated};
+use crate::rtc::RtcState;
+use crate::sensors::Sensors;
+use crate::settings::FlashState;
+use crate::station::{connect_wifi, extract_wallet, factory_reset, measure, read_measurement};
+use crate::sys::{ESP_ERR_NOT_FOUND, EspError};
+use crate::validation::{FieldError, finite, ValidationError, Validator, wpa_passphrase};
+use crate::wifi::{MyceliumWifi, MyceliumWifiSettings};
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "_type")]
+pub enum MaintenanceCommand {
+    ReadSensors,
+    UpdateWifi {
+        wifi_ssid: std::string::String,
+        #[serde(default)]
+        wifi_password: std::string::String
+    },
+    Rename { name: std::string::String },
+    /// Updates the schedule at the backend and the copy the station waters by when the backend is unreachable
+    UpdateSchedule { watering_schedule: WateringScheduleInput },
+    MeasureNow,
+    /// Captures the current reading of the sensor as a reference, e.g. the soil probe in dry soil
+    Calibrate { sensor: Sensor, reference: Reference },
+    SetCalibrationOffset { sensor: Sensor, offset_pf: f64 },
+    ClearPumpFault,
+    FactoryReset,
+    Exit
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "_type")]
+pub enum MaintenanceResult {
+    Sensors { measurement: StationMeasurement },
+    WifiUpdated,
+    Renamed,
+    ScheduleUpdated,
+    Measured,
+    Calibrated { calibration: Calibration },
+    PumpFaultCleared,
+    Failed { error: String<256> }
+}
+
+pub type MaintenanceRequest = RpcRequest<MaintenanceCommand>;
+pub type MaintenanceResponse = RpcResponse<MaintenanceResult>;
+
+/// Handles maintenance commands for an onboarded station, independently of the transport the commands arrive on.
+pub struct MaintenanceController<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock, D : Device, S : Sensors> {
+    flash_state: FlashState<K>,
+    wifi: W,
+    auth: A,
+    backend: B,
+    clock: C,
+    device: D,
+    sensors: S,
+    responders: Responders<MaintenanceResult>
+}
+
+impl<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock, D : Device, S : Sensors> MaintenanceController<K, W, A, B, C, D, S> {
+    pub fn new(flash_state: FlashState<K>, wifi: W, auth: A, backend: B, clock: C, device: D, sensors: S) -> MaintenanceController<K, W, A, B, C, D, S> {
+        MaintenanceController { flash_state, wifi, auth, backend, clock, device, sensors, responders: Responders::default() }
+    }
+
+    /// Registers a subscriber which receives the response to every request.
+    pub fn subscribe_responses<F>(&self, responder: F) where F : Fn(&MaintenanceResponse) + Send + Sync + 'static {
+        self.responders.subscribe(responder);
+    }
+
+    /// Decodes a raw JSON request as received from a transport and handles it. Returns false once the client asks to leave maintenance.
+    pub fn process(&self, bytes: &[u8]) -> bool {
+        match rpc::decode(bytes) {
+            Ok(request) => self.handle(request),
+            Err(rejection) => {
+                self.responders.respond(rejection);
+                true
+            }
+        }
+    }
+
+    pub fn handle(&self, request: MaintenanceRequest) -> bool {
+        let id = request.id;
+
+        let result = match request.command {
+            MaintenanceCommand::ReadSensors => read_measurement(&self.flash_state, &self.clock, &self.sensors).map(|measurement| MaintenanceResult::Sensors { measurement }),
+            MaintenanceCommand::UpdateWifi { wifi_ssid, wifi_password } => {
+                let mut validator = Validator::new();
+
+                validator.not_blank("wifi_ssid", &wifi_ssid);
+                let ssid = validator.bounded("wifi_ssid", &wifi_ssid);
+                validator.check("wifi_password", wpa_passphrase(&wifi_password));
+
+                match validator.finish(MyceliumWifiSettings::basic(ssid, truncated(&wifi_password))) {
+                    Ok(settings) => {
+                        self.responders.respond(MaintenanceResponse::Accepted { id });
+                        self.update_wifi(settings).map(|_| MaintenanceResult::WifiUpdated)
+                    }
+                    Err(fields) => return self.reject(id, fields)
+                }
+            }
+            MaintenanceCommand::Rename { name } => {
+                let mut validator = Validator::new();
+
+                validator.not_blank("name", &name);
+                let name = validator.bounded("name", &name);
+
+                match validator.finish(name) {
+                    Ok(name) => {
+                        self.responders.respond(MaintenanceResponse::Accepted { id });
+                        self.rename(name).map(|_| MaintenanceResult::Renamed)
+                    }
+                    Err(fields) => return self.reject(id, fields)
+                }
+            }
+            MaintenanceCommand::MeasureNow => {
+                self.responders.respond(MaintenanceResponse::Accepted { id });
+                measure(&self.flash_state, &self.wifi, &self.auth, &self.backend, &self.clock, &self.sensors).map(|_| MaintenanceResult::Measured)
+            }
+            MaintenanceCommand::Calibrate { sensor, reference } => {
+                let mut validator = Validator::new();
+
+                validator.check("sensor", self.fitted(sensor));
+
+                match validator.finish(()) {
+                    Ok(_) => {
+                        self.responders.respond(MaintenanceResponse::Accepted { id });
+                        self.calibrate(sensor, reference).map(|calibration| MaintenanceResult::Calibrated { calibration })
+                    }
+                    Err(fields) => return self.reject(id, fields)
+                }
+            }
+            MaintenanceCommand::UpdateSchedule { watering_schedule } => {
+                let mut validator = Validator::new();
+
+                let schedule = watering_schedule.validate(&mut validator);
+
+                match validator.finish(schedule) {
+                    Ok(schedule) => {
+                        self.responders.respond(MaintenanceResponse::Accepted { id });
+                        self.update_schedule(schedule).map(|_| MaintenanceResult::ScheduleUpdated)
+                    }
+                    Err(fields) => return self.reject(id, fields)
+                }
+            }
+            MaintenanceCommand::SetCalibrationOffset { sensor, offset_pf } => {
+                let mut validator = Validator::new();
+
+                validator.check("sensor", self.fitted(sensor));
+                validator.check("offset_pf", finite(offset_pf));
+
+                match validator.finish(()) {
+                    Ok(_) => {
+                        self.responders.respond(MaintenanceResponse::Accepted { id });
+                        self.set_calibration_offset(sensor, offset_pf).map(|calibration| MaintenanceResult::Calibrated { calibration })
+                    }
+                    Err(fields) => return self.reject(id, fields)
+                }
+            }
+            MaintenanceCommand::ClearPumpFault => {
+                self.responders.respond(MaintenanceResponse::Accepted { id });
+                warn!("Clearing pump fault");
+                self.flash_state.set_pump_fault(false).map(|_| MaintenanceResult::PumpFaultCleared).map_err(AppError::from)
+            }
+            MaintenanceCommand::FactoryReset => {
+                self.responders.respond(MaintenanceResponse::Accepted { id });
+                // Restarts into onboarding, so this only returns when wiping the settings failed
+                match factory_reset(&self.flash_state, &self.wifi, &self.auth, &self.backend, &self.clock, &self.device) {
+                    Ok(_) => return false,
+                    Err(err) => Err(err)
+                }
+            }
+            MaintenanceCommand::Exit => {
+                self.responders.respond(MaintenanceResponse::Accepted { id });
+                return false
+            }
+        };
+
+        let result = result.unwrap_or_else(|err| {
+            error!("Error: {:?}", err);
+            MaintenanceResult::Failed { error: truncated(&format!("{:?}", err)) }
+        });
+
+        self.responders.respond(MaintenanceResponse::Completed { id, result });
+
+        true
+    }
+
+    fn reject(&self, id: u32, fields: Vec<FieldError>) -> bool {
+        warn!("Invalid settings: {:?}", fields);
+        self.responders.respond(MaintenanceResponse::Rejected { id: Some(id), reason: RejectionReason::InvalidSettings { fields } });
+        true
+    }
+
+    /// Only persists the settings once connecting with them succeeded, so a typo doesn't lock the station out.
+    fn update_wifi(&self, settings: MyceliumWifiSettings) -> Result<(), AppError> {
+        let enriched_settings = self.wifi.connect(settings)?;
+        RtcState::update(|state| state.set_wifi(enriched_settings.channel.zip(enriched_settings.bssid)));
+        self.flash_state.set_wifi_settings(enriched_settings)?;
+        info!("Updated WiFi settings");
+        Ok(())
+    }
+
+    fn fitted(&self, sensor: Sensor) -> Result<(), ValidationError> {
+        let measurement = match sensor {
+            Sensor::Soil => "soilPf",
+            Sensor::Tank => "tankPf"
+        };
+
+        if self.sensors.capabilities().measurements.contains(&measurement) {
+            Ok(())
+        } else {
+            Err(ValidationError::Invalid { reason: "probe is not fitted" })
+        }
+    }
+
+    fn calibrate(&self, sensor: Sensor, reference: Reference) -> Result<Calibration, AppError> {
+        let measurement = read_measurement(&self.flash_state, &self.clock, &self.sensors)?;
+        let pf = match sensor {
+            Sensor::Soil => measurement.soil_pf,
+            Sensor::Tank => measurement.tank_pf
+        };
+        let pf = pf.ok_or(EspError::from_infallible::<ESP_ERR_NOT_FOUND>())?;
+
+        let calibration = self.flash_state.get_calibration(sensor)?.with_reference(reference, pf);
+        self.flash_state.set_calibration(sensor, calibration.clone())?;
+
+        info!("Calibrated {:?} {:?} at {} pF: {:?}", sensor, reference, pf, calibration);
+        Ok(calibration)
+    }
+
+    fn set_calibration_offset(&self, sensor: Sensor, offset_pf: f64) -> Result<Calibration, AppError> {
+        let calibration = Calibration { offset_pf, ..self.flash_state.get_calibration(sensor)? };
+        self.flash_state.set_calibration(sensor, calibration.clone())?;
+
+        info!("Calibration offset of {:?} set: {:?}", sensor, calibration);
+        Ok(calibration)
+    }
+
+    /// Only keeps the schedule once the backend took it, as the backend sends its own schedule with every check-in.
+    fn update_schedule(&self, schedule: WateringSchedule) -> Result<(), AppError> {
+        connect_wifi(&self.flash_state, &self.wifi)?;
+        let wallet = extract_wallet(&self.auth, &self.flash_state, &self.clock)?;
+        let station_id = self.flash_state.get_station_id()?;
+
+        self.backend.update_station(&wallet.access_token, &station_id, &StationUpdate { name: None, location: None, description: None, water_schedule: Some(schedule.clone()) })?;
+        self.flash_state.set_watering_schedule(schedule.clone())?;
+        RtcState::update(|state| state.set_schedule(Some(&schedule)));
+
+        info!("Updated the watering schedule: {:?}", schedule);
+        Ok(())
+    }
+
+    fn rename(&self, name: String<128>) -> Result<(), AppError> {
+        connect_wifi(&self.flash_state, &self.wifi)?;
+        let wallet = extract_wallet(&self.auth, &self.flash_state, &self.clock)?;
+        let station_id = self.flash_state.get_station_id()?;
+
+        self.backend.update_station(&wallet.access_token, &station_id, &StationUpdate { name: Some(name), location: None, description: None, water_schedule: None })?;
+        info!("Renamed station");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::fakes::{FakeAuth, FakeBackend, FakeClock, FakeDevice, FakeSensors, FakeWifi, MemoryKvStore, rtc_memory};
+    use crate::tokens::TokenWallet;
+
+    const NOW: u64 = 1_714_566_609;
+
+    type TestController = MaintenanceController<MemoryKvStore, FakeWifi, FakeAuth, FakeBackend, FakeClock, FakeDevice, FakeSensors>;
+
+    fn controller() -> (TestController, FlashState<MemoryKvStore>, Arc<Mutex<Vec<MaintenanceResponse>>>) {
+        with_backend(FakeBackend::default())
+    }
+
+    /// An onboarded station
+    fn with_backend(backend: FakeBackend) -> (TestController, FlashState<MemoryKvStore>, Arc<Mutex<Vec<MaintenanceResponse>>>) {
+        let kv = MemoryKvStore::default();
+        let flash_state = FlashState::new(kv.clone());
+        flash_state.set_station_id(FakeBackend::STATION_ID).unwrap();
+        flash_state.set_token_wallet(TokenWallet::new(String::from("access-token"), String::from("refresh-token"), 86_400, NOW)).unwrap();
+        flash_state.set_wifi_settings(MyceliumWifiSettings::basic(String::from("Mycelium"), String::from("correct horse"))).unwrap();
+
+        let controller = MaintenanceController::new(flash_state, FakeWifi::default(), FakeAuth::default(), backend, FakeClock::new(NOW), FakeDevice, FakeSensors::soil());
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let received = responses.clone();
+
+        controller.subscribe_responses(move |response| received.lock().unwrap().push(response.clone()));
+
+        (controller, FlashState::new(kv), responses)
+    }
+
+    fn rejected_fields(responses: &Mutex<Vec<MaintenanceResponse>>) -> Vec<FieldError> {
+        match responses.lock().unwrap().last() {
+            Some(MaintenanceResponse::Rejected { reason: RejectionReason::InvalidSettings { fields }, .. }) => fields.clone(),
+            response => panic!("Expected the settings to be rejected, got {:?}", response)
+        }
+    }
+
+    #[test]
+    fn sets_the_calibration_offset() {
+        let (controller, flash_state, responses) = controller();
+
+        assert!(controller.handle(MaintenanceRequest { id: 1, command: MaintenanceCommand::SetCalibrationOffset { sensor: Sensor::Soil, offset_pf: 12.5 } }));
+
+        assert_eq!(flash_state.get_calibration(Sensor::Soil).unwrap().offset_pf, 12.5);
+        assert!(matches!(responses.lock().unwrap()[..], [
+            MaintenanceResponse::Accepted { id: 1 },
+            MaintenanceResponse::Completed { id: 1, result: MaintenanceResult::Calibrated { .. } }
+        ]));
+    }
+
+    #[test]
+    fn rejects_an_offset_which_is_not_a_number() {
+        let (controller, flash_state, responses) = controller();
+
+        for offset_pf in [f64::NAN, f64::INFINITY] {
+            controller.handle(MaintenanceRequest { id: 1, command: MaintenanceCommand::SetCalibrationOffset { sensor: Sensor::Soil, offset_pf } });
+
+            assert_eq!(rejected_fields(&responses), vec![FieldError { field: "offset_pf", error: ValidationError::Invalid { reason: "not a finite number" } }]);
+        }
+        assert_eq!(flash_state.get_calibration(Sensor::Soil).unwrap(), Calibration::default_for(Sensor::Soil));
+    }
+
+    #[test]
+    fn rejects_an_offset_for_a_probe_which_is_not_fitted() {
+        let (controller, flash_state, responses) = controller();
+
+        controller.handle(MaintenanceRequest { id: 1, command: MaintenanceCommand::SetCalibrationOffset { sensor: Sensor::Tank, offset_pf: 12.5 } });
+
+        assert_eq!(rejected_fields(&responses), vec![FieldError { field: "sensor", error: ValidationError::Invalid { reason: "probe is not fitted" } }]);
+        assert_eq!(flash_state.get_calibration(Sensor::Tank).unwrap(), Calibration::default_for(Sensor::Tank));
+    }
+
+    #[test]
+    fn updates_the_schedule() {
+        let _rtc = rtc_memory();
+        let backend = FakeBackend::default();
+        let (controller, flash_state, responses) = with_backend(backend.clone());
+        let schedule = WateringSchedule::Interval { schedule: String::from("0 0 8 * * ?"), period: String::from("10 seconds") };
+        let input = WateringScheduleInput::Interval { schedule: " 0 0 8 * * ? ".to_string(), period: "10 seconds".to_string() };
+
+        controller.handle(MaintenanceRequest { id: 1, command: MaintenanceCommand::UpdateSchedule { watering_schedule: input } });
+
+        assert!(matches!(responses.lock().unwrap()[..], [
+            MaintenanceResponse::Accepted { id: 1 },
+            MaintenanceResponse::Completed { id: 1, result: MaintenanceResult::ScheduleUpdated }
+        ]));
+        assert_eq!(*backend.schedules.lock().unwrap(), vec![schedule.clone()]);
+        assert_eq!(flash_state.get_opt_watering_schedule().unwrap(), Some(schedule.clone()));
+        assert!(!RtcState::load().schedule_changed(&schedule));
+    }
+
+    #[test]
+    fn keeps_the_schedule_when_the_backend_fails() {
+        let _rtc = rtc_memory();
+        let (controller, flash_state, responses) = with_backend(FakeBackend { failing: true, ..FakeBackend::default() });
+        let input = WateringScheduleInput::Threshold { below_soil_pf: 700, period: "10 seconds".to_string() };
+
+        controller.handle(MaintenanceRequest { id: 1, command: MaintenanceCommand::UpdateSchedule { watering_schedule: input } });
+
+        assert!(matches!(responses.lock().unwrap().last(), Some(MaintenanceResponse::Completed { id: 1, result: MaintenanceResult::Failed { .. } })));
+        assert_eq!(flash_state.get_opt_watering_schedule().unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_an_invalid_schedule() {
+        let backend = FakeBackend::default();
+        let (controller, flash_state, responses) = with_backend(backend.clone());
+        let input = WateringScheduleInput::Interval { schedule: "0 0 25 * * ?".to_string(), period: "soon".to_string() };
+
+        controller.handle(MaintenanceRequest { id: 1, command: MaintenanceCommand::UpdateSchedule { watering_schedule: input } });
+
+        let fields: Vec<&str> = rejected_fields(&responses).iter().map(|field| field.field).collect();
+        assert_eq!(fields, vec!["watering_schedule.schedule", "watering_schedule.period"]);
+        assert!(backend.schedules.lock().unwrap().is_empty());
+        assert_eq!(flash_state.get_opt_watering_schedule().unwrap(), None);
+    }
+}
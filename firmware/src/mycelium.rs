@@ -1,16 +1,24 @@
 
 use std::string::FromUtf8Error;
 
+use chrono::DateTime;
 use embedded_svc::http::client::Client;
+use embedded_svc::http::Method;
 use embedded_svc::io::Write;
-use esp_idf_svc::errors::EspIOError;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::http::client::EspHttpConnection;
-use rand::Rng;
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
 
 use serde_json::{from_str};
 use uuid::Uuid;
 
+use crate::compact;
+use crate::environment::Part;
+#[cfg(target_os = "espidf")]
+use crate::http::EspHttpClient;
+use crate::sensors::SensorStatistics;
+use crate::sys::EspIOError;
+
 #[derive(Debug)]
 pub enum MyceliumError {
     Json(serde_json::Error),
@@ -19,15 +27,21 @@ pub enum MyceliumError {
     UnexpectedResponse { status: u16 }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "_type")]
 pub enum WateringSchedule {
     #[serde(rename_all = "camelCase")]
-    Interval { schedule: heapless::String<16>, period: heapless::String<30> },
+    Interval { schedule: heapless::String<64>, period: heapless::String<30> },
     #[serde(rename_all = "camelCase")]
     Threshold { below_soil_pf: u32, period: heapless::String<30> },
 }
 
+impl Default for WateringSchedule {
+    fn default() -> Self {
+        WateringSchedule::Threshold { below_soil_pf: 500, period: heapless::String::from("5 seconds") }
+    }
+}
+
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct StationInsert {
@@ -35,39 +49,193 @@ pub struct StationInsert {
     pub name: heapless::String<128>,
     pub location: heapless::String<128>,
     pub description: heapless::String<128>,
+    pub watering_schedule: WateringSchedule,
+    pub capabilities: Capabilities
+}
+
+/// The measurements a station provides (by their field names) and the sensor parts it detected, so the backend can tell
+/// a missing sensor from a reading of zero.
+#[derive(Serialize, Debug, Clone)]
+pub struct Capabilities {
+    pub measurements: Vec<&'static str>,
+    pub parts: Vec<Part>
+}
+
+/// A station as registered at the backend, only the fields the station itself needs.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Station {
+    pub id: Uuid,
+    pub mac: String,
+    pub name: String,
+    pub location: String,
     pub watering_schedule: WateringSchedule
 }
 
 #[derive(Serialize, Debug)]
 #[serde(rename_all = "camelCase")]
+pub struct StationUpdate {
+    pub name: Option<heapless::String<128>>,
+    pub location: Option<heapless::String<128>>,
+    pub description: Option<heapless::String<128>>,
+    pub water_schedule: Option<WateringSchedule>
+}
+
+/// Response to a check-in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CheckInResult {
+    pub watering: Option<heapless::String<30>>,
+    pub instruction: Option<StationInstruction>,
+    /// The current schedule, cached to keep watering when the backend is unreachable
+    pub schedule: Option<WateringSchedule>,
+    /// Measurements stored, `None` for a backend which doesn't acknowledge measurements
+    #[serde(default, skip_serializing)]
+    pub accepted: Option<usize>,
+    /// Measurements which were not stored and shouldn't be sent again
+    #[serde(default, skip_serializing)]
+    pub rejected: Vec<MeasurementRejection>,
+    /// Content types of check-ins the backend accepts besides JSON
+    #[serde(default, skip_serializing)]
+    pub encodings: Vec<String>
+}
+
+impl CheckInResult {
+    /// Whether the backend accounted for every one of the `sent` measurements, so they don't have to be sent again.
+    /// Backends which don't acknowledge measurements store all of them on success.
+    pub fn acknowledges(&self, sent: usize) -> bool {
+        match self.accepted {
+            Some(accepted) => accepted + self.rejected.len() == sent,
+            None => true
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MeasurementRejection {
+    pub sequence: Option<u32>,
+    pub on: String,
+    pub reason: String
+}
+
+/// Reports a watering done by the station
+#[derive(Serialize, Debug)]
+pub struct WateringReport {
+    pub watering: heapless::String<30>,
+    pub on: Option<String>
+}
+
+/// Fill level of the tank, reported when it runs low
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TankLevel {
+    pub fill_percentage: f64
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "_type")]
+pub enum StationInstruction {
+    /// The station was deleted at the backend
+    FactoryReset
+}
+
+/// Values of sensors which are not fitted are `None` and left out when serialized.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct StationMeasurement {
-    on: String,
-    battery_voltage: f64,
-    temperature: f64,
-    humidity: f64,
-    lux: f64,
-    soil_pf: f64,
-    tank_pf: f64
-}
-
-impl StationMeasurement {
-    pub fn random(on: String) -> StationMeasurement {
-        let mut rng = rand::thread_rng();
-
-        StationMeasurement {
-            on,
-            battery_voltage: rng.gen_range(2.2f64..3.3f64),
-            temperature: rng.gen_range(10f64..45f64),
-            humidity: rng.gen_range(5f64..100f64),
-            lux: rng.gen_range(0f64..300_000f64),
-            soil_pf: rng.gen_range(0f64..2000f64),
-            tank_pf: rng.gen_range(0f64..2000f64),
+    /// RFC3339 with milliseconds
+    pub on: String,
+    /// Increases with every measurement, also across resets, so the backend can drop a measurement it received before
+    pub sequence: u32,
+    /// Random id which changes when the RTC memory is lost, in case the sequence starts over after the flash was erased
+    pub boot_id: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_voltage: Option<f64>,
+    /// Estimated state of charge of the battery
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_percentage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soil_pf: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tank_pf: Option<f64>,
+    /// Calibrated moisture percentage of `soil_pf`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soil_moisture: Option<f64>,
+    /// Calibrated fill percentage of `tank_pf`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tank_fill: Option<f64>,
+    /// Spread of the samples the values are the mean of, only sent when enabled in the `SamplingConfig`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statistics: Option<SensorStatistics>
+}
+
+#[derive(Serialize, Debug)]
+pub struct CheckIn {
+    pub measurements: Vec<StationMeasurement>,
+    pub capabilities: Capabilities
+}
+
+/// Sends the check-in in the compact encoding when `prefer_compact`, unless it can't be encoded compactly.
+#[cfg(target_os = "espidf")]
+pub fn check_in(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, station_id: &Uuid, request: &CheckIn, idempotency_key: &str, prefer_compact: bool) -> Result<CheckInResult, MyceliumError> {
+    let (content_type, payload_vec) = match prefer_compact.then(|| compact::encode(request)).flatten() {
+        Some(encoded) => (compact::CONTENT_TYPE, encoded),
+        None => ("application/json", serde_json::to_vec(request)?)
+    };
+    let payload = payload_vec.as_slice();
+    let payload_length = format!("{}", payload.len());
+    let bearer = format!("Bearer {}", access_token);
+    let headers = [
+        ("content-type", content_type),
+        ("authorization", bearer.as_str()),
+        ("content-length", &*payload_length),
+        ("idempotency-key", idempotency_key),
+    ];
+    let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
+    let url = format!("{}/api/stations/{}/checkin", base_url, station_id);
+    let mut request = client.put(url.as_str(), &headers)?;
+
+    request.write_all(payload)?;
+    request.flush()?;
+
+    let response = &mut request.submit()?;
+
+    if response.status() == 200 {
+        let (_, body) = response.split();
+        let contents = read_body(body)?;
+
+        Ok(serde_json::from_slice(&contents)?)
+    } else {
+        Err(MyceliumError::UnexpectedResponse { status: response.status() })
+    }
+}
+
+/// Reads a response body until the end, it arrives in chunks of however much the connection has buffered.
+fn read_body<R: embedded_svc::io::Read>(body: &mut R) -> Result<Vec<u8>, R::Error> {
+    let mut contents = Vec::new();
+    let mut buf = [0u8; 512];
+
+    loop {
+        let read = body.read(&mut buf)?;
+
+        if read == 0 {
+            break;
         }
+
+        contents.extend_from_slice(&buf[..read]);
     }
+
+    Ok(contents)
 }
 
-pub fn check_in(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, station_id: &Uuid, measurements: Vec<StationMeasurement>) -> Result<(), MyceliumError> {
-    let payload_vec = serde_json::to_vec(&measurements)?;
+#[cfg(target_os = "espidf")]
+pub fn update_station(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, station_id: &Uuid, update: &StationUpdate) -> Result<(), MyceliumError> {
+    let payload_vec = serde_json::to_vec(&update)?;
     let payload = payload_vec.as_slice();
     let payload_length = format!("{}", payload.len());
     let bearer = format!("Bearer {}", access_token);
@@ -77,7 +245,7 @@ pub fn check_in(client: &mut Client<EspHttpConnection>, access_token: &heapless:
         ("content-length", &*payload_length),
     ];
     let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
-    let url = format!("{}/api/stations/{}/checkin", base_url, station_id);
+    let url = format!("{}/api/stations/{}", base_url, station_id);
     let mut request = client.put(url.as_str(), &headers)?;
 
     request.write_all(payload)?;
@@ -92,6 +260,103 @@ pub fn check_in(client: &mut Client<EspHttpConnection>, access_token: &heapless:
     }
 }
 
+#[cfg(target_os = "espidf")]
+pub fn watered(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, station_id: &Uuid, report: &WateringReport) -> Result<(), MyceliumError> {
+    let payload_vec = serde_json::to_vec(&report)?;
+    let payload = payload_vec.as_slice();
+    let payload_length = format!("{}", payload.len());
+    let bearer = format!("Bearer {}", access_token);
+    let headers = [
+        ("content-type", "application/json"),
+        ("authorization", bearer.as_str()),
+        ("content-length", &*payload_length),
+    ];
+    let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
+    let url = format!("{}/api/stations/{}/watered", base_url, station_id);
+    let mut request = client.post(url.as_str(), &headers)?;
+
+    request.write_all(payload)?;
+    request.flush()?;
+
+    let response = &mut request.submit()?;
+
+    if response.status() == 200 {
+        Ok(())
+    } else {
+        Err(MyceliumError::UnexpectedResponse { status: response.status() })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn low_water(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, station_id: &Uuid, level: &TankLevel) -> Result<(), MyceliumError> {
+    let payload_vec = serde_json::to_vec(&level)?;
+    let payload = payload_vec.as_slice();
+    let payload_length = format!("{}", payload.len());
+    let bearer = format!("Bearer {}", access_token);
+    let headers = [
+        ("content-type", "application/json"),
+        ("authorization", bearer.as_str()),
+        ("content-length", &*payload_length),
+    ];
+    let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
+    let url = format!("{}/api/stations/{}/lowwater", base_url, station_id);
+    let mut request = client.post(url.as_str(), &headers)?;
+
+    request.write_all(payload)?;
+    request.flush()?;
+
+    let response = &mut request.submit()?;
+
+    if response.status() == 200 {
+        Ok(())
+    } else {
+        Err(MyceliumError::UnexpectedResponse { status: response.status() })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn list_stations(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>) -> Result<Vec<Station>, MyceliumError> {
+    let bearer = format!("Bearer {}", access_token);
+    let headers = [
+        ("authorization", bearer.as_str()),
+    ];
+    let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
+    let url = format!("{}/api/stations", base_url);
+    let request = client.request(Method::Get, url.as_str(), &headers)?;
+
+    let response = &mut request.submit()?;
+
+    if response.status() == 200 {
+        let (_, body) = response.split();
+        let contents = read_body(body)?;
+
+        Ok(serde_json::from_slice(&contents)?)
+    } else {
+        Err(MyceliumError::UnexpectedResponse { status: response.status() })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+pub fn delete_station(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, station_id: &Uuid) -> Result<(), MyceliumError> {
+    let bearer = format!("Bearer {}", access_token);
+    let headers = [
+        ("authorization", bearer.as_str()),
+        ("content-length", "0"),
+    ];
+    let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
+    let url = format!("{}/api/stations/{}", base_url, station_id);
+    let request = client.request(Method::Delete, url.as_str(), &headers)?;
+
+    let response = request.submit()?;
+
+    if response.status() == 200 {
+        Ok(())
+    } else {
+        Err(MyceliumError::UnexpectedResponse { status: response.status() })
+    }
+}
+
+#[cfg(target_os = "espidf")]
 pub fn insert_plant(client: &mut Client<EspHttpConnection>, access_token: &heapless::String<756>, insert: &StationInsert) -> Result<Uuid, MyceliumError> {
 
     let payload_vec = serde_json::to_vec(&insert)?;
@@ -127,6 +392,81 @@ pub fn insert_plant(client: &mut Client<EspHttpConnection>, access_token: &heapl
     }
 }
 
+/// Time of the backend from the `Date` header, to set the clock when no time server can be reached.
+#[cfg(target_os = "espidf")]
+pub fn server_time(client: &mut Client<EspHttpConnection>) -> Result<Option<u64>, MyceliumError> {
+    let base_url = option_env!("MYCELIUM_BASE_URL").unwrap_or("http://reindeer-liked-lamprey.ngrok-free.app");
+    let request = client.request(Method::Head, base_url, &[])?;
+    let response = request.submit()?;
+
+    Ok(response.header("date")
+        .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+        .map(|date| date.timestamp() as u64))
+}
+
+pub trait MyceliumBackend : Send + Sync + Clone {
+    fn check_in(&self, access_token: &heapless::String<756>, station_id: &Uuid, request: &CheckIn, idempotency_key: &str, prefer_compact: bool) -> Result<CheckInResult, MyceliumError>;
+    fn insert_plant(&self, access_token: &heapless::String<756>, insert: &StationInsert) -> Result<Uuid, MyceliumError>;
+    fn list_stations(&self, access_token: &heapless::String<756>) -> Result<Vec<Station>, MyceliumError>;
+    fn update_station(&self, access_token: &heapless::String<756>, station_id: &Uuid, update: &StationUpdate) -> Result<(), MyceliumError>;
+    fn watered(&self, access_token: &heapless::String<756>, station_id: &Uuid, report: &WateringReport) -> Result<(), MyceliumError>;
+    fn low_water(&self, access_token: &heapless::String<756>, station_id: &Uuid, level: &TankLevel) -> Result<(), MyceliumError>;
+    fn delete_station(&self, access_token: &heapless::String<756>, station_id: &Uuid) -> Result<(), MyceliumError>;
+    fn server_time(&self) -> Result<Option<u64>, MyceliumError>;
+}
+
+#[cfg(target_os = "espidf")]
+pub struct EspMyceliumBackend {
+    client: EspHttpClient
+}
+
+#[cfg(target_os = "espidf")]
+impl EspMyceliumBackend {
+    pub fn new(client: EspHttpClient) -> EspMyceliumBackend { EspMyceliumBackend { client } }
+}
+
+#[cfg(target_os = "espidf")]
+impl Clone for EspMyceliumBackend {
+    fn clone(&self) -> Self {
+        EspMyceliumBackend { client: self.client.clone() }
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl MyceliumBackend for EspMyceliumBackend {
+    fn check_in(&self, access_token: &heapless::String<756>, station_id: &Uuid, request: &CheckIn, idempotency_key: &str, prefer_compact: bool) -> Result<CheckInResult, MyceliumError> {
+        check_in(&mut self.client.lock(), access_token, station_id, request, idempotency_key, prefer_compact)
+    }
+
+    fn insert_plant(&self, access_token: &heapless::String<756>, insert: &StationInsert) -> Result<Uuid, MyceliumError> {
+        insert_plant(&mut self.client.lock(), access_token, insert)
+    }
+
+    fn list_stations(&self, access_token: &heapless::String<756>) -> Result<Vec<Station>, MyceliumError> {
+        list_stations(&mut self.client.lock(), access_token)
+    }
+
+    fn update_station(&self, access_token: &heapless::String<756>, station_id: &Uuid, update: &StationUpdate) -> Result<(), MyceliumError> {
+        update_station(&mut self.client.lock(), access_token, station_id, update)
+    }
+
+    fn watered(&self, access_token: &heapless::String<756>, station_id: &Uuid, report: &WateringReport) -> Result<(), MyceliumError> {
+        watered(&mut self.client.lock(), access_token, station_id, report)
+    }
+
+    fn low_water(&self, access_token: &heapless::String<756>, station_id: &Uuid, level: &TankLevel) -> Result<(), MyceliumError> {
+        low_water(&mut self.client.lock(), access_token, station_id, level)
+    }
+
+    fn delete_station(&self, access_token: &heapless::String<756>, station_id: &Uuid) -> Result<(), MyceliumError> {
+        delete_station(&mut self.client.lock(), access_token, station_id)
+    }
+
+    fn server_time(&self) -> Result<Option<u64>, MyceliumError> {
+        server_time(&mut self.client.lock())
+    }
+}
+
 impl From<FromUtf8Error> for MyceliumError {
     fn from(value: FromUtf8Error) -> Self {
         MyceliumError::String(value)
@@ -142,4 +482,45 @@ impl From<serde_json::Error> for MyceliumError {
     fn from(value: serde_json::Error) -> Self {
         MyceliumError::Json(value)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+
+    /// Hands out the body in chunks of at most `chunk` bytes, like a connection receiving it over several packets.
+    struct Chunked<'a> {
+        body: &'a [u8],
+        chunk: usize
+    }
+
+    impl embedded_svc::io::Io for Chunked<'_> {
+        type Error = Infallible;
+    }
+
+    impl embedded_svc::io::Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let read = self.body.len().min(self.chunk).min(buf.len());
+            buf[..read].copy_from_slice(&self.body[..read]);
+            self.body = &self.body[read..];
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn reads_a_check_in_result_received_in_chunks() {
+        let rejected = (0..40)
+            .map(|sequence| format!(r#"{{"sequence":{},"on":"1970-01-01T00:00:12.000Z","reason":"Clock not synchronized"}}"#, sequence))
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(r#"{{"watering":null,"instruction":null,"schedule":null,"accepted":2,"rejected":[{}],"encodings":[]}}"#, rejected);
+
+        let contents = read_body(&mut Chunked { body: json.as_bytes(), chunk: 100 }).unwrap();
+        let result = serde_json::from_slice::<CheckInResult>(&contents).unwrap();
+
+        assert!(json.len() > 1024);
+        assert_eq!(result.rejected.len(), 40);
+        assert!(result.acknowledges(42));
+    }
+}
@@ -1,27 +1,110 @@
-use std::sync::{PoisonError, RwLockWriteGuard};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockWriteGuard};
+use std::time::Duration;
 
 
-
-use esp_idf_sys::EspError;
+use log::{error, info, warn};
+use retry::delay::Fixed;
+use retry::{OperationResult, retry};
 use serde::{Deserialize, Serialize};
 
 use heapless::String;
+use uuid::Uuid;
+
 
+use crate::auth0::{Auth0, AuthError, TokenResult, TokenStatus};
+use crate::clock::{Clock, ClockError};
+use crate::device::Device;
+use crate::kv::{KvStore, KvStoreError};
+use crate::mycelium::{Capabilities, MyceliumBackend, MyceliumError, StationInsert, StationUpdate, WateringSchedule};
+use crate::pump::PumpError;
+use crate::rpc;
+use crate::rpc::{RejectionReason, Responders, RpcRequest, RpcResponse, truncated};
+use crate::settings::FlashState;
+use crate::sys::EspError;
+use crate::tokens::TokenWallet;
+use crate::validation::{cron_expression, duration, FieldError, ValidationError, Validator, wpa_passphrase};
+use crate::wifi::{MyceliumWifi, MyceliumWifiSettings};
+
+/// Settings as sent by the client. Fields are unbounded so that oversized values surface as field errors from
+/// `validate` instead of an opaque deserialization error.
+#[derive(Deserialize, Clone, Debug)]
+pub struct OnboardingSettingsInput {
+    pub name: std::string::String,
+    #[serde(default)]
+    pub location: std::string::String,
+    #[serde(default)]
+    pub description: std::string::String,
+    pub wifi_ssid: std::string::String,
+    #[serde(default)]
+    pub wifi_password: std::string::String,
+    #[serde(default)]
+    pub watering_schedule: Option<WateringScheduleInput>
+}
 
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "_type")]
+pub enum WateringScheduleInput {
+    Interval { schedule: std::string::String, period: std::string::String },
+    Threshold { below_soil_pf: u32, period: std::string::String }
+}
 
-use crate::auth0::{AuthError};
-use crate::kv::{KvStoreError};
-use crate::mycelium::MyceliumError;
-use crate::tokens::TokenWalletError;
-use crate::wifi::{MyceliumWifiSettings};
+impl OnboardingSettingsInput {
+    pub fn validate(&self) -> Result<OnboardingSettings, Vec<FieldError>> {
+        let mut validator = Validator::new();
+
+        validator.not_blank("name", &self.name);
+        let name = validator.bounded("name", &self.name);
+        let location = validator.bounded("location", &self.location);
+        let description = validator.bounded("description", &self.description);
+
+        validator.not_blank("wifi_ssid", &self.wifi_ssid);
+        let wifi_ssid = validator.bounded("wifi_ssid", &self.wifi_ssid);
+
+        validator.check("wifi_password", wpa_passphrase(&self.wifi_password));
+        let wifi_password = truncated(&self.wifi_password);
+
+        let watering_schedule = self.watering_schedule.as_ref().map(|schedule| schedule.validate(&mut validator));
+
+        validator.finish(OnboardingSettings { name, location, description, wifi_ssid, wifi_password, watering_schedule })
+    }
+}
 
-#[derive(Deserialize, Clone, Default, Debug)]
+impl WateringScheduleInput {
+    pub fn validate(&self, validator: &mut Validator) -> WateringSchedule {
+        match self {
+            WateringScheduleInput::Interval { schedule, period } => {
+                validator.check("watering_schedule.schedule", cron_expression(schedule));
+                validator.check("watering_schedule.period", duration(period));
+
+                WateringSchedule::Interval {
+                    schedule: validator.bounded("watering_schedule.schedule", schedule.trim()),
+                    period: validator.bounded("watering_schedule.period", period.trim())
+                }
+            }
+            WateringScheduleInput::Threshold { below_soil_pf, period } => {
+                if *below_soil_pf == 0 {
+                    validator.error("watering_schedule.below_soil_pf", ValidationError::Invalid { reason: "threshold must be positive" });
+                }
+                validator.check("watering_schedule.period", duration(period));
+
+                WateringSchedule::Threshold {
+                    below_soil_pf: *below_soil_pf,
+                    period: validator.bounded("watering_schedule.period", period.trim())
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default, Debug)]
 pub struct OnboardingSettings {
     pub name: String<128>,
     pub location: String<128>,
     pub description: String<128>,
     pub wifi_ssid: String<32>,
-    pub wifi_password: String<64>
+    pub wifi_password: String<64>,
+    /// Uses the station's current schedule when re-onboarding, otherwise the default schedule
+    pub watering_schedule: Option<WateringSchedule>
 }
 
 impl OnboardingSettings {
@@ -33,29 +116,246 @@ impl OnboardingSettings {
 #[derive(Deserialize, Clone, Debug)]
 #[serde(tag = "_type")]
 pub enum OnboardingCommand {
-    Initialize { settings: OnboardingSettings },
+    Initialize { settings: OnboardingSettingsInput },
     Reboot
 }
 
+pub type OnboardingRequest = RpcRequest<OnboardingCommand>;
+pub type OnboardingResponse = RpcResponse<OnboardingState>;
+
 #[derive(Serialize, Debug, Clone)]
 #[serde(tag = "_type")]
 pub enum OnboardingState {
     AwaitingSettings,
     ProvisioningWifi,
-    Failed { error: String<256> },
+    Failed { error: String<256>, fields: Vec<FieldError> },
     AwaitingAuthorization { url: String<255> },
     Complete
 }
 
+type StateSubscriber = Box<dyn Fn(&OnboardingState) + Send + Sync>;
+
+/// Drives the onboarding state machine independently of the transport the commands arrive on.
+pub struct OnboardingController<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock, D : Device> {
+    flash_state: FlashState<K>,
+    wifi: W,
+    auth: A,
+    backend: B,
+    clock: C,
+    device: D,
+    capabilities: Capabilities,
+    state: RwLock<OnboardingState>,
+    subscribers: Mutex<Vec<StateSubscriber>>,
+    responders: Responders<OnboardingState>
+}
+
+impl<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock, D : Device> OnboardingController<K, W, A, B, C, D> {
+    pub fn new(flash_state: FlashState<K>, wifi: W, auth: A, backend: B, clock: C, device: D, capabilities: Capabilities) -> OnboardingController<K, W, A, B, C, D> {
+        OnboardingController {
+            flash_state,
+            wifi,
+            auth,
+            backend,
+            clock,
+            device,
+            capabilities,
+            state: RwLock::new(OnboardingState::AwaitingSettings),
+            subscribers: Mutex::new(Vec::new()),
+            responders: Responders::default()
+        }
+    }
+
+    pub fn state(&self) -> OnboardingState {
+        self.state.read().unwrap().clone()
+    }
+
+    /// Registers a subscriber which immediately receives the current state and every state published afterwards.
+    pub fn subscribe<F>(&self, subscriber: F) where F : Fn(&OnboardingState) + Send + Sync + 'static {
+        subscriber(&self.state());
+        self.subscribers.lock().unwrap().push(Box::new(subscriber));
+    }
+
+    /// Registers a subscriber which receives the response to every request.
+    pub fn subscribe_responses<F>(&self, responder: F) where F : Fn(&OnboardingResponse) + Send + Sync + 'static {
+        self.responders.subscribe(responder);
+    }
+
+    /// Decodes a raw JSON request as received from a transport and handles it.
+    pub fn process(&self, bytes: &[u8]) {
+        match rpc::decode(bytes) {
+            Ok(request) => self.handle(request),
+            Err(rejection) => self.responders.respond(rejection)
+        }
+    }
+
+    pub fn handle(&self, request: OnboardingRequest) {
+        let id = request.id;
+
+        match request.command {
+            OnboardingCommand::Initialize { settings } => {
+                let settings = match settings.validate() {
+                    Ok(settings) => settings,
+                    Err(fields) => {
+                        warn!("Invalid settings: {:?}", fields);
+                        self.publish_or_log(OnboardingState::Failed { error: String::from("Invalid settings"), fields: fields.clone() });
+                        self.responders.respond(OnboardingResponse::Rejected { id: Some(id), reason: RejectionReason::InvalidSettings { fields } });
+                        return
+                    }
+                };
+
+                self.responders.respond(OnboardingResponse::Accepted { id });
+
+                // only a failing connection is worth another try, an abandoned authorization would take another 15 minutes
+                let result = retry(Fixed::from_millis(10).take(5), || match self.initialize(&settings) {
+                    Ok(_) => OperationResult::Ok(()),
+                    Err(err) if err.is_transport() => {
+                        warn!("Retrying onboarding: {:?}", err);
+                        OperationResult::Retry(err)
+                    }
+                    Err(err) => OperationResult::Err(err)
+                });
+
+                if let Err(err) = result {
+                    error!("Onboarding failed after {} tries: {:?}", err.tries, err.error);
+                    let error = format!("{:?}", err.error);
+                    self.publish_or_log(OnboardingState::Failed { error: truncated(&error), fields: Vec::new() });
+                }
+
+                self.responders.respond(OnboardingResponse::Completed { id, result: self.state() });
+            },
+            OnboardingCommand::Reboot => {
+                self.responders.respond(OnboardingResponse::Accepted { id });
+                // Give the transport a moment to deliver the acknowledgement
+                std::thread::sleep(Duration::from_millis(500));
+                self.device.restart()
+            }
+        }
+    }
+
+    fn initialize(&self, settings: &OnboardingSettings) -> Result<(), AppError> {
+        self.publish(OnboardingState::ProvisioningWifi)?;
+
+        let wifi_settings = self.flash_state.get_opt_wifi_settings()?.filter(|x: &MyceliumWifiSettings| x.ssid == settings.wifi_ssid).unwrap_or(settings.clone().wifi_settings());
+        let enriched_settings = self.wifi.connect(wifi_settings)?;
+
+        self.flash_state.set_wifi_settings(enriched_settings)?;
+
+        let resp = self.auth.request_device_code()?;
+
+        info!("Got url: {:?}", resp.verification_uri_complete);
+
+        self.publish(OnboardingState::AwaitingAuthorization { url: resp.verification_uri_complete })?;
+
+        let started = self.clock.monotonic();
+
+        loop {
+            if self.clock.monotonic() - started >= Duration::from_secs(resp.expires_in as u64) {
+                return Err(AppError::AuthorizationExpired)
+            }
+
+            match self.auth.poll_token(&resp.device_code) {
+                Ok(TokenResult::Error { error: TokenStatus::ExpiredToken }) => return Err(AppError::AuthorizationExpired),
+                Ok(TokenResult::Error { error }) => warn!("Auth0 error {:?}", error),
+                Ok(TokenResult::AccessToken { .. }) => info!("Skipping!"),
+                Ok(TokenResult::Full { access_token, refresh_token, expires_in }) => {
+                    let wallet = TokenWallet::new(access_token.clone(), refresh_token, expires_in, self.clock.synchronized()?);
+
+                    self.flash_state.set_token_wallet(wallet)?;
+
+                    let station_id = self.register(&access_token, settings)?;
+
+                    self.flash_state.set_station_id(station_id)?;
+                    self.publish(OnboardingState::Complete)?;
+
+                    return Ok(())
+                }
+                Err(err) => warn!("Auth0 error {:?}", err),
+            }
+
+            self.clock.sleep(Duration::from_secs(resp.interval))
+        }
+    }
+
+    /// Reuses the station of the authorized user with this MAC address when re-onboarding, keeping its name, location and
+    /// watering schedule unless a schedule is given. Otherwise a new station is registered with the given settings.
+    fn register(&self, access_token: &String<756>, settings: &OnboardingSettings) -> Result<Uuid, AppError> {
+        let mac = self.device.mac_address()?;
+        let existing = self.backend.list_stations(access_token)?.into_iter().find(|s| s.mac.eq_ignore_ascii_case(&mac));
+
+        match existing {
+            Some(station) => {
+                info!("Re-onboarding station {} ({}), keeping its settings", station.id, station.name);
+
+                let schedule = match &settings.watering_schedule {
+                    Some(schedule) => {
+                        let update = StationUpdate { name: None, location: None, description: None, water_schedule: Some(schedule.clone()) };
+                        self.backend.update_station(access_token, &station.id, &update)?;
+                        schedule.clone()
+                    }
+                    None => station.watering_schedule
+                };
+
+                self.flash_state.set_watering_schedule(schedule)?;
+
+                Ok(station.id)
+            }
+            None => {
+                let insert = StationInsert {
+                    mac,
+                    name: settings.name.clone(),
+                    location: settings.location.clone(),
+                    description: settings.description.clone(),
+                    watering_schedule: settings.watering_schedule.clone().unwrap_or_default(),
+                    capabilities: self.capabilities.clone()
+                };
+
+                let station_id = self.backend.insert_plant(access_token, &insert)?;
+                self.flash_state.set_watering_schedule(insert.watering_schedule)?;
+
+                Ok(station_id)
+            }
+        }
+    }
+
+    fn publish(&self, state: OnboardingState) -> Result<(), AppError> {
+        *self.state.write()? = state.clone();
+
+        for subscriber in self.subscribers.lock().unwrap().iter() {
+            subscriber(&state);
+        }
+
+        Ok(())
+    }
+
+    /// For publishing the failure itself, which has no one left to return an error to.
+    fn publish_or_log(&self, state: OnboardingState) {
+        if let Err(err) = self.publish(state) {
+            error!("Could not publish the onboarding state: {:?}", err);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum AppError {
     RwLock,
+    /// The user didn't authorize the station before the device code expired
+    AuthorizationExpired,
     Kv(KvStoreError),
     Auth(AuthError),
-    TokenWallet(TokenWalletError),
+    Clock(ClockError),
     Mycelium(MyceliumError),
     Json(serde_json::Error),
-    Esp(EspError)
+    Esp(EspError),
+    Pump(PumpError),
+    /// The factory partition has no valid BLE passkey, the device can't be paired with
+    Unprovisioned
+}
+
+impl AppError {
+    /// Whether the network failed, rather than the user or the request, so trying again may succeed.
+    pub fn is_transport(&self) -> bool {
+        matches!(self, AppError::Esp(_) | AppError::Auth(AuthError::IO(_)) | AppError::Mycelium(MyceliumError::IO(_)))
+    }
 }
 
 impl From<EspError> for AppError {
@@ -88,12 +388,151 @@ impl From<KvStoreError> for AppError {
     }
 }
 
-impl From<TokenWalletError> for AppError {
-    fn from(value: TokenWalletError) -> Self { AppError::TokenWallet(value) }
+impl From<PumpError> for AppError {
+    fn from(value: PumpError) -> Self { AppError::Pump(value) }
+}
+
+impl From<ClockError> for AppError {
+    fn from(value: ClockError) -> Self { AppError::Clock(value) }
 }
 
 impl From<PoisonError<RwLockWriteGuard<'_, OnboardingState>>> for AppError {
     fn from(_value: PoisonError<RwLockWriteGuard<'_, OnboardingState>>) -> Self {
         AppError::RwLock
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::fakes::{FakeAuth, FakeBackend, FakeClock, FakeDevice, FakeWifi, MemoryKvStore};
+    use crate::mycelium::Station;
+
+    const NOW: u64 = 1_714_566_609;
+
+    type TestController = OnboardingController<MemoryKvStore, FakeWifi, FakeAuth, FakeBackend, FakeClock, FakeDevice>;
+
+    fn controller(wifi: FakeWifi, auth: FakeAuth, backend: FakeBackend) -> (TestController, FlashState<MemoryKvStore>, Arc<Mutex<Vec<OnboardingResponse>>>) {
+        let kv = MemoryKvStore::default();
+        let capabilities = Capabilities { measurements: vec!["soilPf"], parts: Vec::new() };
+        let controller = OnboardingController::new(FlashState::new(kv.clone()), wifi, auth, backend, FakeClock::new(NOW), FakeDevice, capabilities);
+        let responses = Arc::new(Mutex::new(Vec::new()));
+        let received = responses.clone();
+
+        controller.subscribe_responses(move |response| received.lock().unwrap().push(response.clone()));
+
+        (controller, FlashState::new(kv), responses)
+    }
+
+    fn initialize(name: &str) -> OnboardingRequest {
+        let settings = OnboardingSettingsInput {
+            name: name.to_string(),
+            location: "Kitchen".to_string(),
+            description: std::string::String::new(),
+            wifi_ssid: "Mycelium".to_string(),
+            wifi_password: "correct horse".to_string(),
+            watering_schedule: None
+        };
+
+        OnboardingRequest { id: 1, command: OnboardingCommand::Initialize { settings } }
+    }
+
+    fn failure(controller: &TestController) -> std::string::String {
+        match controller.state() {
+            OnboardingState::Failed { error, .. } => error.to_string(),
+            state => panic!("expected onboarding to fail, but it is {:?}", state)
+        }
+    }
+
+    #[test]
+    fn registers_the_station() {
+        let backend = FakeBackend::default();
+        let (controller, flash_state, responses) = controller(FakeWifi::default(), FakeAuth::default(), backend.clone());
+
+        controller.handle(initialize("Basil"));
+
+        assert!(matches!(controller.state(), OnboardingState::Complete));
+        assert!(matches!(responses.lock().unwrap()[..], [
+            OnboardingResponse::Accepted { id: 1 },
+            OnboardingResponse::Completed { id: 1, result: OnboardingState::Complete }
+        ]));
+        assert_eq!(*backend.inserted.lock().unwrap(), vec!["Basil".to_string()]);
+        assert_eq!(flash_state.get_station_id().unwrap(), FakeBackend::STATION_ID);
+        assert_eq!(flash_state.get_token_wallet().unwrap().refresh_token, "refresh-token");
+        assert_eq!(flash_state.get_wifi_settings().unwrap().channel, Some(6));
+        assert_eq!(flash_state.get_opt_watering_schedule().unwrap(), Some(WateringSchedule::default()));
+    }
+
+    #[test]
+    fn reuses_the_station_with_the_same_mac_address() {
+        let schedule = WateringSchedule::Threshold { below_soil_pf: 800, period: String::from("3 seconds") };
+        let station = Station {
+            id: Uuid::from_u128(42),
+            mac: FakeDevice::MAC.to_lowercase(),
+            name: "Basil".to_string(),
+            location: "Kitchen".to_string(),
+            watering_schedule: schedule.clone()
+        };
+        let backend = FakeBackend { stations: vec![station], ..FakeBackend::default() };
+        let (controller, flash_state, _) = controller(FakeWifi::default(), FakeAuth::default(), backend.clone());
+
+        controller.handle(initialize("Mint"));
+
+        assert!(matches!(controller.state(), OnboardingState::Complete));
+        assert!(backend.inserted.lock().unwrap().is_empty());
+        assert_eq!(flash_state.get_station_id().unwrap(), Uuid::from_u128(42));
+        assert_eq!(flash_state.get_opt_watering_schedule().unwrap(), Some(schedule));
+    }
+
+    #[test]
+    fn fails_when_the_wifi_is_unreachable() {
+        let wifi = FakeWifi { unreachable: true, ..FakeWifi::default() };
+        let (controller, flash_state, responses) = controller(wifi.clone(), FakeAuth::default(), FakeBackend::default());
+
+        controller.handle(initialize("Basil"));
+
+        assert!(failure(&controller).starts_with("Esp("));
+        assert_eq!(*wifi.attempts.lock().unwrap(), 6);
+        assert!(matches!(responses.lock().unwrap().last(), Some(OnboardingResponse::Completed { id: 1, result: OnboardingState::Failed { .. } })));
+        assert!(flash_state.get_opt_wifi_settings().unwrap().is_none());
+        assert!(!flash_state.has_station_id().unwrap());
+    }
+
+    #[test]
+    fn times_out_when_the_user_never_authorizes() {
+        let (controller, flash_state, _) = controller(FakeWifi::default(), FakeAuth { pending: true }, FakeBackend::default());
+
+        controller.handle(initialize("Basil"));
+
+        assert_eq!(failure(&controller), "AuthorizationExpired");
+        // not retried
+        assert!(controller.clock.monotonic() >= Duration::from_secs(900));
+        assert!(controller.clock.monotonic() < Duration::from_secs(2 * 900));
+        assert!(flash_state.get_token_wallet().is_err());
+        assert!(!flash_state.has_station_id().unwrap());
+    }
+
+    #[test]
+    fn fails_when_the_backend_errors() {
+        let backend = FakeBackend { failing: true, ..FakeBackend::default() };
+        let wifi = FakeWifi::default();
+        let (controller, flash_state, _) = controller(wifi.clone(), FakeAuth::default(), backend);
+
+        controller.handle(initialize("Basil"));
+
+        assert_eq!(failure(&controller), "Mycelium(UnexpectedResponse { status: 500 })");
+        assert_eq!(*wifi.attempts.lock().unwrap(), 1);
+        assert!(!flash_state.has_station_id().unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let (controller, _, responses) = controller(FakeWifi::default(), FakeAuth::default(), FakeBackend::default());
+
+        controller.handle(initialize(" "));
+
+        assert_eq!(failure(&controller), "Invalid settings");
+        assert!(matches!(responses.lock().unwrap()[..], [OnboardingResponse::Rejected { id: Some(1), reason: RejectionReason::InvalidSettings { .. } }]));
+    }
+}
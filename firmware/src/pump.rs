@@ -0,0 +1,374 @@
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::gpio::{AnyIOPin, AnyOutputPin, Input, Output, PinDriver};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::kv::{KvStore, KvStoreError};
+use crate::settings::FlashState;
+use crate::sys::EspError;
+
+/// Time for the pump to spin down before checking whether it actually stopped.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+pub trait Pump : Send {
+    fn start(&mut self) -> Result<(), EspError>;
+    fn stop(&mut self) -> Result<(), EspError>;
+    /// Whether the pump is actually running, `None` when the hardware can't sense it.
+    fn is_running(&self) -> Option<bool>;
+}
+
+/// Pump or valve switched by a MOSFET on a GPIO. An optional sense input (e.g. a current sense comparator, active high)
+/// detects a pump which keeps running, without it a stuck pump only shows as the tank level dropping.
+#[cfg(target_os = "espidf")]
+pub struct GpioPump {
+    output: PinDriver<'static, AnyOutputPin, Output>,
+    sense: Option<PinDriver<'static, AnyIOPin, Input>>
+}
+
+#[cfg(target_os = "espidf")]
+impl GpioPump {
+    pub fn new(output: AnyOutputPin, sense: Option<AnyIOPin>) -> Result<GpioPump, EspError> {
+        let mut output = PinDriver::output(output)?;
+        output.set_low()?;
+
+        let sense = match sense {
+            Some(pin) => Some(PinDriver::input(pin)?),
+            None => None
+        };
+
+        Ok(GpioPump { output, sense })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Pump for GpioPump {
+    fn start(&mut self) -> Result<(), EspError> {
+        self.output.set_high()
+    }
+
+    fn stop(&mut self) -> Result<(), EspError> {
+        self.output.set_low()
+    }
+
+    fn is_running(&self) -> Option<bool> {
+        self.sense.as_ref().map(|sense| sense.is_high())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PumpLimits {
+    pub max_run: Duration,
+    pub max_per_day: Duration,
+    /// Below this fill percentage the tank is considered empty, so the pump doesn't run dry
+    pub min_tank_fill: f64,
+    /// Percentage points the tank level may drop between two wakes without watering before the pump is considered
+    /// stuck on, above the noise of the tank probe
+    pub max_idle_drop: f64
+}
+
+impl Default for PumpLimits {
+    fn default() -> Self {
+        PumpLimits { max_run: Duration::from_secs(60), max_per_day: Duration::from_secs(10 * 60), min_tank_fill: 5.0, max_idle_drop: 10.0 }
+    }
+}
+
+/// Run time of the pump on a day (days since the unix epoch), to enforce the daily limit across deep sleep.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PumpUsage {
+    pub day: u64,
+    pub run_ms: u64
+}
+
+#[derive(Debug)]
+pub enum PumpError {
+    Esp(EspError),
+    Kv(KvStoreError),
+    TankEmpty { fill_percentage: f64 },
+    DailyLimitReached { max_per_day: Duration },
+    /// The pump kept running after being stopped, it stays disabled until the fault is cleared
+    StuckOn,
+    Faulted
+}
+
+/// Runs the pump within the limits. A stuck-on pump, detected when the pump senses whether it runs or from the tank
+/// level dropping while it should be off, latches a fault in flash, so it isn't started again after a restart.
+pub struct PumpController<P : Pump, C : Clock, K : KvStore> {
+    pump: P,
+    clock: C,
+    flash_state: FlashState<K>,
+    limits: PumpLimits
+}
+
+impl<P : Pump, C : Clock, K : KvStore> PumpController<P, C, K> {
+    pub fn new(pump: P, clock: C, flash_state: FlashState<K>, limits: PumpLimits) -> PumpController<P, C, K> {
+        PumpController { pump, clock, flash_state, limits }
+    }
+
+    /// Runs the pump for the requested duration, cut short by the limits. Returns how long the pump actually ran.
+    /// Without a tank probe the tank level is unknown, so there is no protection against running dry.
+    pub fn water(&mut self, requested: Duration, tank_fill: Option<f64>) -> Result<Duration, PumpError> {
+        if self.flash_state.get_pump_fault()? {
+            return Err(PumpError::Faulted)
+        }
+
+        if self.pump.is_running() == Some(true) {
+            return self.fault()
+        }
+
+        if let Some(fill_percentage) = tank_fill.filter(|fill| *fill < self.limits.min_tank_fill) {
+            return Err(PumpError::TankEmpty { fill_percentage })
+        }
+
+        let day = self.clock.now() / SECONDS_PER_DAY;
+        let usage = self.flash_state.get_opt_pump_usage()?.filter(|u| u.day == day).unwrap_or(PumpUsage { day, run_ms: 0 });
+        let remaining = self.limits.max_per_day.saturating_sub(Duration::from_millis(usage.run_ms));
+
+        if remaining.is_zero() {
+            return Err(PumpError::DailyLimitReached { max_per_day: self.limits.max_per_day })
+        }
+
+        let run = requested.min(self.limits.max_run).min(remaining);
+
+        if run < requested {
+            warn!("Limiting watering from {:?} to {:?}", requested, run);
+        }
+
+        let started = self.clock.monotonic();
+        let result = self.pump.start().and_then(|_| {
+            self.clock.sleep(run);
+            self.pump.stop()
+        });
+        let ran = self.clock.monotonic().saturating_sub(started);
+
+        if result.is_err() {
+            // make sure not to leave the pump running when starting failed half way
+            let _ = self.pump.stop();
+        }
+
+        // the usage only enforces the daily limit, failing to store it shouldn't hide how the pump did
+        if let Err(err) = self.flash_state.set_pump_usage(PumpUsage { day, run_ms: usage.run_ms + ran.as_millis() as u64 }) {
+            error!("Could not store the pump usage: {:?}", err);
+        }
+
+        result?;
+
+        self.clock.sleep(SETTLE_TIME);
+
+        if self.pump.is_running() == Some(true) {
+            return self.fault()
+        }
+
+        info!("Pump ran for {:?}", ran);
+
+        Ok(ran)
+    }
+
+    /// Checks whether the tank level dropped since the previous wake, while the pump didn't run since. Without a sense
+    /// input this is the only way to notice a pump which keeps running.
+    pub fn check_idle(&mut self, previous_fill: Option<f64>, tank_fill: Option<f64>) -> Result<(), PumpError> {
+        match (previous_fill, tank_fill) {
+            (Some(previous), Some(current)) if previous - current > self.limits.max_idle_drop => {
+                warn!("Tank level dropped from {:.1}% to {:.1}% without watering", previous, current);
+                self.fault()
+            }
+            _ => Ok(())
+        }
+    }
+
+    fn fault<T>(&mut self) -> Result<T, PumpError> {
+        error!("Pump is running while it should be stopped, disabling it");
+        let _ = self.pump.stop();
+        self.flash_state.set_pump_fault(true)?;
+        Err(PumpError::StuckOn)
+    }
+}
+
+impl From<EspError> for PumpError {
+    fn from(value: EspError) -> Self {
+        PumpError::Esp(value)
+    }
+}
+
+impl From<KvStoreError> for PumpError {
+    fn from(value: KvStoreError) -> Self {
+        PumpError::Kv(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::fakes::{FakeClock, MemoryKvStore};
+    use crate::sys::ESP_ERR_TIMEOUT;
+
+    const NOW: u64 = 1_714_566_609;
+
+    /// Adds up how long it ran. A stuck pump keeps running after being stopped.
+    struct FakePump {
+        clock: FakeClock,
+        running: bool,
+        started: Duration,
+        ran: Arc<Mutex<Duration>>,
+        stuck: bool,
+        sensed: bool
+    }
+
+    impl Pump for FakePump {
+        fn start(&mut self) -> Result<(), EspError> {
+            self.running = true;
+            self.started = self.clock.monotonic();
+            Ok(())
+        }
+
+        fn stop(&mut self) -> Result<(), EspError> {
+            if self.running {
+                *self.ran.lock().unwrap() += self.clock.monotonic() - self.started;
+            }
+            self.running = self.stuck;
+            Ok(())
+        }
+
+        fn is_running(&self) -> Option<bool> {
+            self.sensed.then_some(self.running)
+        }
+    }
+
+    fn controller(stuck: bool, sensed: bool) -> (PumpController<FakePump, FakeClock, MemoryKvStore>, Arc<Mutex<Duration>>) {
+        let clock = FakeClock::new(NOW);
+        let ran = Arc::new(Mutex::new(Duration::ZERO));
+        let pump = FakePump { clock: clock.clone(), running: false, started: Duration::ZERO, ran: ran.clone(), stuck, sensed };
+
+        (PumpController::new(pump, clock, FlashState::new(MemoryKvStore::default()), PumpLimits::default()), ran)
+    }
+
+    #[test]
+    fn runs_for_the_requested_duration() {
+        let (mut controller, ran) = controller(false, true);
+
+        assert_eq!(controller.water(Duration::from_secs(10), Some(50.0)).unwrap(), Duration::from_secs(10));
+        assert_eq!(*ran.lock().unwrap(), Duration::from_secs(10));
+        assert!(!controller.pump.running);
+    }
+
+    #[test]
+    fn limits_a_run_to_the_max_run_time() {
+        let (mut controller, ran) = controller(false, true);
+
+        assert_eq!(controller.water(Duration::from_secs(5 * 60), Some(50.0)).unwrap(), Duration::from_secs(60));
+        assert_eq!(*ran.lock().unwrap(), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn limits_the_run_time_per_day() {
+        let (mut controller, ran) = controller(false, true);
+        controller.flash_state.set_pump_usage(PumpUsage { day: NOW / SECONDS_PER_DAY, run_ms: 570_000 }).unwrap();
+
+        assert_eq!(controller.water(Duration::from_secs(60), Some(50.0)).unwrap(), Duration::from_secs(30));
+        assert!(matches!(controller.water(Duration::from_secs(60), Some(50.0)), Err(PumpError::DailyLimitReached { .. })));
+        assert_eq!(*ran.lock().unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn starts_over_with_the_daily_limit_the_next_day() {
+        let (mut controller, _) = controller(false, true);
+        controller.flash_state.set_pump_usage(PumpUsage { day: NOW / SECONDS_PER_DAY - 1, run_ms: 600_000 }).unwrap();
+
+        assert_eq!(controller.water(Duration::from_secs(60), Some(50.0)).unwrap(), Duration::from_secs(60));
+        assert_eq!(controller.flash_state.get_opt_pump_usage().unwrap().unwrap().run_ms, 60_000);
+    }
+
+    #[test]
+    fn does_not_run_with_an_empty_tank() {
+        let (mut controller, ran) = controller(false, true);
+
+        assert!(matches!(controller.water(Duration::from_secs(10), Some(3.0)), Err(PumpError::TankEmpty { .. })));
+        assert_eq!(*ran.lock().unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn runs_without_a_tank_probe() {
+        let (mut controller, _) = controller(false, true);
+
+        assert_eq!(controller.water(Duration::from_secs(10), None).unwrap(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn latches_a_fault_when_the_pump_keeps_running() {
+        let (mut controller, _) = controller(true, true);
+
+        assert!(matches!(controller.water(Duration::from_secs(10), Some(50.0)), Err(PumpError::StuckOn)));
+        assert!(controller.flash_state.get_pump_fault().unwrap());
+
+        // repaired, but still disabled until the fault is cleared
+        controller.pump.stuck = false;
+        controller.pump.running = false;
+        assert!(matches!(controller.water(Duration::from_secs(10), Some(50.0)), Err(PumpError::Faulted)));
+
+        controller.flash_state.set_pump_fault(false).unwrap();
+        assert_eq!(controller.water(Duration::from_secs(10), Some(50.0)).unwrap(), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn does_not_start_a_pump_which_is_already_running() {
+        let (mut controller, ran) = controller(false, true);
+        controller.pump.running = true;
+
+        assert!(matches!(controller.water(Duration::from_secs(10), Some(50.0)), Err(PumpError::StuckOn)));
+        assert_eq!(*ran.lock().unwrap(), Duration::ZERO);
+        assert!(controller.flash_state.get_pump_fault().unwrap());
+    }
+
+    #[test]
+    fn cannot_detect_a_stuck_pump_without_sensing() {
+        let (mut controller, _) = controller(true, false);
+
+        assert_eq!(controller.water(Duration::from_secs(10), Some(50.0)).unwrap(), Duration::from_secs(10));
+        assert!(!controller.flash_state.get_pump_fault().unwrap());
+    }
+
+    #[test]
+    fn latches_a_fault_when_the_tank_drains_without_watering() {
+        let (mut controller, _) = controller(false, false);
+
+        controller.check_idle(None, Some(80.0)).unwrap();
+        controller.check_idle(Some(80.0), None).unwrap();
+        controller.check_idle(Some(80.0), Some(72.0)).unwrap();
+        assert!(!controller.flash_state.get_pump_fault().unwrap());
+
+        assert!(matches!(controller.check_idle(Some(80.0), Some(60.0)), Err(PumpError::StuckOn)));
+        assert!(controller.flash_state.get_pump_fault().unwrap());
+        assert!(matches!(controller.water(Duration::from_secs(10), Some(60.0)), Err(PumpError::Faulted)));
+    }
+
+    #[test]
+    fn stops_the_pump_before_storing_the_usage() {
+        struct FailingPump(bool);
+
+        impl Pump for FailingPump {
+            fn start(&mut self) -> Result<(), EspError> {
+                self.0 = true;
+                Err(EspError::from_infallible::<ESP_ERR_TIMEOUT>())
+            }
+
+            fn stop(&mut self) -> Result<(), EspError> {
+                self.0 = false;
+                Ok(())
+            }
+
+            fn is_running(&self) -> Option<bool> {
+                None
+            }
+        }
+
+        let mut controller = PumpController::new(FailingPump(false), FakeClock::new(NOW), FlashState::new(MemoryKvStore::default()), PumpLimits::default());
+
+        assert!(matches!(controller.water(Duration::from_secs(10), Some(50.0)), Err(PumpError::Esp(_))));
+        assert!(!controller.pump.0);
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::Mutex;
+
+use heapless::String;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::{from_slice, to_vec};
+
+use crate::framing::FrameError;
+use crate::validation::FieldError;
+
+/// A command together with the id the client uses to correlate the responses.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RpcRequest<C> {
+    pub id: u32,
+    #[serde(flatten)]
+    pub command: C
+}
+
+#[derive(Deserialize)]
+struct RequestId {
+    id: Option<u32>
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "_type")]
+pub enum RpcResponse<T> {
+    Accepted { id: u32 },
+    Rejected { id: Option<u32>, reason: RejectionReason },
+    Completed { id: u32, result: T }
+}
+
+impl<T> RpcResponse<T> {
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            RpcResponse::Accepted { id } | RpcResponse::Completed { id, .. } => Some(*id),
+            RpcResponse::Rejected { id, .. } => *id
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "_type")]
+pub enum RejectionReason {
+    InvalidCommand { message: String<128> },
+    InvalidSettings { fields: Vec<FieldError> },
+    MalformedFrame { error: FrameError },
+    /// The response doesn't fit the transport, sent instead of it
+    ResponseTooLarge { length: usize }
+}
+
+/// Decodes a raw JSON request as received from a transport, or returns the rejection to respond with.
+// the rejection is responded with right away, boxing it would only add an allocation
+#[allow(clippy::result_large_err)]
+pub fn decode<C : DeserializeOwned, T>(bytes: &[u8]) -> Result<RpcRequest<C>, RpcResponse<T>> {
+    from_slice::<RpcRequest<C>>(bytes).map_err(|err| {
+        error!("Command not recognized! {:?}", err);
+
+        let id = from_slice::<RequestId>(bytes).ok().and_then(|r| r.id);
+        let message = truncated(&err.to_string());
+
+        RpcResponse::Rejected { id, reason: RejectionReason::InvalidCommand { message } }
+    })
+}
+
+/// Encodes the response for a transport which takes at most `max_length` bytes. A response which doesn't fit is
+/// replaced by a `ResponseTooLarge` rejection, as cutting it off would leave invalid JSON.
+pub fn encode<T : Serialize>(response: &RpcResponse<T>, max_length: usize) -> Vec<u8> {
+    match to_vec(response) {
+        Ok(bytes) if bytes.len() <= max_length => bytes,
+        result => {
+            let length = result.as_ref().map(|bytes| bytes.len()).unwrap_or(0);
+            warn!("Response of {} bytes doesn't fit the transport", length);
+            to_vec(&RpcResponse::<()>::Rejected { id: response.id(), reason: RejectionReason::ResponseTooLarge { length } }).unwrap()
+        }
+    }
+}
+
+type Responder<T> = Box<dyn Fn(&RpcResponse<T>) + Send + Sync>;
+
+/// Subscribers which receive the response to every request, e.g. the transport the request came in on.
+pub struct Responders<T> {
+    responders: Mutex<Vec<Responder<T>>>
+}
+
+impl<T> Responders<T> {
+    pub fn subscribe<F>(&self, responder: F) where F : Fn(&RpcResponse<T>) + Send + Sync + 'static {
+        self.responders.lock().unwrap().push(Box::new(responder));
+    }
+
+    pub fn respond(&self, response: RpcResponse<T>) {
+        for responder in self.responders.lock().unwrap().iter() {
+            responder(&response);
+        }
+    }
+}
+
+impl<T> Default for Responders<T> {
+    fn default() -> Self {
+        Responders { responders: Mutex::new(Vec::new()) }
+    }
+}
+
+/// Converts to a bounded string, cutting off at a character boundary instead of panicking when it does not fit.
+pub fn truncated<const N : usize>(str: &str) -> String<N> {
+    let mut result = String::new();
+
+    for c in str.chars() {
+        if result.push(c).is_err() {
+            break;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, to_value, Value};
+
+    use super::*;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(tag = "_type")]
+    enum Command {
+        Ping,
+        Rename { name: std::string::String }
+    }
+
+    fn rejection(response: RpcResponse<()>) -> (Option<u32>, RejectionReason) {
+        match response {
+            RpcResponse::Rejected { id, reason } => (id, reason),
+            response => panic!("Expected a rejection, got {:?}", response)
+        }
+    }
+
+    #[test]
+    fn decodes_a_flattened_command() {
+        let request = decode::<Command, ()>(br#"{"id":7,"_type":"Rename","name":"Basil"}"#).unwrap();
+
+        assert_eq!(request.id, 7);
+        assert_eq!(request.command, Command::Rename { name: "Basil".to_string() });
+        assert_eq!(decode::<Command, ()>(br#"{"_type":"Ping","id":8}"#).unwrap().command, Command::Ping);
+    }
+
+    #[test]
+    fn rejects_an_unknown_command_with_its_id() {
+        let (id, reason) = rejection(decode::<Command, ()>(br#"{"id":7,"_type":"Launch"}"#).unwrap_err());
+
+        assert_eq!(id, Some(7));
+        assert!(matches!(reason, RejectionReason::InvalidCommand { message } if message.contains("Launch")));
+    }
+
+    #[test]
+    fn rejects_a_request_without_an_id() {
+        let (id, reason) = rejection(decode::<Command, ()>(br#"{"_type":"Ping"}"#).unwrap_err());
+
+        assert_eq!(id, None);
+        assert!(matches!(reason, RejectionReason::InvalidCommand { .. }));
+        assert_eq!(rejection(decode::<Command, ()>(b"not json").unwrap_err()).0, None);
+    }
+
+    #[test]
+    fn encodes_the_responses() {
+        assert_eq!(to_value(RpcResponse::<()>::Accepted { id: 1 }).unwrap(), json!({"_type": "Accepted", "id": 1}));
+        assert_eq!(to_value(RpcResponse::Completed { id: 2, result: 42 }).unwrap(), json!({"_type": "Completed", "id": 2, "result": 42}));
+        assert_eq!(
+            to_value(RpcResponse::<()>::Rejected { id: None, reason: RejectionReason::InvalidCommand { message: truncated("bad") } }).unwrap(),
+            json!({"_type": "Rejected", "id": null, "reason": {"_type": "InvalidCommand", "message": "bad"}})
+        );
+        assert_eq!(
+            to_value(RpcResponse::<()>::Rejected { id: Some(3), reason: RejectionReason::MalformedFrame { error: FrameError::TooLarge { max: 2048 } } }).unwrap(),
+            json!({"_type": "Rejected", "id": 3, "reason": {"_type": "MalformedFrame", "error": {"_type": "TooLarge", "max": 2048}}})
+        );
+    }
+
+    #[test]
+    fn replaces_a_response_which_does_not_fit() {
+        let small = RpcResponse::Completed { id: 4, result: "ok" };
+        assert_eq!(encode(&small, 512), to_vec(&small).unwrap());
+
+        let large = RpcResponse::Completed { id: 5, result: "x".repeat(600) };
+        let encoded: Value = from_slice(&encode(&large, 512)).unwrap();
+
+        assert_eq!(encoded, json!({"_type": "Rejected", "id": 5, "reason": {"_type": "ResponseTooLarge", "length": 640}}));
+    }
+
+    #[test]
+    fn truncates_at_a_character_boundary() {
+        assert_eq!(truncated::<4>("abc").as_str(), "abc");
+        assert_eq!(truncated::<4>("abcdef").as_str(), "abcd");
+        assert_eq!(truncated::<4>("aé€").as_str(), "aé");
+    }
+}
@@ -0,0 +1,352 @@
+use std::mem::size_of;
+use std::ptr::{addr_of, addr_of_mut};
+
+use chrono::DateTime;
+use log::warn;
+
+use crate::mycelium::{StationMeasurement, WateringSchedule};
+use crate::station::millis_to_rfc3339;
+
+/// Measurements kept when a check-in fails, the oldest are dropped first.
+pub const MAX_PENDING_MEASUREMENTS: usize = 32;
+/// Changes whenever the layout of the state changes, so a new firmware doesn't read the state of the old one.
+const VERSION: u32 = 5;
+
+/// A measurement compacted to fixed size, values which are `None` are stored as NaN.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PendingMeasurement {
+    on: u32,
+    millis: u32,
+    sequence: u32,
+    boot_id: u32,
+    values: [f32; 9]
+}
+
+impl PendingMeasurement {
+    const EMPTY: PendingMeasurement = PendingMeasurement { on: 0, millis: 0, sequence: 0, boot_id: 0, values: [0.0; 9] };
+
+    /// Drops the statistics, and the measurement altogether when its time can't be parsed.
+    fn compact(measurement: &StationMeasurement) -> Option<PendingMeasurement> {
+        let on = DateTime::parse_from_rfc3339(&measurement.on).ok()?;
+        let value = |v: Option<f64>| v.map(|v| v as f32).unwrap_or(f32::NAN);
+
+        Some(PendingMeasurement {
+            on: on.timestamp() as u32,
+            millis: on.timestamp_subsec_millis(),
+            sequence: measurement.sequence,
+            boot_id: measurement.boot_id,
+            values: [
+                value(measurement.battery_voltage), value(measurement.battery_percentage), value(measurement.temperature),
+                value(measurement.humidity), value(measurement.lux), value(measurement.soil_pf), value(measurement.tank_pf),
+                value(measurement.soil_moisture), value(measurement.tank_fill)
+            ]
+        })
+    }
+
+    fn expand(&self) -> Option<StationMeasurement> {
+        let value = |i: usize| Some(self.values[i]).filter(|v| !v.is_nan()).map(|v| v as f64);
+
+        Some(StationMeasurement {
+            on: millis_to_rfc3339(self.on as u64 * 1000 + self.millis as u64)?,
+            sequence: self.sequence,
+            boot_id: self.boot_id,
+            battery_voltage: value(0),
+            battery_percentage: value(1),
+            temperature: value(2),
+            humidity: value(3),
+            lux: value(4),
+            soil_pf: value(5),
+            tank_pf: value(6),
+            soil_moisture: value(7),
+            tank_fill: value(8),
+            statistics: None
+        })
+    }
+}
+
+/// State which is kept in RTC slow memory through deep sleep, so most wakes don't have to write it to flash or ask
+/// the network again. It is lost on a power-on or reset, which the checksum detects.
+///
+/// Only plain fixed size fields without padding, so the checksum covers every byte, and of types which are valid for
+/// any bits, as the memory holds garbage after a power-on.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RtcState {
+    version: u32,
+    /// Wakes from deep sleep since the last power-on or reset
+    pub wakes: u32,
+    /// Wakes since the last successful check-in
+    pub wakes_since_check_in: u32,
+    /// Seconds since the epoch of the last time synchronization, 0 when the time was not synchronized yet
+    pub last_sync: u32,
+    /// Seconds the first synchronization moved the clock forward
+    pub sync_offset: u32,
+    /// Random id of the measurements taken since the RTC memory was lost
+    pub boot_id: u32,
+    /// Next sequence number to hand out
+    pub sequence: u32,
+    /// Sequence numbers below this one are reserved in flash, 0 when the reservation has to be read from flash
+    pub sequence_reserved: u32,
+    wifi_bssid: [u8; 6],
+    /// 0 when unknown
+    wifi_channel: u8,
+    /// Whether the backend accepts check-ins in the compact encoding, a `u8` as not every byte is a valid `bool`
+    compact_check_in: u8,
+    pending_len: u32,
+    pending: [PendingMeasurement; MAX_PENDING_MEASUREMENTS],
+    /// Checksum of the JSON of the watering schedule of the last check-in, 0 when unknown
+    schedule_crc: u32,
+    /// Tank fill percentage of the previous wake if the pump didn't run since, NaN when unknown
+    idle_tank_fill: f32
+}
+
+#[repr(C)]
+struct RtcRegion {
+    state: RtcState,
+    crc: u32
+}
+
+#[cfg_attr(target_os = "espidf", link_section = ".rtc.data")]
+static mut RTC_REGION: RtcRegion = RtcRegion { state: RtcState::EMPTY, crc: 0 };
+
+impl RtcState {
+    const EMPTY: RtcState = RtcState {
+        version: VERSION,
+        wakes: 0,
+        wakes_since_check_in: 0,
+        last_sync: 0,
+        sync_offset: 0,
+        boot_id: 0,
+        sequence: 0,
+        sequence_reserved: 0,
+        wifi_bssid: [0; 6],
+        wifi_channel: 0,
+        compact_check_in: 0,
+        pending_len: 0,
+        pending: [PendingMeasurement::EMPTY; MAX_PENDING_MEASUREMENTS],
+        schedule_crc: 0,
+        idle_tank_fill: f32::NAN
+    };
+
+    /// The state kept through the last deep sleep, or an empty state after a power-on or when it was corrupted.
+    pub fn load() -> RtcState {
+        unsafe {
+            if std::ptr::read_volatile(addr_of!(RTC_REGION.crc)) != crc32(state_bytes()) {
+                return RtcState::EMPTY
+            }
+
+            match std::ptr::read_volatile(addr_of!(RTC_REGION.state)) {
+                state if state.version == VERSION => state,
+                _ => RtcState::EMPTY
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        unsafe {
+            std::ptr::write_volatile(addr_of_mut!(RTC_REGION.state), *self);
+            std::ptr::write_volatile(addr_of_mut!(RTC_REGION.crc), crc32(state_bytes()));
+        }
+    }
+
+    /// Forgets the state, e.g. when the station is reset and its measurements don't belong to a station anymore.
+    pub fn clear() {
+        RtcState::EMPTY.save();
+    }
+
+    /// Loads the state, applies the change and saves it again.
+    pub fn update<F, T>(f: F) -> T where F : FnOnce(&mut RtcState) -> T {
+        let mut state = RtcState::load();
+        let result = f(&mut state);
+        state.save();
+        result
+    }
+
+    /// Channel and BSSID of the access point the station connected to last.
+    pub fn wifi(&self) -> Option<(u8, [u8; 6])> {
+        (self.wifi_channel != 0).then_some((self.wifi_channel, self.wifi_bssid))
+    }
+
+    pub fn set_wifi(&mut self, wifi: Option<(u8, [u8; 6])>) {
+        let (channel, bssid) = wifi.unwrap_or((0, [0; 6]));
+        self.wifi_channel = channel;
+        self.wifi_bssid = bssid;
+    }
+
+    /// Measurements of failed check-ins, oldest first.
+    pub fn pending(&self) -> Vec<StationMeasurement> {
+        self.pending[..self.pending_len as usize].iter().filter_map(PendingMeasurement::expand).collect()
+    }
+
+    pub fn push_pending(&mut self, measurements: &[StationMeasurement]) {
+        let mut pending: Vec<PendingMeasurement> = self.pending[..self.pending_len as usize].to_vec();
+        pending.extend(measurements.iter().filter_map(PendingMeasurement::compact));
+
+        if pending.len() > MAX_PENDING_MEASUREMENTS {
+            warn!("Dropping {} pending measurements", pending.len() - MAX_PENDING_MEASUREMENTS);
+            pending.drain(..pending.len() - MAX_PENDING_MEASUREMENTS);
+        }
+
+        self.pending[..pending.len()].copy_from_slice(&pending);
+        self.pending_len = pending.len() as u32;
+    }
+
+    pub fn clear_pending(&mut self) {
+        self.pending_len = 0;
+    }
+
+    pub fn compact_check_in(&self) -> bool {
+        self.compact_check_in != 0
+    }
+
+    pub fn set_compact_check_in(&mut self, compact: bool) {
+        self.compact_check_in = compact as u8;
+    }
+
+    /// Whether the schedule differs from the one of the last check-in. Only a checksum of the schedule is kept, as a
+    /// schedule can be too long to keep itself.
+    pub fn schedule_changed(&self, schedule: &WateringSchedule) -> bool {
+        self.schedule_crc == 0 || self.schedule_crc != schedule_crc(schedule)
+    }
+
+    pub fn set_schedule(&mut self, schedule: Option<&WateringSchedule>) {
+        self.schedule_crc = schedule.map(schedule_crc).unwrap_or(0);
+    }
+
+    /// Tank fill of the previous wake, as long as the pump didn't run since, to notice the tank draining while idle.
+    pub fn idle_tank_fill(&self) -> Option<f64> {
+        (!self.idle_tank_fill.is_nan()).then_some(self.idle_tank_fill as f64)
+    }
+
+    pub fn set_idle_tank_fill(&mut self, tank_fill: Option<f64>) {
+        self.idle_tank_fill = tank_fill.map(|fill| fill as f32).unwrap_or(f32::NAN);
+    }
+}
+
+fn schedule_crc(schedule: &WateringSchedule) -> u32 {
+    serde_json::to_vec(schedule).map(|json| crc32(&json)).unwrap_or(0)
+}
+
+unsafe fn state_bytes() -> &'static [u8] {
+    std::slice::from_raw_parts(addr_of!(RTC_REGION.state) as *const u8, size_of::<RtcState>())
+}
+
+/// CRC-32 (IEEE 802.3), bitwise as the state is only checked once per wake.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for byte in bytes {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakes::rtc_memory;
+
+    fn measurement(sequence: u32) -> StationMeasurement {
+        StationMeasurement {
+            on: "2024-05-01T12:30:09.453Z".to_string(),
+            sequence,
+            boot_id: 7,
+            battery_voltage: Some(3.9),
+            battery_percentage: None,
+            temperature: None,
+            humidity: None,
+            lux: None,
+            soil_pf: Some(812.0),
+            tank_pf: None,
+            soil_moisture: Some(38.25),
+            tank_fill: None,
+            statistics: None
+        }
+    }
+
+    fn interval(schedule: &str) -> WateringSchedule {
+        WateringSchedule::Interval { schedule: heapless::String::from(schedule), period: heapless::String::from("10 seconds") }
+    }
+
+    #[test]
+    fn keeps_the_state_through_deep_sleep() {
+        let _rtc = rtc_memory();
+
+        RtcState::update(|state| {
+            state.wakes = 3;
+            state.set_wifi(Some((6, [1, 2, 3, 4, 5, 6])));
+            state.set_compact_check_in(true);
+            state.set_idle_tank_fill(Some(42.5));
+        });
+
+        let state = RtcState::load();
+        assert_eq!(state.wakes, 3);
+        assert_eq!(state.wifi(), Some((6, [1, 2, 3, 4, 5, 6])));
+        assert!(state.compact_check_in());
+        assert_eq!(state.idle_tank_fill(), Some(42.5));
+    }
+
+    #[test]
+    fn starts_empty_when_the_memory_holds_garbage() {
+        let _rtc = rtc_memory();
+
+        unsafe {
+            std::ptr::write_bytes(addr_of_mut!(RTC_REGION) as *mut u8, 0xa5, size_of::<RtcRegion>());
+        }
+
+        let state = RtcState::load();
+        assert_eq!(state.wakes, 0);
+        assert!(!state.compact_check_in());
+        assert_eq!(state.idle_tank_fill(), None);
+        assert!(state.pending().is_empty());
+    }
+
+    #[test]
+    fn starts_empty_after_a_layout_change() {
+        let _rtc = rtc_memory();
+
+        RtcState { version: VERSION - 1, wakes: 3, ..RtcState::EMPTY }.save();
+
+        assert_eq!(RtcState::load().wakes, 0);
+    }
+
+    #[test]
+    fn drops_the_oldest_pending_measurements() {
+        let _rtc = rtc_memory();
+        let measurements: Vec<_> = (0..MAX_PENDING_MEASUREMENTS as u32 + 5).map(measurement).collect();
+
+        RtcState::update(|state| state.push_pending(&measurements));
+
+        let pending = RtcState::load().pending();
+        assert_eq!(pending.len(), MAX_PENDING_MEASUREMENTS);
+        assert_eq!(pending[0].sequence, 5);
+        assert_eq!(pending[0].on, "2024-05-01T12:30:09.453Z");
+        assert_eq!(pending[0].soil_moisture, Some(38.25));
+        assert_eq!(pending[0].battery_percentage, None);
+    }
+
+    #[test]
+    fn tells_whether_the_schedule_changed() {
+        let _rtc = rtc_memory();
+        let schedule = interval("0 0,15,30,45 6-9,17-21 1-7,15-21 JAN-MAR,SEP-DEC MON-FRI");
+        let mut state = RtcState::load();
+
+        assert!(state.schedule_changed(&schedule));
+
+        state.set_schedule(Some(&schedule));
+        state.save();
+
+        assert!(!RtcState::load().schedule_changed(&schedule));
+        assert!(RtcState::load().schedule_changed(&interval("0 0 8 * * ?")));
+
+        RtcState::update(|state| state.set_schedule(None));
+
+        assert!(RtcState::load().schedule_changed(&schedule));
+    }
+}
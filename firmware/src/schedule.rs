@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+
+/// Bounds the search for the next occurrence, so an expression which never matches (e.g. the 31st of February) terminates.
+const MAX_ITERATIONS: u32 = 100_000;
+
+/// Cron expression in the format used by cron4s at the backend: seconds, minutes, hours, day of month, month and
+/// day of week (0 is Monday). Each field is `*`, `?` (day fields only), a value or a range, optionally with a `/step`,
+/// or a comma separated list of those. Fields are kept as bitmasks of the matching values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronSchedule {
+    seconds: u64,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<CronSchedule, &'static str> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        if fields.len() != 6 {
+            return Err("expected 6 fields: seconds, minutes, hours, day of month, month and day of week")
+        }
+
+        Ok(CronSchedule {
+            seconds: parse_field(fields[0], 0, 59, false)?,
+            minutes: parse_field(fields[1], 0, 59, false)?,
+            hours: parse_field(fields[2], 0, 23, false)?,
+            days_of_month: parse_field(fields[3], 1, 31, true)?,
+            months: parse_field(fields[4], 1, 12, false)?,
+            days_of_week: parse_field(fields[5], 0, 6, true)?
+        })
+    }
+
+    /// The first time strictly after `after` matching the expression.
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut time = after.with_nanosecond(0)? + chrono::Duration::seconds(1);
+
+        for _ in 0..MAX_ITERATIONS {
+            if !matches(self.months, time.month()) {
+                let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !matches(self.days_of_month, time.day()) || !matches(self.days_of_week, time.weekday().num_days_from_monday()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !matches(self.hours, time.hour()) {
+                time = time.with_minute(0)?.with_second(0)? + chrono::Duration::hours(1);
+            } else if !matches(self.minutes, time.minute()) {
+                time = time.with_second(0)? + chrono::Duration::minutes(1);
+            } else if !matches(self.seconds, time.second()) {
+                time += chrono::Duration::seconds(1);
+            } else {
+                return Some(time)
+            }
+        }
+
+        None
+    }
+}
+
+fn matches(mask: u64, value: u32) -> bool {
+    mask & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: u32, max: u32, allows_any: bool) -> Result<u64, &'static str> {
+    let mut mask = 0u64;
+
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err("step is not a positive number")
+            },
+            None => (item, 1)
+        };
+
+        let value = |v: &str| match v.parse::<u32>() {
+            Ok(v) if v >= min && v <= max => Ok(v),
+            _ => Err("value out of range")
+        };
+
+        let (from, to) = match range {
+            "*" => (min, max),
+            "?" if allows_any && step == 1 => (min, max),
+            "?" => return Err("? is only allowed in the day fields"),
+            _ => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                // a single value with a step, like `5/15`, repeats until the end of the range
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?)
+            }
+        };
+
+        if from > to {
+            return Err("range is reversed")
+        }
+
+        for v in (from..=to).step_by(step as usize) {
+            mask |= 1 << v;
+        }
+    }
+
+    Ok(mask)
+}
+
+/// Duration as parsed by Scala's `Duration(String)` at the backend, e.g. `5 seconds` or `1.5min`. Must be positive.
+pub fn parse_duration(duration: &str) -> Result<Duration, &'static str> {
+    const UNITS: [(&[&str], f64); 7] = [
+        (&["d", "day", "days"], 86_400.0),
+        (&["h", "hr", "hrs", "hour", "hours"], 3_600.0),
+        (&["m", "min", "mins", "minute", "minutes"], 60.0),
+        (&["s", "sec", "secs", "second", "seconds"], 1.0),
+        (&["ms", "milli", "millis", "millisecond", "milliseconds"], 1e-3),
+        (&["µs", "micro", "micros", "microsecond", "microseconds"], 1e-6),
+        (&["ns", "nano", "nanos", "nanosecond", "nanoseconds"], 1e-9)
+    ];
+
+    let trimmed = duration.trim();
+    let split = trimmed.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(trimmed.len());
+    let (length, unit) = trimmed.split_at(split);
+    let unit = unit.trim_start();
+
+    let length = match length.parse::<f64>() {
+        Ok(length) if length > 0.0 => length,
+        Ok(_) => return Err("duration must be positive"),
+        Err(_) => return Err("duration must start with a number")
+    };
+
+    let (_, seconds) = UNITS.iter()
+        .find(|(labels, _)| labels.contains(&unit))
+        .ok_or("unknown time unit")?;
+
+    // Scala's durations count nanoseconds in a Long
+    if length * seconds * 1e9 > i64::MAX as f64 {
+        return Err("duration out of range")
+    }
+
+    Ok(Duration::from_secs_f64(length * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expression, time after and the next time, also checked against cron4s by `CronScheduleSuite` of the backend,
+    /// keep the two in sync.
+    const NEXT: [(&str, &str, Option<&str>); 10] = [
+        ("0 */15 * * * ?", "2024-05-01T12:30:09", Some("2024-05-01T12:45:00")),
+        ("9 30 12 * * ?", "2024-05-01T12:30:09", Some("2024-05-02T12:30:09")),
+        ("0 0 6,18 * * ?", "2024-05-01T12:30:09", Some("2024-05-01T18:00:00")),
+        ("0 0 9-17/4 * * ?", "2024-05-01T12:30:09", Some("2024-05-01T13:00:00")),
+        ("30 5-59/20 * * * ?", "2024-05-01T12:30:09", Some("2024-05-01T12:45:30")),
+        ("0 0 0 1 * ?", "2024-05-01T12:30:09", Some("2024-06-01T00:00:00")),
+        ("0 0 0 * 1 ?", "2024-05-01T12:30:09", Some("2025-01-01T00:00:00")),
+        ("0 0 12 29 2 ?", "2024-05-01T12:30:09", Some("2028-02-29T12:00:00")),
+        ("0 0 8 ? * 5", "2024-05-01T12:30:09", Some("2024-05-04T08:00:00")),
+        // both day fields have to match, Friday the 13th
+        ("0 0 12 13 * 4", "2024-05-01T12:30:09", Some("2024-09-13T12:00:00"))
+    ];
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn finds_the_next_time() {
+        for (expression, after, next) in NEXT {
+            assert_eq!(CronSchedule::parse(expression).unwrap().next_after(time(after)), next.map(time), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn repeats_a_value_with_a_step_until_the_end_of_the_range() {
+        assert_eq!(CronSchedule::parse("30 5/20 * * * ?"), CronSchedule::parse("30 5,25,45 * * * ?"));
+    }
+
+    #[test]
+    fn gives_up_on_an_expression_which_never_matches() {
+        assert_eq!(CronSchedule::parse("0 0 0 31 2 ?").unwrap().next_after(time("2024-05-01T12:30:09")), None);
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(CronSchedule::parse("0 0 12 * *").is_err());
+        assert!(CronSchedule::parse("0 60 12 * * ?").is_err());
+        assert!(CronSchedule::parse("0 0 12 0 * ?").is_err());
+        assert!(CronSchedule::parse("0 0 12 * * 7").is_err());
+        assert!(CronSchedule::parse("0 0 18-6 * * ?").is_err());
+        assert!(CronSchedule::parse("0 */0 * * * ?").is_err());
+        assert!(CronSchedule::parse("? 0 12 * * ?").is_err());
+        assert!(CronSchedule::parse("0 0 12 ? * ?/2").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("5 seconds"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration(" 1.5min "), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2 hrs"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("250 millis"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("1 d"), Ok(Duration::from_secs(86_400)));
+        assert_eq!(parse_duration("106751 days"), Ok(Duration::from_secs(106_751 * 86_400)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert_eq!(parse_duration("0 seconds"), Err("duration must be positive"));
+        assert_eq!(parse_duration("-5 seconds"), Err("duration must start with a number"));
+        assert_eq!(parse_duration("seconds"), Err("duration must start with a number"));
+        assert_eq!(parse_duration("5 fortnights"), Err("unknown time unit"));
+        assert_eq!(parse_duration("106752 days"), Err("duration out of range"));
+        assert_eq!(parse_duration("99999999999999999999999 days"), Err("duration out of range"));
+    }
+}
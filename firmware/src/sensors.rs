@@ -0,0 +1,150 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Read, Write, WriteRead};
+use serde::Serialize;
+
+use crate::battery::BatteryLevel;
+use crate::capacitance::{CapacitanceSensor, Probes};
+use crate::environment::{EnvironmentSensors, Part};
+use crate::mycelium::Capabilities;
+use crate::statistics::{aggregate, Statistics};
+use crate::sys::EspError;
+
+#[derive(Clone, Debug)]
+pub struct SamplingConfig {
+    /// Samples taken of every sensor per measurement
+    pub samples: usize,
+    /// Time between two samples
+    pub interval: Duration,
+    /// Samples further from the median than this many standard deviations are dropped
+    pub outlier_threshold: f64,
+    /// Sends the statistics of the samples along with the measurement
+    pub report_statistics: bool
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig { samples: 5, interval: Duration::from_millis(200), outlier_threshold: 3.5, report_statistics: false }
+    }
+}
+
+/// Statistics of every sensor over the samples of a measurement, `None` for a sensor which is not fitted or failed
+/// to measure in all samples.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SensorStatistics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<Statistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub humidity: Option<Statistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lux: Option<Statistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soil_pf: Option<Statistics>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tank_pf: Option<Statistics>
+}
+
+/// All sensors of the station, as read for a measurement.
+pub trait Sensors : Send + Sync + Clone {
+    /// The battery as measured at boot, `None` without a battery monitor.
+    fn battery(&self) -> Option<BatteryLevel>;
+    /// Samples the environment sensors and the capacitive probes, compensating the probes for the temperature when
+    /// it is known.
+    fn read(&self) -> Result<SensorStatistics, EspError>;
+    fn sampling(&self) -> &SamplingConfig;
+    /// The measurements the fitted sensors provide
+    fn capabilities(&self) -> Capabilities;
+}
+
+pub struct StationSensors<S : CapacitanceSensor, I, D> {
+    battery: Option<BatteryLevel>,
+    probes: Probes<S>,
+    environment: EnvironmentSensors<I, D>,
+    sampling: SamplingConfig
+}
+
+impl<S : CapacitanceSensor, I, D> StationSensors<S, I, D> {
+    pub fn new(battery: Option<BatteryLevel>, probes: Probes<S>, environment: EnvironmentSensors<I, D>, sampling: SamplingConfig) -> StationSensors<S, I, D> {
+        StationSensors { battery, probes, environment, sampling }
+    }
+}
+
+impl<S, I, D, E> Sensors for StationSensors<S, I, D>
+    where S : CapacitanceSensor, I : Read<Error = E> + Write<Error = E> + WriteRead<Error = E> + Send, D : DelayMs<u16> + Send, E : Debug {
+
+    fn battery(&self) -> Option<BatteryLevel> {
+        self.battery
+    }
+
+    /// Takes the samples spread over a short window, so a transient like the shadow of a passing cloud is dropped
+    /// as an outlier rather than ending up in the history.
+    fn read(&self) -> Result<SensorStatistics, EspError> {
+        let samples = self.sampling.samples.max(1);
+        let (mut temperature, mut humidity, mut lux, mut soil_pf, mut tank_pf) = (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+
+        for sample in 0..samples {
+            if sample > 0 {
+                std::thread::sleep(self.sampling.interval);
+            }
+
+            let environment = self.environment.read();
+            let probes = self.probes.read(environment.temperature)?;
+
+            temperature.extend(environment.temperature);
+            humidity.extend(environment.humidity);
+            lux.extend(environment.lux);
+            soil_pf.extend(probes.soil_pf);
+            tank_pf.extend(probes.tank_pf);
+        }
+
+        let threshold = self.sampling.outlier_threshold;
+
+        Ok(SensorStatistics {
+            temperature: aggregate(&temperature, threshold),
+            humidity: aggregate(&humidity, threshold),
+            lux: aggregate(&lux, threshold),
+            soil_pf: aggregate(&soil_pf, threshold),
+            tank_pf: aggregate(&tank_pf, threshold)
+        })
+    }
+
+    fn sampling(&self) -> &SamplingConfig {
+        &self.sampling
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let parts = self.environment.parts();
+        let mut measurements = Vec::new();
+
+        if self.battery.is_some() {
+            measurements.extend(["batteryVoltage", "batteryPercentage"]);
+        }
+
+        if parts.iter().any(|p| matches!(p, Part::Sht3x | Part::Sht4x)) {
+            measurements.extend(["temperature", "humidity"]);
+        }
+
+        if parts.contains(&Part::Bh1750) {
+            measurements.push("lux");
+        }
+
+        if self.probes.has_soil() {
+            measurements.extend(["soilPf", "soilMoisture"]);
+        }
+
+        if self.probes.has_tank() {
+            measurements.extend(["tankPf", "tankFill"]);
+        }
+
+        Capabilities { measurements, parts }
+    }
+}
+
+impl<S : CapacitanceSensor, I, D> Clone for StationSensors<S, I, D> {
+    fn clone(&self) -> Self {
+        StationSensors { battery: self.battery, probes: self.probes.clone(), environment: self.environment.clone(), sampling: self.sampling.clone() }
+    }
+}
@@ -1,14 +1,19 @@
 use uuid::Uuid;
-use crate::kv::{KvStore, KvStoreError, NvsKvStore};
+use crate::calibration::{Calibration, Sensor};
+use crate::kv::{KvStore, KvStoreError};
+use crate::mycelium::WateringSchedule;
+use crate::pump::PumpUsage;
+use crate::watering::PendingWatering;
 use crate::tokens::TokenWallet;
 use crate::wifi::MyceliumWifiSettings;
 
-pub struct FlashState {
-    kv: NvsKvStore
+#[derive(Clone)]
+pub struct FlashState<K : KvStore> {
+    kv: K
 }
 
-impl FlashState {
-    pub fn new(kv: NvsKvStore) -> FlashState { FlashState { kv } }
+impl<K : KvStore> FlashState<K> {
+    pub fn new(kv: K) -> FlashState<K> { FlashState { kv } }
 
     pub fn set_wifi_settings(&self, s: MyceliumWifiSettings) -> Result<(), KvStoreError> {
         self.kv.set("wifi", s)
@@ -37,6 +42,65 @@ impl FlashState {
         self.kv.contains("station_id")
     }
 
+    pub fn set_watering_schedule(&self, schedule: WateringSchedule) -> Result<(), KvStoreError> {
+        self.kv.set("schedule", schedule)
+    }
+    pub fn get_opt_watering_schedule(&self) -> Result<Option<WateringSchedule>, KvStoreError> {
+        self.kv.get_opt("schedule")
+    }
+
+    pub fn set_last_watered(&self, on: u64) -> Result<(), KvStoreError> {
+        self.kv.set("last_watered", on)
+    }
+    pub fn get_opt_last_watered(&self) -> Result<Option<u64>, KvStoreError> {
+        self.kv.get_opt("last_watered")
+    }
+
+    pub fn set_pending_waterings(&self, pending: &[PendingWatering]) -> Result<(), KvStoreError> {
+        self.kv.set("watered_queue", pending)
+    }
+    pub fn get_pending_waterings(&self) -> Result<Vec<PendingWatering>, KvStoreError> {
+        Ok(self.kv.get_opt("watered_queue")?.unwrap_or_default())
+    }
+
+    pub fn set_pump_usage(&self, usage: PumpUsage) -> Result<(), KvStoreError> {
+        self.kv.set("pump_usage", usage)
+    }
+    pub fn get_opt_pump_usage(&self) -> Result<Option<PumpUsage>, KvStoreError> {
+        self.kv.get_opt("pump_usage")
+    }
+
+    /// Kept across `erase_settings`, as it concerns the hardware rather than the station's registration.
+    pub fn set_pump_fault(&self, fault: bool) -> Result<(), KvStoreError> {
+        self.kv.set("pump_fault", fault)
+    }
+    pub fn get_pump_fault(&self) -> Result<bool, KvStoreError> {
+        Ok(self.kv.get_opt("pump_fault")?.unwrap_or(false))
+    }
+
+    /// Kept across `erase_settings`, as it concerns the hardware rather than the station's registration.
+    pub fn set_calibration(&self, sensor: Sensor, calibration: Calibration) -> Result<(), KvStoreError> {
+        self.kv.set(calibration_key(sensor), calibration)
+    }
+    pub fn get_calibration(&self, sensor: Sensor) -> Result<Calibration, KvStoreError> {
+        Ok(self.kv.get_opt(calibration_key(sensor))?.unwrap_or(Calibration::default_for(sensor)))
+    }
+
+    pub fn set_low_water_reported(&self, reported: bool) -> Result<(), KvStoreError> {
+        self.kv.set("low_water", reported)
+    }
+    pub fn get_low_water_reported(&self) -> Result<bool, KvStoreError> {
+        Ok(self.kv.get_opt("low_water")?.unwrap_or(false))
+    }
+
+    /// Kept across `erase_settings`, so the sequence of the measurements never goes back.
+    pub fn set_sequence_reserved(&self, reserved: u32) -> Result<(), KvStoreError> {
+        self.kv.set("sequence", reserved)
+    }
+    pub fn get_sequence_reserved(&self) -> Result<u32, KvStoreError> {
+        Ok(self.kv.get_opt("sequence")?.unwrap_or(0u32))
+    }
+
     pub fn reset_errors(&self) -> Result<(), KvStoreError> {
         self.kv.set("num_errors", 0u32)
     }
@@ -54,7 +118,17 @@ impl FlashState {
         self.kv.remove("station_id")?;
         self.kv.remove("wifi")?;
         self.kv.remove("token_wallet")?;
+        self.kv.remove("schedule")?;
+        self.kv.remove("last_watered")?;
+        self.kv.remove("watered_queue")?;
+        self.kv.remove("low_water")?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+fn calibration_key(sensor: Sensor) -> &'static str {
+    match sensor {
+        Sensor::Soil => "soil_cal",
+        Sensor::Tank => "tank_cal"
+    }
+}
@@ -0,0 +1,320 @@
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use log::{error, info, warn};
+
+use crate::auth0::{Auth0, TokenResult};
+use crate::calibration::Sensor;
+use crate::clock::{Clock, ClockError};
+use crate::compact;
+use crate::kv::KvStore;
+use crate::device::Device;
+use crate::mycelium::{Capabilities, CheckIn, CheckInResult, MyceliumBackend, MyceliumError, StationMeasurement};
+use crate::onboarding::AppError;
+use crate::rtc::RtcState;
+use crate::sensors::Sensors;
+use crate::settings::FlashState;
+use crate::statistics::Statistics;
+use crate::tokens::TokenWallet;
+use crate::ulp::UlpReading;
+use crate::wifi::{MyceliumWifi, MyceliumWifiSettings};
+
+pub fn extract_wallet<K : KvStore, A : Auth0, C : Clock>(auth: &A, flash_state: &FlashState<K>, clock: &C) -> Result<TokenWallet, AppError>  {
+    let wallet = flash_state.get_token_wallet()?;
+    let now = clock.synchronized()?;
+
+    if wallet.needs_refresh(now) {
+        let resp = auth.refresh_token(&wallet.refresh_token)?;
+
+        match resp {
+            TokenResult::Error { error } => error!("Token error: {:?}", error),
+            TokenResult::AccessToken { access_token, expires_in } => {
+                let new_wallet = wallet.update(access_token, expires_in, now);
+                flash_state.set_token_wallet(new_wallet.clone())?;
+                return Ok(new_wallet.clone())
+            }
+            _ => info!("Ignoring")
+        }
+    }
+
+    Ok(wallet)
+}
+
+/// Sequence numbers are reserved in flash in blocks, so only one in this many measurements writes to flash. The numbers
+/// left of a block are skipped after a reset.
+const SEQUENCE_BLOCK: u32 = 256;
+
+/// Hands out the next sequence number of a measurement, which keeps increasing across resets.
+pub fn next_sequence<K : KvStore>(flash_state: &FlashState<K>) -> Result<u32, AppError> {
+    let state = RtcState::load();
+    let (sequence, reserved) = match state.sequence_reserved {
+        0 => {
+            let reserved = flash_state.get_sequence_reserved()?;
+            (reserved, reserved)
+        }
+        reserved => (state.sequence, reserved)
+    };
+
+    let reserved = if sequence >= reserved {
+        flash_state.set_sequence_reserved(sequence + SEQUENCE_BLOCK)?;
+        sequence + SEQUENCE_BLOCK
+    } else {
+        reserved
+    };
+
+    RtcState::update(|state| {
+        state.sequence = sequence + 1;
+        state.sequence_reserved = reserved;
+    });
+
+    Ok(sequence)
+}
+
+/// Samples the sensors and sends the mean of every sensor, with the capacitive probes calibrated to percentages. Dated
+/// by the RTC, which is rebased in `check_in` when it wasn't synchronized yet.
+pub fn sample<K : KvStore, C : Clock, S : Sensors>(flash_state: &FlashState<K>, clock: &C, sensors: &S) -> Result<StationMeasurement, AppError> {
+    let measurement = read_measurement(flash_state, clock, sensors)?;
+
+    Ok(StationMeasurement { sequence: next_sequence(flash_state)?, ..measurement })
+}
+
+/// Like `sample`, but without a sequence number, for a measurement which is shown instead of sent, e.g. during
+/// maintenance. Numbering it would spend a sequence number, and eventually a flash write, on every read.
+pub fn read_measurement<K : KvStore, C : Clock, S : Sensors>(flash_state: &FlashState<K>, clock: &C, sensors: &S) -> Result<StationMeasurement, AppError> {
+    let rfc3339 = millis_to_rfc3339(clock.now() * 1000).ok_or(AppError::Clock(ClockError::Unsynchronized))?;
+    let battery = sensors.battery();
+    let statistics = sensors.read()?;
+    let mean = |s: &Option<Statistics>| s.as_ref().map(|s| s.mean);
+    let (soil_pf, tank_pf) = (mean(&statistics.soil_pf), mean(&statistics.tank_pf));
+    let soil = flash_state.get_calibration(Sensor::Soil)?;
+    let tank = flash_state.get_calibration(Sensor::Tank)?;
+
+    Ok(StationMeasurement {
+        on: rfc3339,
+        sequence: 0,
+        boot_id: RtcState::load().boot_id,
+        battery_voltage: battery.map(|b| b.voltage),
+        battery_percentage: battery.map(|b| b.percentage),
+        temperature: mean(&statistics.temperature),
+        humidity: mean(&statistics.humidity),
+        lux: mean(&statistics.lux),
+        soil_pf,
+        tank_pf,
+        soil_moisture: soil_pf.map(|pf| soil.percentage(pf)),
+        tank_fill: tank_pf.map(|pf| tank.percentage(pf)),
+        statistics: sensors.sampling().report_statistics.then_some(statistics)
+    })
+}
+
+/// Measurements of the samples the ULP took while sleeping, which only have the soil probe and the battery.
+pub fn sampled_while_sleeping<K : KvStore>(flash_state: &FlashState<K>, readings: &[UlpReading]) -> Result<Vec<StationMeasurement>, AppError> {
+    let soil = flash_state.get_calibration(Sensor::Soil)?;
+    let boot_id = RtcState::load().boot_id;
+    let mut measurements = Vec::new();
+
+    for reading in readings {
+        let on = match millis_to_rfc3339(reading.on * 1000) {
+            Some(on) => on,
+            None => continue
+        };
+
+        measurements.push(StationMeasurement {
+            on,
+            sequence: next_sequence(flash_state)?,
+            boot_id,
+            battery_voltage: Some(reading.battery.voltage),
+            battery_percentage: Some(reading.battery.percentage),
+            temperature: None,
+            humidity: None,
+            lux: None,
+            soil_pf: Some(reading.soil_pf),
+            tank_pf: None,
+            soil_moisture: Some(soil.percentage(reading.soil_pf)),
+            tank_fill: None,
+            statistics: None
+        });
+    }
+
+    Ok(measurements)
+}
+
+pub fn measure<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock, S : Sensors>(flash_state: &FlashState<K>, wifi: &W, auth: &A, backend: &B, clock: &C, sensors: &S) -> Result<CheckInResult, AppError> {
+    check_in(flash_state, wifi, auth, backend, clock, vec![sample(flash_state, clock, sensors)?], sensors.capabilities())
+}
+
+/// Connects to the access point of the last connection, kept in RTC memory, which skips scanning for it. Scans again
+/// when that fails, e.g. because the access point moved to another channel.
+pub fn connect_wifi<K : KvStore, W : MyceliumWifi>(flash_state: &FlashState<K>, wifi: &W) -> Result<(), AppError> {
+    let settings = flash_state.get_wifi_settings()?;
+    let cached = match RtcState::load().wifi() {
+        Some((channel, bssid)) => MyceliumWifiSettings { channel: Some(channel), bssid: Some(bssid), ..settings.clone() },
+        None => settings.clone()
+    };
+
+    let connected = if cached.channel.is_some() || cached.bssid.is_some() {
+        wifi.connect(cached).or_else(|err| {
+            warn!("Could not connect to the known access point, scanning: {:?}", err);
+            wifi.connect(MyceliumWifiSettings::basic(settings.ssid, settings.password))
+        })?
+    } else {
+        wifi.connect(cached)?
+    };
+
+    RtcState::update(|state| state.set_wifi(connected.channel.zip(connected.bssid)));
+
+    Ok(())
+}
+
+/// Sends the measurements, oldest first, and caches the returned watering schedule. The schedule is only written to
+/// flash when it differs from the one of the last check-in.
+pub fn check_in<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock>(flash_state: &FlashState<K>, wifi: &W, auth: &A, backend: &B, clock: &C, measurements: Vec<StationMeasurement>, capabilities: Capabilities) -> Result<CheckInResult, AppError> {
+    connect_wifi(flash_state, wifi)?;
+    let wallet = extract_wallet(auth, flash_state, clock)?;
+    let station_id = flash_state.get_station_id()?;
+
+    // only now the clock is synchronized, measurements taken after a power-on are still dated 1970
+    let measurements = measurements.into_iter().map(|measurement| rebased(clock, measurement)).collect::<Vec<_>>();
+    let key = idempotency_key(&measurements);
+    let request = CheckIn { measurements, capabilities };
+    let prefer_compact = RtcState::load().compact_check_in();
+
+    let result = match backend.check_in(&wallet.access_token, &station_id, &request, &key, prefer_compact) {
+        Err(MyceliumError::UnexpectedResponse { status: 400 | 415 }) if prefer_compact => {
+            warn!("Backend doesn't accept compact check-ins anymore, sending JSON");
+            backend.check_in(&wallet.access_token, &station_id, &request, &key, false)
+        }
+        result => result
+    }?;
+
+    for rejection in &result.rejected {
+        warn!("Measurement {:?} of {} rejected: {}", rejection.sequence, rejection.on, rejection.reason);
+    }
+
+    if let Some(schedule) = &result.schedule {
+        if RtcState::load().schedule_changed(schedule) {
+            flash_state.set_watering_schedule(schedule.clone())?;
+        }
+    }
+
+    RtcState::update(|state| {
+        state.set_schedule(result.schedule.as_ref());
+        state.wakes_since_check_in = 0;
+        state.set_compact_check_in(result.encodings.iter().any(|encoding| encoding == compact::CONTENT_TYPE));
+    });
+
+    Ok(result)
+}
+
+fn rebased<C : Clock>(clock: &C, measurement: StationMeasurement) -> StationMeasurement {
+    let on = DateTime::parse_from_rfc3339(&measurement.on).ok()
+        .and_then(|on| millis_to_rfc3339(clock.rebase(on.timestamp_millis() as u64)));
+
+    match on {
+        Some(on) => StationMeasurement { on, ..measurement },
+        None => measurement
+    }
+}
+
+/// Identifies the batch by its first and last measurement, so a check-in which is sent again after its response got
+/// lost gets the result of the first rather than being handled twice.
+fn idempotency_key(measurements: &[StationMeasurement]) -> String {
+    match (measurements.first(), measurements.last()) {
+        (Some(first), Some(last)) => format!("{:08x}-{}-{:08x}-{}-{}", first.boot_id, first.sequence, last.boot_id, last.sequence, measurements.len()),
+        _ => String::from("empty")
+    }
+}
+
+/// Deregisters the station and revokes its refresh token, then wipes the settings and restarts into onboarding.
+///
+/// Deregistering is best effort: a station which can't reach the backend anymore still needs to be resettable.
+pub fn factory_reset<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock, D : Device>(flash_state: &FlashState<K>, wifi: &W, auth: &A, backend: &B, clock: &C, device: &D) -> Result<(), AppError> {
+    warn!("Factory reset");
+
+    if let Err(err) = deregister(flash_state, wifi, auth, backend, clock) {
+        error!("Could not deregister station: {:?}", err);
+    }
+
+    flash_state.erase_settings()?;
+    RtcState::clear();
+    device.restart();
+
+    Ok(())
+}
+
+fn deregister<K : KvStore, W : MyceliumWifi, A : Auth0, B : MyceliumBackend, C : Clock>(flash_state: &FlashState<K>, wifi: &W, auth: &A, backend: &B, clock: &C) -> Result<(), AppError> {
+    connect_wifi(flash_state, wifi)?;
+    let wallet = extract_wallet(auth, flash_state, clock)?;
+    let station_id = flash_state.get_station_id()?;
+
+    backend.delete_station(&wallet.access_token, &station_id)?;
+    info!("Deleted station {}", station_id);
+
+    auth.revoke_token(&wallet.refresh_token)?;
+    info!("Revoked refresh token");
+
+    Ok(())
+}
+
+// returns a rfc3389 - 2018-01-26T18:30:09Z
+pub fn timestamp_to_rfc3389(timestamp: u64) -> Option<String> {
+    NaiveDateTime::from_timestamp_opt(timestamp as i64, 0)
+        .map(|x| Utc.from_utc_datetime(&x).to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+// returns a rfc3339 with milliseconds - 2018-01-26T18:30:09.453Z
+pub fn millis_to_rfc3339(millis: u64) -> Option<String> {
+    NaiveDateTime::from_timestamp_millis(millis as i64)
+        .map(|x| Utc.from_utc_datetime(&x).to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakes::{MemoryKvStore, rtc_memory};
+
+    fn take(flash_state: &FlashState<MemoryKvStore>, count: usize) -> Vec<u32> {
+        (0..count).map(|_| next_sequence(flash_state).unwrap()).collect()
+    }
+
+    #[test]
+    fn reserves_sequence_numbers_in_blocks() {
+        let _rtc = rtc_memory();
+        let flash_state = FlashState::new(MemoryKvStore::default());
+
+        assert_eq!(take(&flash_state, 600), (0..600).collect::<Vec<u32>>());
+        assert_eq!(flash_state.get_sequence_reserved().unwrap(), 3 * SEQUENCE_BLOCK);
+    }
+
+    #[test]
+    fn skips_the_rest_of_the_block_after_a_restart() {
+        let _rtc = rtc_memory();
+        let flash_state = FlashState::new(MemoryKvStore::default());
+
+        let before = take(&flash_state, 100);
+        RtcState::clear();
+        let after = take(&flash_state, 10);
+
+        assert_eq!(*before.last().unwrap(), 99);
+        assert_eq!(after[0], SEQUENCE_BLOCK);
+        assert!(after[0] - before.last().unwrap() <= SEQUENCE_BLOCK);
+        assert_eq!(after, (SEQUENCE_BLOCK..SEQUENCE_BLOCK + 10).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn never_reuses_a_number_across_restarts() {
+        let _rtc = rtc_memory();
+        let flash_state = FlashState::new(MemoryKvStore::default());
+        let mut all: Vec<u32> = Vec::new();
+
+        for count in [1, 255, 256, 257, 3, 0, 512] {
+            let taken = take(&flash_state, count);
+
+            if let (Some(last), Some(first)) = (all.last(), taken.first()) {
+                assert!(first > last && first - last <= SEQUENCE_BLOCK, "{} after {}", first, last);
+            }
+
+            all.extend(taken);
+            RtcState::clear();
+        }
+
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Serialize;
+
+/// Scales the median absolute deviation to the standard deviation of normally distributed readings.
+const MAD_SCALE: f64 = 1.4826;
+/// Lower bound of the spread relative to the median, so a window of nearly identical readings doesn't turn the
+/// smallest difference into an outlier.
+const MIN_RELATIVE_SPREAD: f64 = 0.01;
+
+/// Summary of the readings of one sensor within a sampling window, after the outliers were dropped.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Statistics {
+    pub mean: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Population standard deviation
+    pub stddev: f64,
+    /// Readings left after rejecting the outliers
+    pub samples: usize
+}
+
+/// Drops the readings which are more than `threshold` standard deviations away from the median, estimating the
+/// deviation from the median absolute deviation so the outliers themselves don't inflate it. At least three readings
+/// are needed to tell which one is off, fewer are all kept.
+pub fn reject_outliers(readings: &[f64], threshold: f64) -> Vec<f64> {
+    if readings.len() < 3 {
+        return readings.to_vec()
+    }
+
+    let centre = median(readings);
+    let deviations: Vec<f64> = readings.iter().map(|r| (r - centre).abs()).collect();
+    let spread = (MAD_SCALE * median(&deviations)).max(MIN_RELATIVE_SPREAD * centre.abs());
+
+    readings.iter().zip(deviations)
+        .filter(|(_, deviation)| if spread > 0.0 { deviation / spread <= threshold } else { *deviation == 0.0 })
+        .map(|(reading, _)| *reading)
+        .collect()
+}
+
+/// Statistics of the readings which are not outliers, `None` without readings.
+pub fn aggregate(readings: &[f64], threshold: f64) -> Option<Statistics> {
+    let kept = reject_outliers(readings, threshold);
+
+    if kept.is_empty() {
+        return None
+    }
+
+    let n = kept.len() as f64;
+    let mean = kept.iter().sum::<f64>() / n;
+    let variance = kept.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n;
+
+    Some(Statistics {
+        mean,
+        min: kept.iter().copied().fold(f64::INFINITY, f64::min),
+        max: kept.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        stddev: variance.sqrt(),
+        samples: kept.len()
+    })
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let middle = sorted.len() / 2;
+
+    if sorted.len() % 2 == 0 {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: f64 = 3.5;
+
+    #[test]
+    fn keeps_fewer_than_three_readings() {
+        assert_eq!(reject_outliers(&[], THRESHOLD), Vec::<f64>::new());
+        assert_eq!(reject_outliers(&[100.0], THRESHOLD), vec![100.0]);
+        assert_eq!(reject_outliers(&[100.0, 500.0], THRESHOLD), vec![100.0, 500.0]);
+    }
+
+    #[test]
+    fn drops_a_single_spike() {
+        assert_eq!(reject_outliers(&[100.0, 101.0, 99.0, 100.0, 500.0], THRESHOLD), vec![100.0, 101.0, 99.0, 100.0]);
+        assert_eq!(reject_outliers(&[100.0, 101.0, -300.0, 99.0, 100.0], THRESHOLD), vec![100.0, 101.0, 99.0, 100.0]);
+    }
+
+    #[test]
+    fn keeps_identical_readings() {
+        assert_eq!(reject_outliers(&[42.0; 5], THRESHOLD), vec![42.0; 5]);
+        assert_eq!(reject_outliers(&[0.0; 5], THRESHOLD), vec![0.0; 5]);
+    }
+
+    #[test]
+    fn keeps_a_small_difference_between_nearly_identical_readings() {
+        // the median absolute deviation is 0, the relative spread keeps the reading which is slightly off
+        assert_eq!(reject_outliers(&[1000.0, 1000.0, 1000.0, 1000.5], THRESHOLD), vec![1000.0, 1000.0, 1000.0, 1000.5]);
+        // without a spread to compare to only the readings at the median are kept
+        assert_eq!(reject_outliers(&[0.0, 0.0, 0.0, 5.0], THRESHOLD), vec![0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn aggregates_the_readings_left() {
+        let statistics = aggregate(&[100.0, 101.0, 99.0, 100.0, 500.0], THRESHOLD).unwrap();
+
+        assert_eq!(statistics, Statistics { mean: 100.0, min: 99.0, max: 101.0, stddev: 0.5f64.sqrt(), samples: 4 });
+    }
+
+    #[test]
+    fn aggregates_identical_readings_without_a_deviation() {
+        assert_eq!(aggregate(&[21.5; 3], THRESHOLD), Some(Statistics { mean: 21.5, min: 21.5, max: 21.5, stddev: 0.0, samples: 3 }));
+    }
+
+    #[test]
+    fn aggregates_nothing_without_readings() {
+        assert_eq!(aggregate(&[], THRESHOLD), None);
+    }
+}
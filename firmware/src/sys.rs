@@ -0,0 +1,55 @@
+//! The few ESP-IDF types the logic of the station depends on. On the ESP32 these are re-exported, elsewhere they are
+//! stood in for, so everything but the drivers builds and is tested on the host.
+
+#[cfg(target_os = "espidf")]
+pub use esp_idf_svc::errors::EspIOError;
+#[cfg(target_os = "espidf")]
+pub use esp_idf_sys::{adc1_channel_t, adc1_channel_t_ADC1_CHANNEL_7, EspError, ESP_ERR_NOT_FOUND, ESP_ERR_TIMEOUT, touch_pad_t, touch_pad_t_TOUCH_PAD_NUM8};
+
+#[cfg(not(target_os = "espidf"))]
+pub use host::*;
+
+#[cfg(not(target_os = "espidf"))]
+#[allow(non_camel_case_types, non_upper_case_globals)]
+mod host {
+    use std::fmt;
+    use std::num::NonZeroI32;
+
+    pub type esp_err_t = i32;
+    pub type touch_pad_t = u32;
+    pub type adc1_channel_t = u32;
+
+    pub const ESP_ERR_NOT_FOUND: esp_err_t = 0x105;
+    pub const ESP_ERR_TIMEOUT: esp_err_t = 0x107;
+    pub const touch_pad_t_TOUCH_PAD_NUM8: touch_pad_t = 8;
+    pub const adc1_channel_t_ADC1_CHANNEL_7: adc1_channel_t = 7;
+
+    /// Error code of an ESP-IDF call, like `esp_idf_sys::EspError`.
+    #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+    pub struct EspError(NonZeroI32);
+
+    impl EspError {
+        pub const fn from_infallible<const E: esp_err_t>() -> Self {
+            match NonZeroI32::new(E) {
+                Some(code) => EspError(code),
+                None => panic!("ESP_OK is not an error")
+            }
+        }
+
+        pub fn code(&self) -> esp_err_t {
+            self.0.get()
+        }
+    }
+
+    impl fmt::Display for EspError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "ESP-IDF error {:#x}", self.code())
+        }
+    }
+
+    impl std::error::Error for EspError {}
+
+    /// Error of the ESP-IDF HTTP client, like `esp_idf_svc::errors::EspIOError`.
+    #[derive(Debug)]
+    pub struct EspIOError(pub EspError);
+}
@@ -0,0 +1,33 @@
+use log::{info, warn};
+
+use crate::auth0::Auth0;
+use crate::clock::Clock;
+use crate::kv::KvStore;
+use crate::mycelium::{MyceliumBackend, TankLevel};
+use crate::onboarding::AppError;
+use crate::settings::FlashState;
+use crate::station::extract_wallet;
+
+/// Below this fill percentage the backend is notified to refill the tank.
+const LOW_WATER: f64 = 20.0;
+/// Above this the tank counts as refilled, so a level hovering around the threshold doesn't notify over and over.
+const REFILLED: f64 = 30.0;
+
+/// Notifies the backend once when the tank runs low, and again only after it has been refilled in between.
+pub fn alert_low_water<K : KvStore, A : Auth0, B : MyceliumBackend, C : Clock>(flash_state: &FlashState<K>, auth: &A, backend: &B, clock: &C, fill_percentage: f64) -> Result<(), AppError> {
+    let reported = flash_state.get_low_water_reported()?;
+
+    if fill_percentage < LOW_WATER && !reported {
+        let wallet = extract_wallet(auth, flash_state, clock)?;
+        let station_id = flash_state.get_station_id()?;
+
+        backend.low_water(&wallet.access_token, &station_id, &TankLevel { fill_percentage })?;
+        flash_state.set_low_water_reported(true)?;
+        warn!("Tank is low ({:.0}%), notified the backend", fill_percentage);
+    } else if fill_percentage >= REFILLED && reported {
+        flash_state.set_low_water_reported(false)?;
+        info!("Tank was refilled ({:.0}%)", fill_percentage);
+    }
+
+    Ok(())
+}
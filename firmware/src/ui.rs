@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::gpio::{AnyIOPin, AnyOutputPin, Input, Output, PinDriver, Pull};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::*;
+use log::info;
+
+use crate::onboarding::{AppError, OnboardingState};
+
+const LONG_PRESS: Duration = Duration::from_secs(3);
+const VERY_LONG_PRESS: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Press {
+    /// Measure now
+    Short,
+    /// Enter maintenance
+    Long,
+    /// Factory reset
+    VeryLong
+}
+
+/// Active low push button, e.g. the BOOT button of a devkit which has an external pull-up.
+#[cfg(target_os = "espidf")]
+pub struct Button {
+    pin: PinDriver<'static, AnyIOPin, Input>
+}
+
+#[cfg(target_os = "espidf")]
+impl Button {
+    pub fn new(pin: AnyIOPin) -> Result<Button, EspError> {
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+
+        Ok(Button { pin })
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pin.is_low()
+    }
+
+    /// Measures how long the button is held from now on. The LED lights up once a long press is reached
+    /// and flickers once a very long press is reached, so the user knows when to let go.
+    pub fn press(&self, led: &StatusLed) -> Press {
+        let start = Instant::now();
+        let mut press = Press::Short;
+
+        while self.is_pressed() {
+            let held = start.elapsed();
+
+            if held >= VERY_LONG_PRESS && press != Press::VeryLong {
+                press = Press::VeryLong;
+                led.show(LedPattern::FACTORY_RESET);
+            } else if held >= LONG_PRESS && press == Press::Short {
+                press = Press::Long;
+                led.show(LedPattern::On);
+            }
+
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        info!("Button press: {:?}", press);
+        led.show(LedPattern::Off);
+
+        press
+    }
+
+    /// Wakes the station from deep sleep when the button is pressed. Uses ext1 as it keeps working with the RTC peripherals powered down.
+    pub fn enable_wakeup(&self) -> Result<(), EspError> {
+        esp!(unsafe { esp_sleep_enable_ext1_wakeup(1u64 << self.pin.pin(), esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ALL_LOW) })
+    }
+}
+
+/// Whether the station was woken from deep sleep by the button.
+#[cfg(target_os = "espidf")]
+pub fn woken_by_button() -> bool {
+    unsafe { esp_sleep_get_wakeup_cause() == esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LedPattern {
+    Off,
+    On,
+    Blink { on_ms: u64, off_ms: u64 },
+    /// A number of short pulses followed by a pause, used to tell error classes apart
+    Pulses { count: u8 }
+}
+
+impl LedPattern {
+    pub const MAINTENANCE: LedPattern = LedPattern::Blink { on_ms: 500, off_ms: 500 };
+    pub const FACTORY_RESET: LedPattern = LedPattern::Blink { on_ms: 50, off_ms: 50 };
+
+    pub fn error(error: &AppError) -> LedPattern {
+        let count = match error {
+            AppError::Esp(_) => 2,
+            AppError::Auth(_) | AppError::AuthorizationExpired | AppError::Clock(_) => 3,
+            AppError::Mycelium(_) => 4,
+            AppError::Kv(_) | AppError::Json(_) | AppError::RwLock | AppError::Unprovisioned => 5,
+            AppError::Pump(_) => 6
+        };
+
+        LedPattern::Pulses { count }
+    }
+}
+
+impl From<&OnboardingState> for LedPattern {
+    fn from(state: &OnboardingState) -> Self {
+        match state {
+            OnboardingState::AwaitingSettings => LedPattern::Blink { on_ms: 1000, off_ms: 1000 },
+            OnboardingState::ProvisioningWifi => LedPattern::Blink { on_ms: 100, off_ms: 100 },
+            OnboardingState::AwaitingAuthorization { .. } => LedPattern::Blink { on_ms: 100, off_ms: 900 },
+            OnboardingState::Failed { .. } => LedPattern::Pulses { count: 1 },
+            OnboardingState::Complete => LedPattern::On
+        }
+    }
+}
+
+/// Status LED driven by a background thread which repeats the current pattern.
+#[cfg(target_os = "espidf")]
+pub struct StatusLed {
+    pattern: Arc<Mutex<LedPattern>>
+}
+
+#[cfg(target_os = "espidf")]
+impl StatusLed {
+    pub fn start(pin: AnyOutputPin) -> Result<StatusLed, EspError> {
+        let mut pin = PinDriver::output(pin)?;
+        let pattern = Arc::new(Mutex::new(LedPattern::Off));
+        let current = pattern.clone();
+
+        std::thread::Builder::new()
+            .stack_size(2048)
+            .spawn(move || loop {
+                let pattern = *current.lock().unwrap();
+                run_pattern(&mut pin, pattern).unwrap();
+            })
+            .unwrap();
+
+        Ok(StatusLed { pattern })
+    }
+
+    pub fn show(&self, pattern: LedPattern) {
+        *self.pattern.lock().unwrap() = pattern;
+    }
+
+    /// Subscriber for `OnboardingController::subscribe`, shows the pattern of the published state.
+    pub fn state_subscriber(&self) -> impl Fn(&OnboardingState) + Send + Sync + 'static {
+        let pattern = self.pattern.clone();
+        move |s| *pattern.lock().unwrap() = LedPattern::from(s)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn run_pattern(pin: &mut PinDriver<'static, AnyOutputPin, Output>, pattern: LedPattern) -> Result<(), EspError> {
+    match pattern {
+        LedPattern::Off => {
+            pin.set_low()?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        LedPattern::On => {
+            pin.set_high()?;
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        LedPattern::Blink { on_ms, off_ms } => {
+            pin.set_high()?;
+            std::thread::sleep(Duration::from_millis(on_ms));
+            pin.set_low()?;
+            std::thread::sleep(Duration::from_millis(off_ms));
+        }
+        LedPattern::Pulses { count } => {
+            for _ in 0..count {
+                pin.set_high()?;
+                std::thread::sleep(Duration::from_millis(150));
+                pin.set_low()?;
+                std::thread::sleep(Duration::from_millis(250));
+            }
+            std::thread::sleep(Duration::from_millis(1500));
+        }
+    }
+
+    Ok(())
+}
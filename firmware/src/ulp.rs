@@ -0,0 +1,421 @@
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::ulp::{ULP, UlpDriver};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::*;
+use log::{info, warn};
+
+use crate::battery::{BatteryConfig, BatteryLevel};
+use crate::capacitance::DEFAULT_TOUCH_SCALE;
+use crate::sys::{adc1_channel_t, adc1_channel_t_ADC1_CHANNEL_7, touch_pad_t, touch_pad_t_TOUCH_PAD_NUM8};
+
+/// First word of the sample buffer in RTC slow memory, the program is loaded below it.
+const DATA_BASE: usize = 128;
+/// Number of samples in the buffer, written by the ULP.
+const COUNT: usize = DATA_BASE;
+/// Time the ULP was started in seconds since the epoch, a full word written by the main CPU.
+const STARTED: usize = DATA_BASE + 1;
+/// Soil and battery reading of every sample, two words per sample.
+const SAMPLES: usize = DATA_BASE + 2;
+
+/// RTC peripherals as selected by the REG_RD and REG_WR instructions, with the offsets of the registers within them.
+const PERIPH_RTC_CNTL: u32 = 0;
+const PERIPH_SENS: u32 = 2;
+const RTC_CNTL_STATE0: u32 = 0x18;
+const RTC_CNTL_LOW_POWER_ST: u32 = 0xc0;
+const SENS_SAR_TOUCH_OUT1: u32 = 0x70;
+
+const ULP_BINARY_MAGIC: u32 = 0x0070_6c75;
+
+#[derive(Clone, Debug)]
+pub struct UlpConfig {
+    /// Time between two samples taken while sleeping
+    pub period: Duration,
+    /// Samples kept in RTC memory, the station wakes up when they are all taken. Together with the measurement of the
+    /// wake they have to fit the pending measurements in RTC memory, so a failed check-in keeps them all.
+    pub capacity: usize,
+    /// Time after which the station wakes up to upload the samples, even when the buffer isn't full
+    pub upload_interval: Duration,
+    /// Touch pad of the soil probe
+    pub soil_pad: touch_pad_t,
+    /// ADC1 channel of the battery voltage divider
+    pub battery_channel: adc1_channel_t
+}
+
+impl Default for UlpConfig {
+    /// Soil probe on touch pad 8 and the battery on ADC1 channel 7 (GPIO35), sampled every 2 minutes for an hour.
+    fn default() -> Self {
+        UlpConfig {
+            period: Duration::from_secs(2 * 60),
+            capacity: 30,
+            upload_interval: Duration::from_secs(60 * 60),
+            soil_pad: touch_pad_t_TOUCH_PAD_NUM8,
+            battery_channel: adc1_channel_t_ADC1_CHANNEL_7
+        }
+    }
+}
+
+/// Sample taken by the ULP while the station was sleeping.
+#[derive(Debug, Clone)]
+pub struct UlpReading {
+    /// Seconds since the epoch
+    pub on: u64,
+    /// Uncompensated and unfiltered, a single touch pad reading
+    pub soil_pf: f64,
+    pub battery: BatteryLevel
+}
+
+/// Samples the soil probe and the battery with the ULP coprocessor during deep sleep, so the main cores and the
+/// radio only wake up to upload. The ULP wakes the station early when the buffer is full or the soil is drier than
+/// the threshold of the watering schedule.
+///
+/// The touch sensor keeps measuring the pads by itself in timer mode and the ULP reads the result, the battery is
+/// read with the ULP's own ADC instruction. Readings are converted when the main CPU collects them.
+#[cfg(target_os = "espidf")]
+pub struct UlpSampler {
+    driver: UlpDriver<'static>,
+    config: UlpConfig,
+    battery: BatteryConfig
+}
+
+#[cfg(target_os = "espidf")]
+impl UlpSampler {
+    /// Stops the ULP so it doesn't write to the buffer while it is read. After a power-on or reset the RTC memory
+    /// holds garbage, so the buffer is only kept when waking from deep sleep.
+    pub fn new(ulp: ULP, config: UlpConfig, battery: BatteryConfig) -> Result<UlpSampler, EspError> {
+        let mut driver = UlpDriver::new(ulp)?;
+        driver.stop()?;
+
+        let mut sampler = UlpSampler { driver, config, battery };
+
+        if unsafe { esp_reset_reason() } != esp_reset_reason_t_ESP_RST_DEEPSLEEP {
+            sampler.clear()?;
+        }
+
+        Ok(sampler)
+    }
+
+    pub fn config(&self) -> &UlpConfig {
+        &self.config
+    }
+
+    pub fn is_full(&self) -> Result<bool, EspError> {
+        Ok(self.count()? >= self.config.capacity)
+    }
+
+    /// The samples taken since the buffer was cleared, oldest first. The ULP doesn't know the time, so the time of a
+    /// sample is estimated from when the ULP was first started and the period, which is off by the time the station
+    /// was awake in between.
+    pub fn readings(&self) -> Result<Vec<UlpReading>, EspError> {
+        let count = self.count()?;
+        let started = unsafe { std::ptr::read_volatile(word(STARTED)) } as u64;
+        let mut characteristics = esp_adc_cal_characteristics_t::default();
+
+        unsafe {
+            esp_adc_cal_characterize(adc_unit_t_ADC_UNIT_1, adc_atten_t_ADC_ATTEN_DB_11, adc_bits_width_t_ADC_WIDTH_BIT_12, 1100, &mut characteristics);
+        }
+
+        (0..count).map(|n| {
+            let soil_count = unsafe { self.driver.read_word(word(SAMPLES + 2 * n))? }.value();
+            let battery_raw = unsafe { self.driver.read_word(word(SAMPLES + 2 * n + 1))? }.value();
+            let millivolts = unsafe { esp_adc_cal_raw_to_voltage(battery_raw as u32, &characteristics) };
+            let voltage = millivolts as f64 / 1000.0 * self.battery.divider_ratio;
+
+            Ok(UlpReading {
+                on: started + self.config.period.as_secs() * (n as u64 + 1),
+                soil_pf: DEFAULT_TOUCH_SCALE / soil_count.max(1) as f64,
+                battery: BatteryLevel { voltage, percentage: self.battery.chemistry.state_of_charge(voltage) }
+            })
+        }).collect()
+    }
+
+    /// Empties the buffer, once its samples are uploaded.
+    pub fn clear(&mut self) -> Result<(), EspError> {
+        unsafe { self.driver.write_word(word(COUNT), 0) }
+    }
+
+    /// Starts sampling for the deep sleep, appending to the buffer. The station wakes up when the soil probe reads
+    /// below `dry_below_pf`.
+    pub fn start(&mut self, dry_below_pf: Option<f64>, now: u64) -> Result<(), EspError> {
+        if self.count()? == 0 {
+            unsafe { std::ptr::write_volatile(word(STARTED), now as u32) };
+        }
+
+        // the touch pad counts cycles, so a lower capacitance is a higher count
+        let threshold = dry_below_pf.map(|pf| (DEFAULT_TOUCH_SCALE / pf).ceil().min(u16::MAX as f64) as u16);
+        let program = binary(&program(&self.config, threshold));
+
+        unsafe {
+            esp!(touch_pad_set_fsm_mode(touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER))?;
+            esp!(adc1_config_width(adc_bits_width_t_ADC_WIDTH_BIT_12))?;
+            esp!(adc1_config_channel_atten(self.config.battery_channel, adc_atten_t_ADC_ATTEN_DB_11))?;
+            adc1_ulp_enable();
+
+            self.driver.load(&program)?;
+            self.driver.set_sleep_period_default(self.config.period)?;
+            self.driver.start(std::ptr::null())?;
+
+            esp!(esp_sleep_enable_ulp_wakeup())?;
+            // the touch sensor and the ADC are RTC peripherals
+            esp!(esp_sleep_pd_config(esp_sleep_pd_domain_t_ESP_PD_DOMAIN_RTC_PERIPH, esp_sleep_pd_option_t_ESP_PD_OPTION_ON))?;
+        }
+
+        info!("ULP sampling every {:?}, waking below {:?} pF", self.config.period, dry_below_pf);
+
+        Ok(())
+    }
+
+    fn count(&self) -> Result<usize, EspError> {
+        let count = unsafe { self.driver.read_word(word(COUNT))? }.value() as usize;
+
+        if count > self.config.capacity {
+            warn!("Invalid ULP sample count {}", count);
+            return Ok(0)
+        }
+
+        Ok(count)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+fn word(index: usize) -> *mut u32 {
+    unsafe { (ULP::MEM_START as *mut u32).add(index) }
+}
+
+/// Branches to one of the labels at the end of the program, resolved once the length of the program is known.
+enum Op {
+    Word(u32),
+    WakeIfAtLeast(u16),
+    ExitIfZero
+}
+
+/// Every run reads the soil probe into R2 and the battery into R1, appends them to the buffer and wakes the main CPU
+/// when the buffer is full or the soil count reaches the threshold.
+fn program(config: &UlpConfig, threshold: Option<u16>) -> Vec<u32> {
+    let pad = config.soil_pad;
+    let touch_out = SENS_SAR_TOUCH_OUT1 / 4 + pad / 2;
+    // even pads are in the upper half of the output register
+    let (high, low) = if pad % 2 == 0 { (31, 16) } else { (15, 0) };
+    let capacity = config.capacity as u16;
+
+    let mut ops = vec![
+        Op::Word(rd_reg(PERIPH_SENS, touch_out, high, low)),
+        Op::Word(alu_reg(ALU_MOVE, 2, 0, 0)),
+        Op::Word(adc(1, 0, config.battery_channel + 1)),
+        Op::Word(alu_imm(ALU_MOVE, 3, 0, DATA_BASE as u16)),
+        Op::Word(ld(0, 3, 0)),
+        // nothing is overwritten when full, the main CPU is woken until it collects the samples
+        Op::WakeIfAtLeast(capacity),
+        Op::Word(alu_imm(ALU_LSH, 0, 0, 1)),
+        Op::Word(alu_reg(ALU_ADD, 0, 0, 3)),
+        Op::Word(st(2, 0, (SAMPLES - DATA_BASE) as u32)),
+        Op::Word(st(1, 0, (SAMPLES - DATA_BASE) as u32 + 1)),
+        Op::Word(ld(0, 3, 0)),
+        Op::Word(alu_imm(ALU_ADD, 0, 0, 1)),
+        Op::Word(st(0, 3, 0)),
+        Op::WakeIfAtLeast(capacity)
+    ];
+
+    if let Some(threshold) = threshold {
+        ops.push(Op::Word(alu_reg(ALU_MOVE, 0, 2, 0)));
+        ops.push(Op::WakeIfAtLeast(threshold));
+    }
+
+    ops.push(Op::Word(halt()));
+
+    let wake = ops.len();
+
+    // the SoC can only be woken once it is ready to, otherwise the next run tries again
+    ops.push(Op::Word(rd_reg(PERIPH_RTC_CNTL, RTC_CNTL_LOW_POWER_ST / 4, 19, 19)));
+    ops.push(Op::Word(alu_imm(ALU_AND, 0, 0, 1)));
+    ops.push(Op::ExitIfZero);
+    ops.push(Op::Word(wake_up()));
+    // stops the ULP timer until the main CPU starts the ULP again
+    ops.push(Op::Word(wr_reg(PERIPH_RTC_CNTL, RTC_CNTL_STATE0 / 4, 24, 24, 0)));
+
+    let exit = ops.len();
+
+    ops.push(Op::Word(halt()));
+
+    let program: Vec<u32> = ops.iter().enumerate().map(|(pc, op)| match op {
+        Op::Word(word) => *word,
+        Op::WakeIfAtLeast(value) => jumpr_ge(wake as i32 - pc as i32, *value),
+        Op::ExitIfZero => jump_eq(exit as u32)
+    }).collect();
+
+    assert!(program.len() <= DATA_BASE, "ULP program overlaps its data");
+
+    program
+}
+
+/// Prefixes the program with the header `ulp_load_binary` expects, without data or bss sections.
+fn binary(program: &[u32]) -> Vec<u8> {
+    let text_size = (program.len() * 4) as u16;
+    let mut binary = Vec::with_capacity(12 + text_size as usize);
+
+    binary.extend(ULP_BINARY_MAGIC.to_le_bytes());
+    binary.extend(12u16.to_le_bytes());
+    binary.extend(text_size.to_le_bytes());
+    binary.extend(0u16.to_le_bytes());
+    binary.extend(0u16.to_le_bytes());
+    program.iter().for_each(|word| binary.extend(word.to_le_bytes()));
+
+    binary
+}
+
+// Encodings of the ULP FSM instructions, as described in the ESP32 technical reference manual.
+
+const ALU_ADD: u32 = 0;
+const ALU_AND: u32 = 2;
+const ALU_MOVE: u32 = 4;
+const ALU_LSH: u32 = 5;
+
+fn alu_reg(sel: u32, rd: u32, rs: u32, rt: u32) -> u32 {
+    7 << 28 | sel << 21 | rt << 4 | rs << 2 | rd
+}
+
+fn alu_imm(sel: u32, rd: u32, rs: u32, imm: u16) -> u32 {
+    7 << 28 | 1 << 25 | sel << 21 | (imm as u32) << 4 | rs << 2 | rd
+}
+
+/// Stores `rs` at the word address in `rd` plus `offset`.
+fn st(rs: u32, rd: u32, offset: u32) -> u32 {
+    6 << 28 | 4 << 25 | offset << 10 | rd << 2 | rs
+}
+
+/// Loads the word at the address in `rs` plus `offset` into `rd`.
+fn ld(rd: u32, rs: u32, offset: u32) -> u32 {
+    13 << 28 | offset << 10 | rs << 2 | rd
+}
+
+/// Jumps `offset` words relative to the current instruction when R0 is at least `value`.
+fn jumpr_ge(offset: i32, value: u16) -> u32 {
+    let sign = if offset < 0 { 1 } else { 0 };
+    8 << 28 | 1 << 25 | sign << 24 | (offset.unsigned_abs() & 0x7f) << 17 | 1 << 16 | value as u32
+}
+
+/// Jumps to the absolute word address when the result of the last ALU operation was zero.
+fn jump_eq(address: u32) -> u32 {
+    8 << 28 | 1 << 22 | address << 2
+}
+
+/// Reads bits `low` to `high` of a register into R0.
+fn rd_reg(periph: u32, address: u32, high: u32, low: u32) -> u32 {
+    2 << 28 | high << 23 | low << 18 | periph << 8 | address
+}
+
+fn wr_reg(periph: u32, address: u32, high: u32, low: u32, data: u32) -> u32 {
+    1 << 28 | high << 23 | low << 18 | data << 10 | periph << 8 | address
+}
+
+/// Converts a channel of SAR ADC `sar` (0 for ADC1) into `rd`, the channel is selected by `mux`, one based.
+fn adc(rd: u32, sar: u32, mux: u32) -> u32 {
+    5 << 28 | sar << 6 | mux << 2 | rd
+}
+
+fn wake_up() -> u32 {
+    9 << 28 | 1
+}
+
+fn halt() -> u32 {
+    11 << 28
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::MAX_PENDING_MEASUREMENTS;
+
+    // Reference encodings of the instruction macros in `ulp.h` of ESP-IDF 4.4
+
+    #[test]
+    fn encodes_alu_instructions() {
+        // I_MOVI(R3, 128), I_LSHI(R0, R0, 1), I_ADDI(R0, R0, 1), I_ANDI(R0, R0, 1)
+        assert_eq!(alu_imm(ALU_MOVE, 3, 0, 128), 0x7280_0803);
+        assert_eq!(alu_imm(ALU_LSH, 0, 0, 1), 0x72a0_0010);
+        assert_eq!(alu_imm(ALU_ADD, 0, 0, 1), 0x7200_0010);
+        assert_eq!(alu_imm(ALU_AND, 0, 0, 1), 0x7240_0010);
+        // I_ADDR(R0, R0, R3), I_MOVR(R2, R0)
+        assert_eq!(alu_reg(ALU_ADD, 0, 0, 3), 0x7000_0030);
+        assert_eq!(alu_reg(ALU_MOVE, 2, 0, 0), 0x7080_0002);
+    }
+
+    #[test]
+    fn encodes_memory_instructions() {
+        // I_ST(R2, R0, 2), I_ST(R0, R3, 0)
+        assert_eq!(st(2, 0, 2), 0x6800_0802);
+        assert_eq!(st(0, 3, 0), 0x6800_000c);
+        // I_LD(R0, R3, 0), I_LD(R1, R2, 5)
+        assert_eq!(ld(0, 3, 0), 0xd000_000c);
+        assert_eq!(ld(1, 2, 5), 0xd000_1409);
+    }
+
+    #[test]
+    fn encodes_branches() {
+        // I_BGE(9, 60), I_BGE(-3, 1000), I_BXZI(25)
+        assert_eq!(jumpr_ge(9, 60), 0x8213_003c);
+        assert_eq!(jumpr_ge(-3, 1000), 0x8307_03e8);
+        assert_eq!(jump_eq(25), 0x8040_0064);
+    }
+
+    #[test]
+    fn encodes_peripheral_instructions() {
+        // I_RD_REG(SENS_SAR_TOUCH_OUT5_REG, 16, 31), I_RD_REG(RTC_CNTL_LOW_POWER_ST_REG, 19, 19)
+        assert_eq!(rd_reg(PERIPH_SENS, 0x20, 31, 16), 0x2fc0_0220);
+        assert_eq!(rd_reg(PERIPH_RTC_CNTL, 0x30, 19, 19), 0x29cc_0030);
+        // I_WR_REG(RTC_CNTL_STATE0_REG, 24, 24, 0)
+        assert_eq!(wr_reg(PERIPH_RTC_CNTL, 0x06, 24, 24, 0), 0x1c60_0006);
+        // I_ADC(R1, 0, 7)
+        assert_eq!(adc(1, 0, 8), 0x5000_0021);
+        // I_WAKE(), I_HALT()
+        assert_eq!(wake_up(), 0x9000_0001);
+        assert_eq!(halt(), 0xb000_0000);
+    }
+
+    #[test]
+    fn lays_out_the_program() {
+        let program = program(&UlpConfig::default(), None);
+
+        assert_eq!(program.len(), 21);
+        // soil probe on touch pad 8 in the upper half of SENS_SAR_TOUCH_OUT5
+        assert_eq!(program[0], 0x2fc0_0220);
+        assert_eq!(program[2], 0x5000_0021);
+        // both capacity checks branch to the wake up at 15, the check of the readiness to exit at 20
+        assert_eq!(program[5], jumpr_ge(10, 30));
+        assert_eq!(program[13], jumpr_ge(2, 30));
+        assert_eq!(program[14], halt());
+        assert_eq!(program[15], 0x29cc_0030);
+        assert_eq!(program[17], jump_eq(20));
+        assert_eq!(program[18], wake_up());
+        assert_eq!(program[20], halt());
+    }
+
+    #[test]
+    fn wakes_below_the_threshold() {
+        let program = program(&UlpConfig::default(), Some(1500));
+
+        assert_eq!(program.len(), 23);
+        assert_eq!(program[5], jumpr_ge(12, 30));
+        assert_eq!(program[14], alu_reg(ALU_MOVE, 0, 2, 0));
+        assert_eq!(program[15], jumpr_ge(2, 1500));
+        assert_eq!(program[16], halt());
+        assert_eq!(program[19], jump_eq(22));
+    }
+
+    #[test]
+    fn prefixes_the_binary_header() {
+        let binary = binary(&[halt(), wake_up()]);
+
+        assert_eq!(&binary[..12], &[0x75, 0x6c, 0x70, 0x00, 12, 0, 8, 0, 0, 0, 0, 0]);
+        assert_eq!(&binary[12..], &[0, 0, 0, 0xb0, 1, 0, 0, 0x90]);
+    }
+
+    #[test]
+    fn keeps_the_samples_of_a_failed_check_in() {
+        let config = UlpConfig::default();
+
+        assert!(config.capacity < MAX_PENDING_MEASUREMENTS);
+        assert!(config.period * config.capacity as u32 >= config.upload_interval);
+    }
+}
@@ -0,0 +1,178 @@
+use heapless::String;
+use serde::Serialize;
+
+use crate::schedule::{CronSchedule, parse_duration};
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub error: ValidationError
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "_type")]
+pub enum ValidationError {
+    Empty,
+    TooLong { max_bytes: usize, actual_bytes: usize },
+    TooShort { min_chars: usize, actual_chars: usize },
+    NotPrintableAscii,
+    Invalid { reason: &'static str }
+}
+
+/// Collects every field error instead of stopping at the first one, so all of them can be reported back at once.
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>
+}
+
+impl Validator {
+    pub fn new() -> Validator {
+        Validator { errors: Vec::new() }
+    }
+
+    pub fn error(&mut self, field: &'static str, error: ValidationError) {
+        self.errors.push(FieldError { field, error });
+    }
+
+    pub fn check(&mut self, field: &'static str, result: Result<(), ValidationError>) {
+        if let Err(error) = result {
+            self.error(field, error);
+        }
+    }
+
+    pub fn not_blank(&mut self, field: &'static str, value: &str) {
+        if value.trim().is_empty() {
+            self.error(field, ValidationError::Empty);
+        }
+    }
+
+    /// Converts to a bounded string. The capacity is in bytes, so multi-byte UTF-8 characters count for more than one.
+    pub fn bounded<const N : usize>(&mut self, field: &'static str, value: &str) -> String<N> {
+        let mut result = String::new();
+
+        if result.push_str(value).is_err() {
+            self.error(field, ValidationError::TooLong { max_bytes: N, actual_bytes: value.len() });
+        }
+
+        result
+    }
+
+    pub fn finish<T>(self, value: T) -> Result<T, Vec<FieldError>> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+/// WPA2 passphrases are 8 to 63 printable ASCII characters, or a 64 digit hexadecimal key. An empty password means an open network.
+pub fn wpa_passphrase(password: &str) -> Result<(), ValidationError> {
+    let chars = password.chars().count();
+
+    if password.is_empty() || (chars == 64 && password.chars().all(|c| c.is_ascii_hexdigit())) {
+        Ok(())
+    } else if !password.chars().all(|c| c.is_ascii() && !c.is_ascii_control()) {
+        Err(ValidationError::NotPrintableAscii)
+    } else if chars < 8 {
+        Err(ValidationError::TooShort { min_chars: 8, actual_chars: chars })
+    } else if chars > 63 {
+        Err(ValidationError::TooLong { max_bytes: 63, actual_bytes: password.len() })
+    } else {
+        Ok(())
+    }
+}
+
+/// Rejects NaN and infinity, which JSON can't carry back and which would poison every reading they are applied to.
+pub fn finite(value: f64) -> Result<(), ValidationError> {
+    if value.is_finite() {
+        Ok(())
+    } else {
+        Err(ValidationError::Invalid { reason: "not a finite number" })
+    }
+}
+
+/// Cron expression as parsed by cron4s at the backend, see `CronSchedule`.
+pub fn cron_expression(expression: &str) -> Result<(), ValidationError> {
+    if expression.trim().is_empty() {
+        Err(ValidationError::Empty)
+    } else {
+        CronSchedule::parse(expression).map(|_| ()).map_err(|reason| ValidationError::Invalid { reason })
+    }
+}
+
+/// Duration as parsed by Scala's `Duration(String)` at the backend, e.g. `5 seconds` or `1.5min`. Must be positive.
+pub fn duration(duration: &str) -> Result<(), ValidationError> {
+    if duration.trim().is_empty() {
+        Err(ValidationError::Empty)
+    } else {
+        parse_duration(duration).map(|_| ()).map_err(|reason| ValidationError::Invalid { reason })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_every_field_error() {
+        let mut validator = Validator::new();
+
+        validator.not_blank("name", "  ");
+        let name: String<4> = validator.bounded("name", "Basil");
+        validator.check("wifi_password", wpa_passphrase("short"));
+        validator.check("period", Ok(()));
+
+        assert_eq!(name.as_str(), "");
+        assert_eq!(validator.finish(()), Err(vec![
+            FieldError { field: "name", error: ValidationError::Empty },
+            FieldError { field: "name", error: ValidationError::TooLong { max_bytes: 4, actual_bytes: 5 } },
+            FieldError { field: "wifi_password", error: ValidationError::TooShort { min_chars: 8, actual_chars: 5 } }
+        ]));
+    }
+
+    #[test]
+    fn passes_valid_fields() {
+        let mut validator = Validator::new();
+
+        validator.not_blank("name", "Basil");
+        let name: String<5> = validator.bounded("name", "Basil");
+
+        assert_eq!(validator.finish(name).unwrap().as_str(), "Basil");
+    }
+
+    #[test]
+    fn bounds_strings_in_bytes() {
+        let mut validator = Validator::new();
+
+        let name: String<5> = validator.bounded("name", "Bäsil");
+
+        assert_eq!(name.as_str(), "");
+        assert_eq!(validator.finish(()), Err(vec![FieldError { field: "name", error: ValidationError::TooLong { max_bytes: 5, actual_bytes: 6 } }]));
+    }
+
+    #[test]
+    fn accepts_wpa_passphrases() {
+        assert_eq!(wpa_passphrase(""), Ok(()));
+        assert_eq!(wpa_passphrase("12345678"), Ok(()));
+        assert_eq!(wpa_passphrase(&"a".repeat(63)), Ok(()));
+        assert_eq!(wpa_passphrase("correct horse battery staple"), Ok(()));
+        assert_eq!(wpa_passphrase(&"0123456789abcdeF".repeat(4)), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_wpa_passphrases() {
+        assert_eq!(wpa_passphrase("1234567"), Err(ValidationError::TooShort { min_chars: 8, actual_chars: 7 }));
+        assert_eq!(wpa_passphrase(&"g".repeat(64)), Err(ValidationError::TooLong { max_bytes: 63, actual_bytes: 64 }));
+        assert_eq!(wpa_passphrase("wachtwoord€"), Err(ValidationError::NotPrintableAscii));
+        assert_eq!(wpa_passphrase("pass\tword"), Err(ValidationError::NotPrintableAscii));
+    }
+
+    #[test]
+    fn accepts_only_finite_numbers() {
+        assert_eq!(finite(-12.5), Ok(()));
+        assert!(finite(f64::NAN).is_err());
+        assert!(finite(f64::INFINITY).is_err());
+        assert!(finite(f64::NEG_INFINITY).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use chrono::NaiveDateTime;
+use heapless::String;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::auth0::Auth0;
+use crate::clock::Clock;
+use crate::kv::KvStore;
+use crate::mycelium::{MyceliumBackend, StationMeasurement, WateringReport, WateringSchedule};
+use crate::onboarding::AppError;
+use crate::pump::{Pump, PumpController, PumpError};
+use crate::rpc::truncated;
+use crate::schedule::{CronSchedule, parse_duration};
+use crate::settings::FlashState;
+use crate::station::{extract_wallet, timestamp_to_rfc3389};
+
+/// Keeps the queue within the size of a single NVS value, dropping the oldest waterings first.
+const MAX_PENDING_WATERINGS: usize = 24;
+/// Before this (2023-01-01) the clock has not been synchronized, so interval schedules can't be evaluated.
+const MIN_VALID_TIME: u64 = 1_672_531_200;
+
+/// A watering done by the station, queued until it is reported to the backend.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingWatering {
+    /// Unknown when the clock was not synchronized yet, the backend then uses the time it is reported
+    pub on: Option<u64>,
+    pub period: String<30>
+}
+
+/// Evaluates the schedule like the backend does, returning the period to water for when due. Like the backend, an
+/// interval schedule is only due once the station watered before, as there is no time to count from otherwise.
+pub fn due(schedule: &WateringSchedule, measurement: &StationMeasurement, last_watered: Option<u64>, now: u64) -> Option<String<30>> {
+    match schedule {
+        WateringSchedule::Threshold { below_soil_pf, period } => {
+            matches!(measurement.soil_pf, Some(pf) if pf < *below_soil_pf as f64).then(|| period.clone())
+        }
+        WateringSchedule::Interval { schedule, period } => {
+            if now < MIN_VALID_TIME {
+                warn!("Clock not synchronized, skipping interval schedule");
+                return None
+            }
+
+            let cron = CronSchedule::parse(schedule).map_err(|reason| warn!("Invalid cached schedule {}: {}", schedule, reason)).ok()?;
+            let since = NaiveDateTime::from_timestamp_opt(last_watered? as i64, 0)?;
+            let now = NaiveDateTime::from_timestamp_opt(now as i64, 0)?;
+
+            cron.next_after(since).filter(|next| *next <= now).map(|_| period.clone())
+        }
+    }
+}
+
+/// Evaluates the cached schedule, for when the backend can't be reached to decide.
+pub fn local_watering<K : KvStore, C : Clock>(flash_state: &FlashState<K>, clock: &C, measurement: &StationMeasurement) -> Result<Option<String<30>>, AppError> {
+    let schedule = match flash_state.get_opt_watering_schedule()? {
+        Some(schedule) => schedule,
+        None => {
+            warn!("No cached watering schedule");
+            return Ok(None)
+        }
+    };
+
+    let watering = due(&schedule, measurement, flash_state.get_opt_last_watered()?, clock.now());
+
+    if let Some(period) = &watering {
+        info!("Watering locally for {} ({:?})", period, schedule);
+    }
+
+    Ok(watering)
+}
+
+/// Waters for the given period within the limits of the pump and queues the watering to be reported.
+/// Skipping the watering because the tank is empty or the daily limit is reached is not an error.
+pub fn water<K : KvStore, P : Pump, C : Clock>(flash_state: &FlashState<K>, clock: &C, pump: &mut PumpController<P, C, K>, period: String<30>, tank_fill: Option<f64>) -> Result<(), AppError> {
+    let duration = match parse_duration(&period) {
+        Ok(duration) => duration,
+        Err(reason) => {
+            warn!("Not watering, invalid period {}: {}", period, reason);
+            return Ok(())
+        }
+    };
+
+    info!("Watering for {:?}", duration);
+
+    let ran = match pump.water(duration, tank_fill) {
+        Ok(ran) => ran,
+        Err(err @ (PumpError::TankEmpty { .. } | PumpError::DailyLimitReached { .. })) => {
+            warn!("Not watering: {:?}", err);
+            return Ok(())
+        }
+        Err(err) => return Err(AppError::Pump(err))
+    };
+
+    let now = Some(clock.now()).filter(|now| *now >= MIN_VALID_TIME);
+
+    if let Some(now) = now {
+        flash_state.set_last_watered(now)?;
+    }
+
+    let mut pending = flash_state.get_pending_waterings()?;
+
+    if pending.len() >= MAX_PENDING_WATERINGS {
+        warn!("Watering queue full, dropping the oldest watering");
+        pending.remove(0);
+    }
+
+    pending.push(PendingWatering { on: now, period: truncated(&format!("{} milliseconds", ran.as_millis())) });
+    flash_state.set_pending_waterings(&pending)?;
+
+    Ok(())
+}
+
+/// Reports queued waterings oldest first, keeping the ones which could not be reported for the next time.
+pub fn report_waterings<K : KvStore, A : Auth0, B : MyceliumBackend, C : Clock>(flash_state: &FlashState<K>, auth: &A, backend: &B, clock: &C) -> Result<(), AppError> {
+    let mut pending = flash_state.get_pending_waterings()?;
+
+    if pending.is_empty() {
+        return Ok(())
+    }
+
+    let wallet = extract_wallet(auth, flash_state, clock)?;
+    let station_id = flash_state.get_station_id()?;
+
+    while let Some(watering) = pending.first() {
+        let report = WateringReport {
+            watering: watering.period.clone(),
+            on: watering.on.and_then(timestamp_to_rfc3389)
+        };
+
+        backend.watered(&wallet.access_token, &station_id, &report)?;
+        pending.remove(0);
+        flash_state.set_pending_waterings(&pending)?;
+    }
+
+    info!("Reported queued waterings");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_714_566_609;
+
+    fn measurement(soil_pf: Option<f64>) -> StationMeasurement {
+        StationMeasurement {
+            on: "2024-05-01T12:30:09.000Z".to_string(),
+            sequence: 1,
+            boot_id: 1,
+            battery_voltage: None,
+            battery_percentage: None,
+            temperature: None,
+            humidity: None,
+            lux: None,
+            soil_pf,
+            tank_pf: None,
+            soil_moisture: None,
+            tank_fill: None,
+            statistics: None
+        }
+    }
+
+    fn hourly() -> WateringSchedule {
+        WateringSchedule::Interval { schedule: String::from("0 0 * * * ?"), period: String::from("5 seconds") }
+    }
+
+    #[test]
+    fn waters_below_the_threshold() {
+        let schedule = WateringSchedule::Threshold { below_soil_pf: 800, period: String::from("3 seconds") };
+
+        assert_eq!(due(&schedule, &measurement(Some(799.0)), None, NOW), Some(String::from("3 seconds")));
+        assert_eq!(due(&schedule, &measurement(Some(800.0)), None, NOW), None);
+        assert_eq!(due(&schedule, &measurement(None), None, NOW), None);
+    }
+
+    #[test]
+    fn waters_once_the_next_scheduled_time_passed() {
+        // at 12:30:09, last watered at 12:00:00 and at 11:59:00
+        assert_eq!(due(&hourly(), &measurement(None), Some(NOW - 31 * 60 - 9 + 60), NOW), None);
+        assert_eq!(due(&hourly(), &measurement(None), Some(NOW - 31 * 60 - 9), NOW), Some(String::from("5 seconds")));
+    }
+
+    #[test]
+    fn does_not_water_on_an_interval_without_a_last_watering() {
+        assert_eq!(due(&hourly(), &measurement(None), None, NOW), None);
+    }
+
+    #[test]
+    fn does_not_water_on_an_interval_without_the_time() {
+        assert_eq!(due(&hourly(), &measurement(None), Some(0), 3600), None);
+    }
+}
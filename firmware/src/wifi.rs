@@ -2,12 +2,14 @@ use std::sync::{Arc, Mutex};
 // based on https://github.com/ferrous-systems/espressif-trainings/blob/1ec7fd78660c58739019b4c146634077a08e3d5e/common/lib/esp32-c3-dkc02-bsc/src/wifi.rs
 // based on https://github.com/ivmarkov/rust-esp32-std-demo/blob/main/src/main.rs
 use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 
 use heapless::String;
 use log::{debug};
-use esp_idf_sys::EspError;
+use crate::sys::EspError;
 use serde::{Deserialize, Serialize};
 
 
@@ -29,23 +31,27 @@ pub trait MyceliumWifi : Send + Sync + Clone {
     fn connect(&self, settings: MyceliumWifiSettings) -> Result<MyceliumWifiSettings, EspError>;
 }
 
+#[cfg(target_os = "espidf")]
 pub struct EspMyceliumWifi {
     esp_wifi: Arc<Mutex<EspWifi<'static>>>,
     sysloop: Arc<Mutex<EspSystemEventLoop>>
 }
 
+#[cfg(target_os = "espidf")]
 impl EspMyceliumWifi {
     pub fn new(sysloop: EspSystemEventLoop, wifi: EspWifi<'static>) -> EspMyceliumWifi {
         EspMyceliumWifi { esp_wifi: Arc::new(Mutex::new(wifi)), sysloop: Arc::new(Mutex::new(sysloop)) }
     }
 }
 
+#[cfg(target_os = "espidf")]
 impl Clone for EspMyceliumWifi {
     fn clone(&self) -> Self {
         EspMyceliumWifi { esp_wifi: self.esp_wifi.clone(), sysloop: self.sysloop.clone() }
     }
 }
 
+#[cfg(target_os = "espidf")]
 impl MyceliumWifi for EspMyceliumWifi {
 
     fn connect(&self, settings: MyceliumWifiSettings) -> Result<MyceliumWifiSettings, EspError> {
@@ -104,5 +110,7 @@ impl MyceliumWifi for EspMyceliumWifi {
     }
 }
 
+#[cfg(target_os = "espidf")]
 unsafe impl Send for EspMyceliumWifi { }
+#[cfg(target_os = "espidf")]
 unsafe impl Sync for EspMyceliumWifi { }
\ No newline at end of file
@@ -19,8 +19,9 @@ export type StationEventScheduleChanged = {
   schedule: WateringSchedule;
 };
 export type StationEventWatered = { _type: "Watered"; period: string };
+export type StationEventLowWater = { _type: "LowWater"; fillPercentage: number };
 
-export type StationEvent = StationEventScheduleChanged | StationEventWatered;
+export type StationEvent = StationEventScheduleChanged | StationEventWatered | StationEventLowWater;
 
 export type StationLog = { on: string; event: StationEvent };
 
@@ -30,16 +31,44 @@ export type Station = {
   description: string;
   location: string;
   wateringSchedule: WateringSchedule;
+  capabilities: StationCapabilities | null;
+};
+
+export type StationCapabilities = {
+  measurements: string[];
+  parts: string[];
+};
+
+export type Statistics = {
+  mean: number;
+  min: number;
+  max: number;
+  stddev: number;
+  samples: number;
+};
+
+export type SensorStatistics = {
+  temperature: Statistics | null;
+  humidity: Statistics | null;
+  lux: Statistics | null;
+  soilPf: Statistics | null;
+  tankPf: Statistics | null;
 };
 
 export type StationMeasurement = {
   on: string;
-  batteryVoltage: number;
-  temperature: number;
-  humidity: number;
-  lux: number;
-  soilPf: number;
-  tankPf: number;
+  batteryVoltage: number | null;
+  batteryPercentage: number | null;
+  temperature: number | null;
+  humidity: number | null;
+  lux: number | null;
+  soilPf: number | null;
+  tankPf: number | null;
+  soilMoisture: number | null;
+  tankFill: number | null;
+  statistics: SensorStatistics | null;
+  sequence: number | null;
+  bootId: number | null;
 };
 
 export type StationDetails = {
@@ -3,7 +3,7 @@ import moment from "moment";
 
 type DataPoint = {
   on: string;
-  value: number;
+  value: number | null;
 };
 
 type Props = {
@@ -4,7 +4,7 @@ import { StationDetails, StationLog, StationMeasurement, WateringSchedule, getSt
 import Retrieve from "../Retrieve";
 import PlantLocation from "../components/PlantLocation";
 import PlantWateringSchedule from "../components/PlantWateringSchedule";
-import { CalendarDaysIcon, EyeDropperIcon } from "@heroicons/react/20/solid";
+import { CalendarDaysIcon, ExclamationTriangleIcon, EyeDropperIcon } from "@heroicons/react/20/solid";
 import moment from "moment";
 
 type ScheduleChangedProps = {
@@ -71,6 +71,36 @@ const PlantLogItemWatered = (props: WateredProps) => {
   );
 };
 
+type LowWaterProps = {
+  fillPercentage: number;
+  on: string;
+  lastItem: boolean;
+};
+
+const PlantLogItemLowWater = (props: LowWaterProps) => {
+  return (
+    <li>
+      <div className="relative pb-8">
+        {!props.lastItem && <span className="absolute left-5 top-5 -ml-px h-full w-0.5 bg-gray-200" aria-hidden="true" />}
+        <div className="relative flex items-start space-x-3">
+          <div>
+            <div className="relative px-1">
+              <div className="flex h-8 w-8 items-center justify-center rounded-full bg-gray-100 ring-8 ring-white">
+                <ExclamationTriangleIcon className="h-5 w-5 text-amber-500" aria-hidden="true" />
+              </div>
+            </div>
+          </div>
+          <div className="min-w-0 flex-1 py-1.5">
+            <div className="text-sm text-gray-500">
+              Tank is running low at <span className="font-semibold">{Math.round(props.fillPercentage)}%</span>, refill it - {relativeDate(props.on)}
+            </div>
+          </div>
+        </div>
+      </div>
+    </li>
+  );
+};
+
 type PlantLogProps = { plantId: string };
 
 const PlantLog = (props: PlantLogProps) => {
@@ -81,6 +111,9 @@ const PlantLog = (props: PlantLogProps) => {
 
       case "Watered":
         return <PlantLogItemWatered key={`item-${idx}`} on={item.on} period={item.event.period} lastItem={lastItem} />;
+
+      case "LowWater":
+        return <PlantLogItemLowWater key={`item-${idx}`} on={item.on} fillPercentage={item.event.fillPercentage} lastItem={lastItem} />;
     }
   };
 
@@ -116,6 +149,8 @@ export const PlantView = () => {
     const plantId = station.id;
     const host = import.meta.env.MODE == "production" ? "https://mycelium.fly.dev" : "http://localhost:8080";
     const measurements = splitMeasurements(stationDetails.measurements);
+    // stations which don't report their capabilities show every graph
+    const measures = (measurement: string) => station.capabilities?.measurements.includes(measurement) ?? true;
 
     return (
       <>
@@ -155,12 +190,12 @@ export const PlantView = () => {
           <div className="mx-auto max-w-7xl py-4">
             <div className="mx-auto grid max-w-2xl grid-cols-1 grid-rows-1 items-start gap-x-8  lg:mx-0 lg:max-w-none lg:grid-cols-3">
               <div className="sm:mx-0 lg:col-span-2 lg:row-span-2 lg:row-end-2">
-                <AreaGraph header="Soil capacitive" label="pF" data={measurements.soilPf} />
-                <AreaGraph header="Relative humidity" label="%" data={measurements.humidity} />
-                <AreaGraph header="Temperature" label="Celsius" data={measurements.temperature} />
-                <AreaGraph header="Lux" label="lx" data={measurements.lux} />
-                <AreaGraph header="Watertank capacitive" label="pF" data={measurements.tankPf} />
-                <AreaGraph header="Battery voltage" label="V" data={measurements.batteryVoltage} />
+                {measures("soilPf") && <AreaGraph header="Soil capacitive" label="pF" data={measurements.soilPf} />}
+                {measures("humidity") && <AreaGraph header="Relative humidity" label="%" data={measurements.humidity} />}
+                {measures("temperature") && <AreaGraph header="Temperature" label="Celsius" data={measurements.temperature} />}
+                {measures("lux") && <AreaGraph header="Lux" label="lx" data={measurements.lux} />}
+                {measures("tankPf") && <AreaGraph header="Watertank capacitive" label="pF" data={measurements.tankPf} />}
+                {measures("batteryVoltage") && <AreaGraph header="Battery voltage" label="V" data={measurements.batteryVoltage} />}
               </div>
               <div className="lg:col-start-3">
                 <h2 className="text-sm font-semibold leading-6 text-gray-900 mb-5">Activity</h2>